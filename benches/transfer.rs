@@ -0,0 +1,230 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::future::Future;
+use std::io::{self, Cursor, SeekFrom};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tftp::client::Client;
+use tftp::options::OptionBuilder;
+use tftp::server::Server;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::runtime::Runtime;
+use tokio::time::Sleep;
+
+const FILE_SIZE: usize = 4 * 1024 * 1024;
+const CONCURRENT_FILE_SIZE: usize = 256 * 1024;
+const CONCURRENCY: usize = 16;
+const SLOW_FILE_SIZE: usize = 1024 * 1024;
+const READ_DELAY: Duration = Duration::from_millis(1);
+
+fn free_addr() -> SocketAddr {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.local_addr().unwrap()
+}
+
+// ループバックで RRQ を繰り返し、モードごとの転送速度を比較する。
+fn rrq(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let root = tempfile::tempdir().unwrap();
+    let content: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root.path().join("a"), &content).unwrap();
+
+    let options = OptionBuilder::default()
+        .blksize(1428)
+        .windowsize(16)
+        .build();
+    let server = Server::new(free_addr(), root.path(), options.clone()).unwrap();
+    let addr = *server.service_addr();
+    rt.spawn(server.serve_forever());
+    rt.block_on(tokio::time::sleep(Duration::from_millis(100)));
+
+    let local = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("rrq");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(20);
+    for mode in ["octet", "netascii"] {
+        let client = Client::new(addr, mode, options.clone());
+        let local_file = local.path().join(mode);
+        group.bench_with_input(BenchmarkId::from_parameter(mode), &mode, |b, _| {
+            b.to_async(&rt).iter(|| get(&client, &local_file))
+        });
+    }
+    group.finish();
+}
+
+async fn get(client: &Client, local_file: &Path) {
+    let _ = std::fs::remove_file(local_file);
+    client.get(local_file, "a").await.unwrap();
+}
+
+// 同時に複数の RRQ を処理し、共有のバッファの有無で転送速度を比較する。
+fn concurrent(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let root = tempfile::tempdir().unwrap();
+    let content: Vec<u8> = (0..CONCURRENT_FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root.path().join("a"), &content).unwrap();
+
+    let options = OptionBuilder::default().blksize(1428).build();
+    let mut group = c.benchmark_group("concurrent");
+    group.throughput(Throughput::Bytes(
+        (CONCURRENT_FILE_SIZE * CONCURRENCY) as u64,
+    ));
+    group.sample_size(10);
+    for pool in [false, true] {
+        let mut server = Server::new(free_addr(), root.path(), options.clone()).unwrap();
+        if pool {
+            server = server.with_buffer_pool(CONCURRENCY * 2);
+        }
+        let addr = *server.service_addr();
+        rt.spawn(server.serve_forever());
+        rt.block_on(tokio::time::sleep(Duration::from_millis(100)));
+
+        let mut client = Client::new(addr, "octet", options.clone());
+        client.set_concurrency(CONCURRENCY);
+        let local = tempfile::tempdir().unwrap();
+        let local_files: Vec<PathBuf> = (0..CONCURRENCY)
+            .map(|i| local.path().join(i.to_string()))
+            .collect();
+        let name = if pool { "pool" } else { "alloc" };
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter(|| get_many(&client, &local_files))
+        });
+    }
+    group.finish();
+}
+
+async fn get_many(client: &Client, local_files: &[PathBuf]) {
+    let pairs: Vec<(&Path, &str)> = local_files
+        .iter()
+        .map(|f| {
+            let _ = std::fs::remove_file(f);
+            (f.as_path(), "a")
+        })
+        .collect();
+    for ret in client.get_many(&pairs).await {
+        ret.unwrap();
+    }
+}
+
+// sendmmsg と recvmmsg でまとめて送受信した場合と転送速度を比較する。
+// batch-io フィーチャが無効な場合や Linux 以外ではどちらも 1 パケットずつ送受信する。
+fn batch_io(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let root = tempfile::tempdir().unwrap();
+    let content: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root.path().join("a"), &content).unwrap();
+
+    let options = OptionBuilder::default()
+        .blksize(1428)
+        .windowsize(32)
+        .build();
+    let local = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("batch_io");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(20);
+    for batch_io in [false, true] {
+        let mut server = Server::new(free_addr(), root.path(), options.clone()).unwrap();
+        if batch_io {
+            server = server.with_batch_io();
+        }
+        let addr = *server.service_addr();
+        rt.spawn(server.serve_forever());
+        rt.block_on(tokio::time::sleep(Duration::from_millis(100)));
+
+        let client = Client::new(addr, "octet", options.clone());
+        let local_file = local.path().join(batch_io.to_string());
+        group.bench_with_input(BenchmarkId::from_parameter(batch_io), &batch_io, |b, _| {
+            b.to_async(&rt).iter(|| get(&client, &local_file))
+        });
+    }
+    group.finish();
+}
+
+// 読み込みのたびに待たされるディスクを模した読み込み元。
+struct SlowReader {
+    inner: Cursor<Vec<u8>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl SlowReader {
+    fn new(content: Vec<u8>) -> Self {
+        SlowReader {
+            inner: Cursor::new(content),
+            delay: None,
+        }
+    }
+}
+
+impl AsyncRead for SlowReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let delay = self
+            .delay
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(READ_DELAY)));
+        if delay.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.delay = None;
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncSeek for SlowReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_complete(cx)
+    }
+}
+
+// 読み込みの遅いディスクで、先読みの有無による転送速度を比較する。
+// ループバックでは ACK がすぐに届くため、先読みが ACK の処理を遅らせないことの確認になる。
+fn read_ahead(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let _guard = rt.enter();
+    let root = tempfile::tempdir().unwrap();
+    let content: Vec<u8> = (0..SLOW_FILE_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let options = OptionBuilder::default()
+        .blksize(1428)
+        .windowsize(16)
+        .build();
+    let local = tempfile::tempdir().unwrap();
+    let mut group = c.benchmark_group("read_ahead");
+    group.throughput(Throughput::Bytes(SLOW_FILE_SIZE as u64));
+    group.sample_size(10);
+    for read_ahead in [false, true] {
+        let source = content.clone();
+        let mut server = Server::new(free_addr(), root.path(), options.clone())
+            .unwrap()
+            .with_read_source(move |_| Some(SlowReader::new(source.clone())));
+        if read_ahead {
+            server = server.with_read_ahead();
+        }
+        let addr = *server.service_addr();
+        rt.spawn(server.serve_forever());
+        rt.block_on(tokio::time::sleep(Duration::from_millis(100)));
+
+        let client = Client::new(addr, "octet", options.clone());
+        let local_file = local.path().join(read_ahead.to_string());
+        group.bench_with_input(
+            BenchmarkId::from_parameter(read_ahead),
+            &read_ahead,
+            |b, _| b.to_async(&rt).iter(|| get(&client, &local_file)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, rrq, concurrent, batch_io, read_ahead);
+criterion_main!(benches);
@@ -1,7 +1,8 @@
 use clap::{Arg, Command};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 use tftp::client::Client;
 use tftp::error::Error;
 use tftp::options::OptionBuilder;
@@ -15,7 +16,7 @@ async fn main() -> Result<(), Error> {
         .arg(
             Arg::new("host")
                 .value_name("HOST")
-                .value_parser(check_type::<Ipv4Addr>)
+                .value_parser(check_type::<IpAddr>)
                 .required(true)
                 .help("connect server's IP address."),
         )
@@ -28,6 +29,14 @@ async fn main() -> Result<(), Error> {
                 .value_parser(check_type::<u16>)
                 .help("connect server's port."),
         )
+        .arg(
+            Arg::new("bind")
+                .short('i')
+                .long("bind")
+                .value_name("IPADDRESS")
+                .value_parser(check_type::<IpAddr>)
+                .help("bind local IP address."),
+        )
         .arg(
             Arg::new("remote_file")
                 .value_name("REMOTE FILE")
@@ -81,9 +90,30 @@ async fn main() -> Result<(), Error> {
                 .value_parser(check_type::<u16>)
                 .help("windowsize."),
         )
+        .arg(
+            Arg::new("ttl")
+                .long("ttl")
+                .value_name("TTL")
+                .value_parser(check_type::<u32>)
+                .help("IP TTL of sending packets."),
+        )
+        .arg(
+            Arg::new("tos")
+                .long("tos")
+                .value_name("TOS")
+                .value_parser(check_type::<u8>)
+                .help("IP ToS (DSCP << 2) of sending packets."),
+        )
+        .arg(
+            Arg::new("max_duration")
+                .long("max-duration")
+                .value_name("SECONDS")
+                .value_parser(check_type::<u64>)
+                .help("abort transfers exceeding this duration."),
+        )
         .get_matches();
 
-    let address = matches.get_one::<Ipv4Addr>("host").unwrap();
+    let address = matches.get_one::<IpAddr>("host").unwrap();
     let port = matches.get_one::<u16>("port").unwrap();
     let remote = matches.get_one::<String>("remote_file").unwrap();
     let local = matches.get_one::<String>("local_file").unwrap();
@@ -108,17 +138,31 @@ async fn main() -> Result<(), Error> {
         builder = builder.windowsize(*windowsize);
     }
 
-    let client = Client::new(
-        format!("{}:{}", address, port).parse()?,
-        mode,
-        builder.build(),
-    );
+    let mut client = Client::new(SocketAddr::new(*address, *port), mode, builder.build());
+
+    if let Some(bind) = matches.get_one::<IpAddr>("bind") {
+        client.set_local_bind(*bind);
+    }
+
+    if let Some(ttl) = matches.get_one::<u32>("ttl") {
+        client.set_ttl(*ttl);
+    }
+
+    if let Some(tos) = matches.get_one::<u8>("tos") {
+        client.set_tos(*tos);
+    }
+
+    if let Some(max_duration) = matches.get_one::<u64>("max_duration") {
+        client.set_max_duration(Duration::from_secs(*max_duration));
+    }
 
     match op.as_str() {
-        "get" => client.get(Path::new(local), remote).await,
-        "put" => client.put(Path::new(local), remote).await,
+        "get" => client.get(Path::new(local), remote).await?,
+        "put" => client.put(Path::new(local), remote).await?,
         _ => unimplemented!(),
-    }
+    };
+
+    Ok(())
 }
 
 fn check_type<T>(value: &str) -> Result<T, String>
@@ -1,9 +1,9 @@
-use clap::{Arg, Command};
-use std::net::Ipv4Addr;
+use clap::{Arg, ArgAction, Command};
+use std::net::{IpAddr, Ipv4Addr};
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
 use tftp::error::Error;
-use tftp::options::OptionBuilder;
 use tftp::server::Server;
 
 #[tokio::main]
@@ -16,28 +16,33 @@ async fn main() -> Result<(), Error> {
             Arg::new("bind")
                 .short('i')
                 .long("bind")
-                .default_value("0.0.0.0")
                 .value_name("IPADDRESS")
                 .value_parser(check_type::<Ipv4Addr>)
-                .help("bind server's IP address."),
+                .help("bind server's IP address. [env: TFTP_BIND] [default: 0.0.0.0]"),
         )
         .arg(
             Arg::new("port")
                 .short('p')
                 .long("port")
-                .default_value("69")
                 .value_name("PORT")
                 .value_parser(check_type::<u16>)
-                .help("bind server's port."),
+                .help("bind server's port. [env: TFTP_PORT] [default: 69]"),
         )
         .arg(
             Arg::new("root")
                 .short('r')
                 .long("root")
-                .default_value(".")
                 .value_name("PATH")
                 .value_parser(check_root)
-                .help("publish TFTP root directory."),
+                .help("publish TFTP root directory. [env: TFTP_ROOT] [default: .]"),
+        )
+        .arg(
+            Arg::new("allow_mode")
+                .long("allow-mode")
+                .value_name("MODE")
+                .value_parser(["netascii", "octet", "mail"])
+                .action(ArgAction::Append)
+                .help("allowed transfer mode."),
         )
         .arg(
             Arg::new("blksize")
@@ -63,20 +68,49 @@ async fn main() -> Result<(), Error> {
                 .value_parser(check_type::<u16>)
                 .help("windowsize."),
         )
+        .arg(
+            Arg::new("ttl")
+                .long("ttl")
+                .value_name("TTL")
+                .value_parser(check_type::<u32>)
+                .help("IP TTL of sending packets."),
+        )
+        .arg(
+            Arg::new("tos")
+                .long("tos")
+                .value_name("TOS")
+                .value_parser(check_type::<u8>)
+                .help("IP ToS (DSCP << 2) of sending packets."),
+        )
+        .arg(
+            Arg::new("max_duration")
+                .long("max-duration")
+                .value_name("SECONDS")
+                .value_parser(check_type::<u64>)
+                .help("abort transfers exceeding this duration."),
+        )
         .get_matches();
 
-    let address = matches.get_one::<Ipv4Addr>("bind").unwrap();
-    let port = matches.get_one::<u16>("port").unwrap();
-    let root = matches.get_one::<String>("root").unwrap();
+    let mut builder = Server::from_env()?;
+
+    if let Some(address) = matches.get_one::<Ipv4Addr>("bind") {
+        builder = builder.bind(IpAddr::from(*address));
+    }
+
+    if let Some(port) = matches.get_one::<u16>("port") {
+        builder = builder.port(*port);
+    }
 
-    let mut builder = OptionBuilder::default();
+    if let Some(root) = matches.get_one::<String>("root") {
+        builder = builder.root(Path::new(root));
+    }
 
     if let Some(blksize) = matches.get_one::<u16>("blksize") {
         builder = builder.blksize(*blksize);
     }
 
     if matches.get_flag("timeout") {
-        builder = builder.timeout(0);
+        builder = builder.timeout();
     }
 
     if matches.get_flag("tsize") {
@@ -87,11 +121,25 @@ async fn main() -> Result<(), Error> {
         builder = builder.windowsize(*windowsize);
     }
 
-    let server = Server::new(
-        format!("{0}:{1}", address, port).parse()?,
-        Path::new(root),
-        builder.build(),
-    )?;
+    let mut server = builder.build()?;
+
+    if let Some(modes) = matches.get_many::<String>("allow_mode") {
+        let modes: Vec<&str> = modes.map(|m| m.as_str()).collect();
+        server = server.with_allowed_modes(&modes);
+    }
+
+    if let Some(ttl) = matches.get_one::<u32>("ttl") {
+        server = server.with_ttl(*ttl);
+    }
+
+    if let Some(tos) = matches.get_one::<u8>("tos") {
+        server = server.with_tos(*tos);
+    }
+
+    if let Some(max_duration) = matches.get_one::<u64>("max_duration") {
+        server = server.with_max_duration(Duration::from_secs(*max_duration));
+    }
+
     server.serve_forever().await?;
     Ok(())
 }
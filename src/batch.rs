@@ -0,0 +1,171 @@
+use bytes::Bytes;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+// 接続済みソケットに複数のデータグラムを sendmmsg でまとめて送信する。
+// 送信済みの数を sent に保持し、失敗後に呼び直した場合は続きから送信する。
+pub async fn send_all(sock: &UdpSocket, packets: &[Bytes], sent: &AtomicUsize) -> io::Result<()> {
+    while sent.load(Ordering::Relaxed) < packets.len() {
+        sock.writable().await?;
+        let start = sent.load(Ordering::Relaxed);
+        match sock.try_io(Interest::WRITABLE, || sendmmsg(sock, &packets[start..])) {
+            Ok(n) => {
+                sent.fetch_add(n, Ordering::Relaxed);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+// 受信済みのデータグラムを recvmmsg でまとめて最大 max 個受信する。1 個も届いていなければ待つ。
+pub async fn recv_all(sock: &UdpSocket, size: usize, max: usize) -> io::Result<Vec<Bytes>> {
+    loop {
+        sock.readable().await?;
+        match sock.try_io(Interest::READABLE, || recvmmsg(sock, size, max)) {
+            Ok(packets) => return Ok(packets),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn sendmmsg(sock: &UdpSocket, packets: &[Bytes]) -> io::Result<usize> {
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|p| libc::iovec {
+            iov_base: p.as_ptr() as *mut libc::c_void,
+            iov_len: p.len(),
+        })
+        .collect();
+
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iov| {
+            // SAFETY: mmsghdr は全てのフィールドが 0 で有効な値になる。
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // SAFETY: msgs と iovecs は呼び出しの間有効で、送信元の packets も借用している。
+    let ret = unsafe { libc::sendmmsg(sock.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret as usize)
+}
+
+fn recvmmsg(sock: &UdpSocket, size: usize, max: usize) -> io::Result<Vec<Bytes>> {
+    let mut bufs: Vec<Vec<u8>> = (0..max.max(1)).map(|_| vec![0u8; size]).collect();
+    let mut iovecs: Vec<libc::iovec> = bufs
+        .iter_mut()
+        .map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut libc::c_void,
+            iov_len: b.len(),
+        })
+        .collect();
+
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iov| {
+            // SAFETY: mmsghdr は全てのフィールドが 0 で有効な値になる。
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect();
+
+    // SAFETY: msgs と iovecs は呼び出しの間有効で、受信先の bufs も借用している。
+    let ret = unsafe {
+        libc::recvmmsg(
+            sock.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as _,
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let lens: Vec<usize> = msgs[..ret as usize]
+        .iter()
+        .map(|m| m.msg_len as usize)
+        .collect();
+    Ok(bufs
+        .into_iter()
+        .zip(lens)
+        .map(|(mut buf, len)| {
+            buf.truncate(len);
+            Bytes::from(buf)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn send_all_ok() -> io::Result<()> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        sock.connect(peer.local_addr()?).await?;
+
+        let packets = vec![Bytes::from("a"), Bytes::from("bc"), Bytes::from("def")];
+        let sent = AtomicUsize::new(0);
+        send_all(&sock, &packets, &sent).await?;
+        assert_eq!(3, sent.load(Ordering::Relaxed));
+
+        let mut buf = vec![0; 16];
+        for packet in packets {
+            let size = peer.recv(&mut buf).await?;
+            assert_eq!(packet.as_ref(), &buf[..size]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_all_resume() -> io::Result<()> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        sock.connect(peer.local_addr()?).await?;
+
+        // 先頭の 2 個は送信済みとして続きから送信する。
+        let packets = vec![Bytes::from("a"), Bytes::from("bc"), Bytes::from("def")];
+        let sent = AtomicUsize::new(2);
+        send_all(&sock, &packets, &sent).await?;
+
+        let mut buf = vec![0; 16];
+        let size = peer.recv(&mut buf).await?;
+        assert_eq!(b"def", &buf[..size]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn recv_all_ok() -> io::Result<()> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        sock.connect(peer.local_addr()?).await?;
+
+        for packet in ["a", "bc", "def"] {
+            peer.send_to(packet.as_bytes(), sock.local_addr()?).await?;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let packets = recv_all(&sock, 16, 8).await?;
+        assert_eq!(vec!["a", "bc", "def"], packets);
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use super::error::Error;
+use bytes::Bytes;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::fs;
+
+#[derive(Debug)]
+pub struct FileCache {
+    capacity: usize,
+    // 末尾ほど最近使用したエントリ。
+    entries: Mutex<Vec<CacheEntry>>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    path: PathBuf,
+    modified: SystemTime,
+    content: Bytes,
+}
+
+impl FileCache {
+    pub fn new(capacity: usize) -> Self {
+        FileCache {
+            capacity,
+            entries: Mutex::new(vec![]),
+        }
+    }
+
+    pub async fn get(&self, path: &Path) -> Result<Bytes, Error> {
+        let modified = fs::metadata(path).await?.modified()?;
+
+        if let Some(content) = self.lookup(path, modified) {
+            return Ok(content);
+        }
+
+        let content = Bytes::from(fs::read(path).await?);
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|e| e.path != path);
+        entries.push(CacheEntry {
+            path: path.to_path_buf(),
+            modified,
+            content: content.clone(),
+        });
+        if entries.len() > self.capacity {
+            entries.remove(0);
+        }
+
+        Ok(content)
+    }
+
+    fn lookup(&self, path: &Path, modified: SystemTime) -> Option<Bytes> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries
+            .iter()
+            .position(|e| e.path == path && e.modified == modified)?;
+        let entry = entries.remove(index);
+        let content = entry.content.clone();
+        entries.push(entry);
+        Some(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::options::Options;
+    use crate::server::Server;
+    use crate::testutil;
+
+    #[tokio::test]
+    async fn get_evict_lru() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        for name in ["a", "b", "c"] {
+            std::fs::write(root.path().join(name), name)?;
+        }
+
+        let cache = FileCache::new(2);
+        cache.get(&root.path().join("a")).await?;
+        cache.get(&root.path().join("b")).await?;
+        cache.get(&root.path().join("a")).await?;
+        cache.get(&root.path().join("c")).await?;
+
+        let entries = cache.entries.lock().unwrap();
+        let paths: Vec<&PathBuf> = entries.iter().map(|e| &e.path).collect();
+        assert_eq!(vec![&root.path().join("a"), &root.path().join("c")], paths);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_concurrent_with_cache() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_file_cache(4);
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let (local1, local2) = (local.path().join("1"), local.path().join("2"));
+        let client = Client::new(addr, "octet", Options::default());
+        let (ret1, ret2) = tokio::join!(client.get(&local1, "a"), client.get(&local2, "a"));
+        ret1?;
+        ret2?;
+
+        assert_eq!(content, std::fs::read(local1)?);
+        assert_eq!(content, std::fs::read(local2)?);
+        Ok(())
+    }
+}
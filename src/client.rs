@@ -1,19 +1,105 @@
+#[cfg(feature = "digest")]
+use super::digest::Digest;
 use super::error::Error;
 use super::file;
 use super::handle_packet;
-use super::options::Options;
+use super::options::{OptionBuilder, Options};
 use super::packet;
+use super::recorder::{BlockTiming, PacketTrace, Recorder};
 use super::session;
-use super::OpCode;
-use std::net::SocketAddr;
+#[cfg(feature = "socks5")]
+use super::socks5;
+use super::{apply_tos, apply_ttl, bind_socket, ErrorCode, OpCode, SocketConfig};
+use bytes::Bytes;
+use log::{error, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use tokio::fs::File;
-use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+
+const PROBE_FILENAME: &str = ".tftp-probe-nonexistent";
+const DEFAULT_PORT: u16 = 69;
+// 転送したブロック数に対する再送回数の割合 (%) がこれを超えた場合に警告する。
+const HIGH_RETRANSMIT_RATE: u64 = 10;
+
+// ERROR OptionNotSupport で拒否された場合は転送されず、Error::PeerError として失敗する。
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionNegotiation {
+    Accepted(Options),
+    Ignored,
+}
+
+// 転送は完了したが、注意が必要な事象。
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    HighRetransmitRate { retransmits: u32, blocks: u64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct TransferStats {
+    bytes: u64,
+    elapsed: Duration,
+    negotiation: OptionNegotiation,
+    warnings: Vec<Warning>,
+    confirmed: bool,
+}
+
+impl TransferStats {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn elapsed(&self) -> &Duration {
+        &self.elapsed
+    }
+
+    pub fn negotiation(&self) -> &OptionNegotiation {
+        &self.negotiation
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // x-confirm によってサーバと転送完了を確認できたか。
+    pub fn confirmed(&self) -> bool {
+        self.confirmed
+    }
+}
 
 pub struct Client {
     remote_addr: SocketAddr,
     mode: String,
     options: Options,
+    peer_error_handler: Option<session::PeerErrorHandler>,
+    #[cfg(feature = "socks5")]
+    socks5_proxy: Option<SocketAddr>,
+    recorder: Option<Recorder>,
+    preallocate: bool,
+    local_bind: Option<IpAddr>,
+    min_transfer_rate: Option<u64>,
+    max_duration: Option<Duration>,
+    min_timeout: Option<Duration>,
+    ack_frequency: Option<u16>,
+    batch_io: bool,
+    read_ahead: bool,
+    resume: bool,
+    retryable_error_codes: Vec<ErrorCode>,
+    socket_config: Option<SocketConfig>,
+    first_block_timeout: Option<Duration>,
+    dally: Option<Duration>,
+    ttl: Option<u32>,
+    tos: Option<u8>,
+    busy_retry: Option<(u32, Duration)>,
+    concurrency: usize,
+    adaptive_window: bool,
 }
 
 impl Client {
@@ -22,42 +108,1861 @@ impl Client {
             remote_addr,
             mode: mode.to_string(),
             options,
+            peer_error_handler: None,
+            #[cfg(feature = "socks5")]
+            socks5_proxy: None,
+            recorder: None,
+            preallocate: false,
+            local_bind: None,
+            min_transfer_rate: None,
+            max_duration: None,
+            min_timeout: None,
+            ack_frequency: None,
+            batch_io: false,
+            read_ahead: false,
+            resume: false,
+            retryable_error_codes: vec![],
+            socket_config: None,
+            first_block_timeout: None,
+            dally: None,
+            ttl: None,
+            tos: None,
+            busy_retry: None,
+            concurrency: 1,
+            adaptive_window: false,
+        }
+    }
+
+    // key=value 形式の設定ファイルから作成する。# で始まる行はコメントとする。
+    pub fn from_config(path: &Path) -> Result<Client, Error> {
+        let mut host = None;
+        let mut port = DEFAULT_PORT;
+        let mut mode = "octet".to_string();
+        let mut builder = OptionBuilder::default();
+
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| Error::InvalidConfig(line.to_string()))?;
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "host" => host = Some(value.to_string()),
+                "port" => port = config_value(key, value)?,
+                "mode" => {
+                    if value != "netascii" && value != "octet" {
+                        return Err(Error::InvalidConfig(key.to_string()));
+                    }
+                    mode = value.to_string();
+                }
+                "blksize" => {
+                    let blksize = config_value::<u16>(key, value)?;
+                    if !(8..=65464).contains(&blksize) {
+                        return Err(Error::InvalidConfig(key.to_string()));
+                    }
+                    builder = builder.blksize(blksize);
+                }
+                "timeout" => {
+                    let timeout = config_value::<u8>(key, value)?;
+                    if timeout < 1 {
+                        return Err(Error::InvalidConfig(key.to_string()));
+                    }
+                    builder = builder.timeout(timeout);
+                }
+                "tsize" => {
+                    if config_value::<bool>(key, value)? {
+                        builder = builder.tsize();
+                    }
+                }
+                "windowsize" => {
+                    let windowsize = config_value::<u16>(key, value)?;
+                    if windowsize < 1 {
+                        return Err(Error::InvalidConfig(key.to_string()));
+                    }
+                    builder = builder.windowsize(windowsize);
+                }
+                _ => return Err(Error::InvalidConfig(key.to_string())),
+            }
+        }
+
+        let host = host.ok_or_else(|| Error::InvalidConfig("host".to_string()))?;
+        let remote_addr = (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::InvalidConfig("host".to_string()))?;
+
+        Ok(Client::new(remote_addr, &mode, builder.build()))
+    }
+
+    pub fn set_local_bind(&mut self, ip: IpAddr) {
+        self.local_bind = Some(ip);
+    }
+
+    // ソケットをバインドする前に呼び出し、任意のソケットオプションを設定する。
+    pub fn set_socket_config(
+        &mut self,
+        f: impl Fn(&socket2::Socket) -> std::io::Result<()> + Send + Sync + 'static,
+    ) {
+        self.socket_config = Some(Arc::new(f));
+    }
+
+    // put のウィンドウ内の DATA を sendmmsg でまとめて送信する (batch-io フィーチャ、Linux のみ)。
+    pub fn set_batch_io(&mut self, batch_io: bool) {
+        self.batch_io = batch_io;
+    }
+
+    // put で ACK を待つ間に次のウィンドウを先読みする。読み込みの遅いディスク向け。
+    pub fn set_read_ahead(&mut self, read_ahead: bool) {
+        self.read_ahead = read_ahead;
+    }
+
+    // RRQ の受信時に frequency ブロックごとに ACK を送信する。
+    pub fn set_ack_frequency(&mut self, frequency: u16) {
+        self.ack_frequency = Some(frequency);
+    }
+
+    // 送信するパケットの TTL。ルーティングさせない場合は 1 を指定する。
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = Some(ttl);
+    }
+
+    // 送信するパケットの ToS。DSCP を指定する場合は 2 ビット左にシフトした値を指定する。
+    pub fn set_tos(&mut self, tos: u8) {
+        self.tos = Some(tos);
+    }
+
+    // RRQ の最後の ACK を送信した後、再送された最後の DATA を待つ時間。
+    pub fn set_dally(&mut self, dally: Duration) {
+        self.dally = Some(dally);
+    }
+
+    // 要求を送信してから最初のブロックを待つ時間。
+    pub fn set_first_block_timeout(&mut self, timeout: Duration) {
+        self.first_block_timeout = Some(timeout);
+    }
+
+    // 再送までの待ち時間を timeout オプションより短くする。
+    pub fn set_min_timeout(&mut self, timeout: Duration) {
+        self.min_timeout = Some(timeout);
+    }
+
+    // 転送速度 (bytes/sec) がこれを下回る場合は中断する。
+    pub fn set_min_transfer_rate(&mut self, rate: u64) {
+        self.min_transfer_rate = Some(rate);
+    }
+
+    // 受信が続いていても、この時間を超える転送は中断する。
+    pub fn set_max_duration(&mut self, duration: Duration) {
+        self.max_duration = Some(duration);
+    }
+
+    // octet モードの get でローカルファイルが既に存在する場合、その続きから受信する。
+    // サーバが x-offset に対応していない場合は最初から受信し直す。
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    // tsize を取得できた場合、受信前にファイルサイズを確保する。
+    pub fn set_preallocate(&mut self, preallocate: bool) {
+        self.preallocate = preallocate;
+    }
+
+    pub fn with_trace(&mut self) {
+        self.recorder = Some(Recorder::default());
+    }
+
+    pub fn trace(&self) -> Vec<PacketTrace> {
+        match self.recorder.as_ref() {
+            Some(recorder) => recorder.packets(),
+            _ => vec![],
+        }
+    }
+
+    // with_trace を有効にした put で、DATA ブロックごとの送信と ACK 受信の時刻を返す。
+    pub fn block_timings(&self) -> Vec<BlockTiming> {
+        match self.recorder.as_ref() {
+            Some(recorder) => recorder.timings(),
+            _ => vec![],
+        }
+    }
+
+    pub fn on_peer_error(&mut self, cb: impl Fn(ErrorCode, &str) + Send + Sync + 'static) {
+        self.peer_error_handler = Some(Arc::new(cb));
+    }
+
+    #[cfg(feature = "socks5")]
+    pub fn set_socks5_proxy(&mut self, proxy_addr: SocketAddr) {
+        self.socks5_proxy = Some(proxy_addr);
+    }
+
+    // サーバが混雑を理由に拒否した場合、delay 待ってから attempts 回まで要求を送り直す。
+    pub fn set_busy_retry(&mut self, attempts: u32, delay: Duration) {
+        self.busy_retry = Some((attempts, delay));
+    }
+
+    // busy に加えて、これらのエラーコードの ERROR を受信した場合も set_busy_retry の設定で送り直す。
+    // FileNotFound など、それ以外のエラーコードは送り直さない。
+    pub fn set_retryable_error_codes(&mut self, codes: &[ErrorCode]) {
+        self.retryable_error_codes = codes.to_vec();
+    }
+
+    // get_with_retries でやり直す際に、損失を減らすため windowsize を半分にして要求する。
+    pub fn set_adaptive_window(&mut self, adaptive: bool) {
+        self.adaptive_window = adaptive;
+    }
+
+    // get_many で同時に実行する転送数。
+    pub fn set_concurrency(&mut self, concurrency: usize) {
+        self.concurrency = concurrency.max(1);
+    }
+
+    // 複数のファイルを取得する。失敗したファイルがあっても残りの転送は続ける。
+    pub async fn get_many(&self, pairs: &[(&Path, &str)]) -> Vec<Result<TransferStats, Error>> {
+        let pending = pairs
+            .iter()
+            .enumerate()
+            .map(|(i, (local_file, remote_file))| {
+                (
+                    i,
+                    Box::pin(self.get(local_file, remote_file)) as TransferFuture,
+                )
+            })
+            .collect();
+
+        Transfers {
+            pending,
+            running: vec![],
+            results: pairs.iter().map(|_| None).collect(),
+            concurrency: self.concurrency,
+        }
+        .await
+    }
+
+    pub async fn get(&self, local_file: &Path, remote_file: &str) -> Result<TransferStats, Error> {
+        self.get_with_options(local_file, remote_file, &self.options)
+            .await
+    }
+
+    async fn get_with_options(
+        &self,
+        local_file: &Path,
+        remote_file: &str,
+        options: &Options,
+    ) -> Result<TransferStats, Error> {
+        let mut attempt = 1;
+        let mut resume = self.resume;
+        loop {
+            let offset = if resume {
+                self.resume_offset(local_file).await
+            } else {
+                None
+            };
+
+            let mut options = options.clone();
+            let local = match offset {
+                Some(offset) => {
+                    options.set_offset(offset);
+                    file::open_append(local_file).await?
+                }
+                _ => file::open_create(local_file).await?,
+            };
+
+            let req = packet::Request::rrq(remote_file, &self.mode, &options);
+
+            let ret = self.handl_request(req, local, |_| {}).await;
+            if matches!(ret, Err(Error::ResumeRejected)) {
+                warn!("[{}] resume rejected. restart", self.remote_addr);
+                file::remove(local_file).await;
+                resume = false;
+                continue;
+            }
+            if matches!(&ret, Err(e) if self.is_retryable(e))
+                && self.wait_busy_retry(&mut attempt).await
+            {
+                if offset.is_none() {
+                    file::remove(local_file).await;
+                }
+                continue;
+            }
+            return ret;
+        }
+    }
+
+    // ローカルファイルの更新日時を x-mtime で送り、サーバのファイルの方が新しい場合のみ取得する。
+    // 変更されていない場合は None を返し、ローカルファイルはそのまま残す。
+    pub async fn get_if_modified(
+        &self,
+        local_file: &Path,
+        remote_file: &str,
+    ) -> Result<Option<TransferStats>, Error> {
+        let mtime = match file::mtime(local_file).await {
+            Ok(mtime) => mtime,
+            _ => return self.get(local_file, remote_file).await.map(Some),
+        };
+
+        let mut options = self.options.clone();
+        options.set_mtime(Some(mtime));
+
+        // 受信に失敗してもローカルファイルを壊さないよう、一時ファイルに受信してから置き換える。
+        let temp_file = file::temp_path(local_file);
+        let local = file::open_create(&temp_file).await?;
+
+        let req = packet::Request::rrq(remote_file, &self.mode, &options);
+
+        match self.handl_request(req, local, |_| {}).await {
+            Ok(stats) => {
+                file::persist(&temp_file, local_file).await?;
+                Ok(Some(stats))
+            }
+            Err(Error::NotModified) => {
+                file::remove(&temp_file).await;
+                Ok(None)
+            }
+            Err(e) => {
+                file::remove(&temp_file).await;
+                Err(e)
+            }
         }
     }
 
-    pub async fn get(&self, local_file: &Path, remote_file: &str) -> Result<(), Error> {
+    async fn resume_offset(&self, local_file: &Path) -> Option<u64> {
+        if !self.mode.eq_ignore_ascii_case("octet") {
+            return None;
+        }
+
+        tokio::fs::metadata(local_file)
+            .await
+            .ok()
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+    }
+
+    // ローカルファイルに書き込んだデータの SHA-256 を転送と同時に計算する。
+    #[cfg(feature = "digest")]
+    pub async fn get_with_digest(
+        &self,
+        local_file: &Path,
+        remote_file: &str,
+    ) -> Result<(TransferStats, [u8; 32]), Error> {
         let local = file::open_create(local_file).await?;
 
         let req = packet::Request::rrq(remote_file, &self.mode, &self.options);
 
-        self.handl_request(req, local).await
+        let digest = Digest::default();
+        let stats = self
+            .handl_request(req, local, |s| s.set_digest(&digest))
+            .await?;
+        Ok((stats, digest.finalize()))
+    }
+
+    // 一時的な障害で失敗した場合は、転送全体を最初からやり直す。
+    pub async fn get_with_retries(
+        &self,
+        local_file: &Path,
+        remote_file: &str,
+        attempts: u32,
+    ) -> Result<TransferStats, Error> {
+        let mut options = self.options.clone();
+        let mut attempt = 1;
+        loop {
+            match self
+                .get_with_options(local_file, remote_file, &options)
+                .await
+            {
+                Err(e) if attempt < attempts && e.is_transient() => {
+                    warn!("[{}] failed to get. retry: {:?}", self.remote_addr, e);
+                    if !self.resume {
+                        file::remove(local_file).await;
+                    }
+                    attempt += 1;
+
+                    if self.adaptive_window && options.windowsize() > 1 {
+                        options.set_windowsize(options.windowsize() / 2);
+                        warn!(
+                            "[{}] reduced windowsize: {}",
+                            self.remote_addr,
+                            options.windowsize()
+                        );
+                    }
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    pub async fn put(&self, local_file: &Path, remote_file: &str) -> Result<TransferStats, Error> {
+        let local_file = local_file.canonicalize()?;
+
+        let mut attempt = 1;
+        loop {
+            let local = file::open_read(&local_file).await?;
+
+            let mut req = packet::Request::wrq(remote_file, &self.mode, &self.options);
+            req.options_mut().set_tsize(&local_file, &self.mode).await?;
+
+            let ret = self.handl_request(req, local, |_| {}).await;
+            if matches!(&ret, Err(e) if self.is_retryable(e))
+                && self.wait_busy_retry(&mut attempt).await
+            {
+                continue;
+            }
+            return ret;
+        }
     }
 
-    pub async fn put(&self, local_file: &Path, remote_file: &str) -> Result<(), Error> {
+    // ローカルファイルから読み込んだデータの SHA-256 を転送と同時に計算する。
+    #[cfg(feature = "digest")]
+    pub async fn put_with_digest(
+        &self,
+        local_file: &Path,
+        remote_file: &str,
+    ) -> Result<(TransferStats, [u8; 32]), Error> {
         let local_file = local_file.canonicalize()?;
         let local = file::open_read(&local_file).await?;
 
         let mut req = packet::Request::wrq(remote_file, &self.mode, &self.options);
-        req.options_mut().set_tsize(&local_file);
+        req.options_mut().set_tsize(&local_file, &self.mode).await?;
 
-        self.handl_request(req, local).await
+        let digest = Digest::default();
+        let stats = self
+            .handl_request(req, local, |s| s.set_digest(&digest))
+            .await?;
+        Ok((stats, digest.finalize()))
     }
 
-    async fn handl_request(&self, req: packet::Request, file: File) -> Result<(), Error> {
-        let sock = UdpSocket::bind("0.0.0.0:0").await?;
+    pub async fn probe(&self) -> Result<Duration, Error> {
+        let req = packet::Request::rrq(PROBE_FILENAME, "octet", &Options::default());
+
+        let mut session = self.open_session().await?;
+
+        let started_at = Instant::now();
+        let (_, mut buf) = session.send_req_recv_data(&req).await?;
+        let elapsed = started_at.elapsed();
+
+        if !matches!(packet::parse_opcode(&mut buf)?, Some(OpCode::Error)) {
+            // 転送が開始された場合は中断する。
+            session.send_error(&Error::Aborted).await?;
+        }
+
+        Ok(elapsed)
+    }
+
+    // x-head オプションでファイルを転送せずに tsize を問い合わせる。
+    // サーバが tsize を返さない場合は None を返す。
+    pub async fn size(&self, remote_file: &str) -> Result<Option<u64>, Error> {
+        let options = OptionBuilder::default().head().build();
+        let req = packet::Request::rrq(remote_file, &self.mode, &options);
+
+        let mut session = self.open_session().await?;
+        let (_, mut buf) = session.send_req_recv_data(&req).await?;
+
+        let size = match packet::parse_opcode(&mut buf)? {
+            Some(OpCode::Error) => {
+                let error = packet::parse_error(&mut buf)?;
+                return match ErrorCode::from(error.error_code()) {
+                    ErrorCode::FileNotFound => Err(Error::FileNotFound),
+                    ErrorCode::AccessViolation => Err(Error::AccessViolation),
+                    _ => Ok(None),
+                };
+            }
+            Some(OpCode::Oack) => {
+                let options = packet::parse_oack(&mut buf)?;
+                // x-head を返したサーバは必ず tsize を含める。
+                if options.head() || options.tsize() != 0 {
+                    Some(options.tsize())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        // x-head に対応していないサーバは転送を開始するため中断する。
+        session.send_error(&Error::Aborted).await?;
+        Ok(size)
+    }
+
+    // 要求を送信して最初の応答を受信し、オプションの交渉までを行う。
+    // ローカルファイルは作成されるため、転送しない場合も空のファイルが残る。
+    pub async fn connect_get(
+        &self,
+        local_file: &Path,
+        remote_file: &str,
+    ) -> Result<Transfer, Error> {
+        let local = file::open_create(local_file).await?;
+        let req = packet::Request::rrq(remote_file, &self.mode, &self.options);
+        self.connect(req, local, |_| {}).await
+    }
+
+    pub async fn connect_put(
+        &self,
+        local_file: &Path,
+        remote_file: &str,
+    ) -> Result<Transfer, Error> {
+        let local_file = local_file.canonicalize()?;
+        let local = file::open_read(&local_file).await?;
+
+        let mut req = packet::Request::wrq(remote_file, &self.mode, &self.options);
+        req.options_mut().set_tsize(&local_file, &self.mode).await?;
+        self.connect(req, local, |_| {}).await
+    }
+
+    // 混雑による拒否と、設定したエラーコードの ERROR を受信した場合は要求を送り直す。
+    // 送り直しても成功しなかった場合は、受信したエラーをそのまま返す。
+    fn is_retryable(&self, e: &Error) -> bool {
+        match e {
+            Error::ServerBusy => true,
+            Error::FileNotFound
+            | Error::AccessViolation
+            | Error::NoSuchUser
+            | Error::PeerError(..) => self.retryable_error_codes.contains(&e.error_code()),
+            _ => false,
+        }
+    }
+
+    async fn wait_busy_retry(&self, attempt: &mut u32) -> bool {
+        match self.busy_retry {
+            Some((attempts, delay)) if *attempt < attempts => {
+                warn!("[{}] server busy. retry", self.remote_addr);
+                time::sleep(delay).await;
+                *attempt += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    async fn open_session(&self) -> Result<session::TftpSession, Error> {
+        let local_ip = self.local_bind.unwrap_or(match self.remote_addr {
+            SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+        });
+        let sock = bind_socket(SocketAddr::new(local_ip, 0), self.socket_config.as_ref())?;
+        apply_ttl(&sock, self.ttl)?;
+        apply_tos(&sock, self.tos)?;
+
+        #[cfg(feature = "socks5")]
+        let socks5 = match self.socks5_proxy {
+            Some(proxy_addr) => {
+                let socks5 = socks5::UdpAssociate::connect(proxy_addr, sock.local_addr()?).await?;
+                sock.connect(socks5.relay_addr()).await?;
+                Some(socks5)
+            }
+            _ => None,
+        };
 
         let mut session = session::TftpSession::new(sock, self.remote_addr);
+        #[cfg(feature = "socks5")]
+        if let Some(socks5) = socks5 {
+            session.set_socks5(socks5);
+        }
+        session.set_peer_error_handler(self.peer_error_handler.clone());
+        session.set_min_transfer_rate(self.min_transfer_rate);
+        session.set_max_duration(self.max_duration);
+        session.set_batch_io(self.batch_io);
+        session.set_read_ahead(self.read_ahead);
+        session.set_min_timeout(self.min_timeout);
+        session.set_ack_frequency(self.ack_frequency);
+        session.set_first_block_timeout(self.first_block_timeout);
+        session.set_dally(self.dally);
+
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.clear();
+        }
+        session.set_recorder(self.recorder.clone());
+
+        Ok(session)
+    }
+
+    async fn handl_request(
+        &self,
+        req: packet::Request,
+        file: File,
+        prepare: impl FnOnce(&mut session::TftpSession),
+    ) -> Result<TransferStats, Error> {
+        self.connect(req, file, prepare).await?.complete().await
+    }
+
+    async fn connect(
+        &self,
+        req: packet::Request,
+        file: File,
+        prepare: impl FnOnce(&mut session::TftpSession),
+    ) -> Result<Transfer, Error> {
+        let started_at = Instant::now();
+
+        let mut session = self.open_session().await?;
         session.set_mode(req.mode());
         match *req.op_code() {
-            OpCode::Rrq => session.set_writer(file),
+            OpCode::Rrq => {
+                // 再開する場合は既存の内容を切り詰めないよう確保しない。
+                if self.preallocate && req.options().offset() == 0 {
+                    session.set_preallocate(Some(file.try_clone().await?));
+                }
+                session.set_writer(Box::new(file));
+            }
             OpCode::Wrq => session.set_reader(file),
             _ => panic!(),
         }
+        prepare(&mut session);
 
         let (_, buf) = session.send_req_recv_data(&req).await?;
 
-        handle_packet(req.op_code(), &mut session, buf).await?;
+        let op_code = packet::parse_opcode(&mut buf.clone())?;
+
+        let offset = req.options().offset();
+        if offset > 0 && op_code != Some(OpCode::Error) {
+            let accepted = match op_code {
+                Some(OpCode::Oack) => packet::parse_oack(&mut buf.slice(2..))?.offset() == offset,
+                _ => false,
+            };
+            if !accepted {
+                session.send_error(&Error::ResumeRejected).await?;
+                return Err(Error::ResumeRejected);
+            }
+        }
+
+        // x-mtime をそのまま返した OACK は、ファイルが変更されていないことを示す。
+        if let (Some(mtime), Some(OpCode::Oack)) = (req.options().mtime(), &op_code) {
+            if packet::parse_oack(&mut buf.slice(2..))?.mtime() == Some(mtime) {
+                return Err(Error::NotModified);
+            }
+        }
+
+        let (phase, options) = match op_code {
+            Some(OpCode::Oack) => (
+                TransferPhase::Negotiated,
+                packet::parse_oack(&mut buf.slice(2..))?,
+            ),
+            Some(OpCode::Error) => (TransferPhase::Refused, Options::default()),
+            _ => (TransferPhase::Started, Options::default()),
+        };
+
+        Ok(Transfer {
+            req_code: req.op_code().clone(),
+            session,
+            reply: buf,
+            options,
+            phase,
+            started_at,
+        })
+    }
+}
+
+// 要求に対する最初の応答の種類。
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferPhase {
+    // OACK を受信し、オプションの交渉を終えた。
+    Negotiated,
+    // サーバがオプションを返さずに DATA (RRQ) または ACK (WRQ) を返した。
+    Started,
+    // サーバが ERROR を返した。
+    Refused,
+}
+
+// 接続とオプションの交渉を終え、転送の開始を待っている状態。
+// complete で転送を完了させるか、abort で中断する。
+pub struct Transfer {
+    req_code: OpCode,
+    session: session::TftpSession,
+    reply: Bytes,
+    options: Options,
+    phase: TransferPhase,
+    started_at: Instant,
+}
+
+impl Transfer {
+    // 応答を返したサーバのアドレス (TID)。
+    pub fn remote_tid(&self) -> &SocketAddr {
+        self.session.remote_addr()
+    }
+
+    // OACK で合意したオプション。OACK がない場合は既定値になる。
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn phase(&self) -> TransferPhase {
+        self.phase
+    }
+
+    pub async fn abort(self) -> Result<(), Error> {
+        if self.phase != TransferPhase::Refused {
+            self.session.send_error(&Error::Aborted).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn complete(self) -> Result<TransferStats, Error> {
+        let Transfer {
+            req_code,
+            mut session,
+            reply: buf,
+            started_at,
+            ..
+        } = self;
+        let op_code = packet::parse_opcode(&mut buf.clone())?;
+
+        if let Err(e) = handle_packet(&req_code, &mut session, buf.clone()).await {
+            if let Err(e) = session.send_error(&e).await {
+                error!("failed to send error: [{}] {:?}", session.remote_addr(), e);
+            }
+            return Err(e);
+        }
+
+        let negotiation = match op_code {
+            Some(OpCode::Oack) => OptionNegotiation::Accepted(session.options().clone()),
+            _ => OptionNegotiation::Ignored,
+        };
+
+        let mut warnings = vec![];
+        let (retransmits, blocks) = (session.retransmits(), session.transferred_blocks());
+        if retransmits as u64 * 100 > blocks.max(1) * HIGH_RETRANSMIT_RATE {
+            warnings.push(Warning::HighRetransmitRate {
+                retransmits,
+                blocks,
+            });
+        }
+
+        Ok(TransferStats {
+            bytes: session.transferred_bytes(),
+            elapsed: started_at.elapsed(),
+            negotiation,
+            warnings,
+            confirmed: session.confirmed(),
+        })
+    }
+}
+
+type TransferFuture<'a> = Pin<Box<dyn Future<Output = Result<TransferStats, Error>> + 'a>>;
+
+// 同時に実行する数を制限しながら、すべての転送を完了まで進める。
+struct Transfers<'a> {
+    pending: VecDeque<(usize, TransferFuture<'a>)>,
+    running: Vec<(usize, TransferFuture<'a>)>,
+    results: Vec<Option<Result<TransferStats, Error>>>,
+    concurrency: usize,
+}
+
+impl Future for Transfers<'_> {
+    type Output = Vec<Result<TransferStats, Error>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            while this.running.len() < this.concurrency {
+                match this.pending.pop_front() {
+                    Some(transfer) => this.running.push(transfer),
+                    _ => break,
+                }
+            }
+
+            let mut completed = false;
+            let mut i = 0;
+            while i < this.running.len() {
+                match this.running[i].1.as_mut().poll(cx) {
+                    Poll::Ready(ret) => {
+                        let (index, _) = this.running.swap_remove(i);
+                        this.results[index] = Some(ret);
+                        completed = true;
+                    }
+                    Poll::Pending => i += 1,
+                }
+            }
+
+            if this.running.is_empty() {
+                let results = this.results.drain(..).map(|r| r.unwrap()).collect();
+                return Poll::Ready(results);
+            }
+
+            // 空きができた場合は待機中の転送を開始する。
+            if !completed {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+fn config_value<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse::<T>()
+        .map_err(|_| Error::InvalidConfig(key.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::Direction;
+    use crate::server::Server;
+    use crate::testutil;
+    use bytes::{BufMut, BytesMut};
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn get_blksize_reduced_by_server() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().blksize(4096).build();
+        let client = Client::new(addr, "octet", options);
+        client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_get_then_complete() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().blksize(4096).build();
+        let client = Client::new(addr, "octet", options);
+        let transfer = client.connect_get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(TransferPhase::Negotiated, transfer.phase());
+        assert_eq!(1024, transfer.options().blksize());
+        assert_eq!(addr.ip(), transfer.remote_tid().ip());
+        assert_ne!(addr.port(), transfer.remote_tid().port());
+
+        let stats = transfer.complete().await?;
+        assert_eq!(5000, stats.bytes());
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_on_peer_error() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            peer.send_to(b"\x00\x05\x00\x01missing\x00", remote)
+                .await
+                .unwrap();
+        });
+
+        let errors = Arc::new(std::sync::Mutex::new(vec![]));
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        let received = errors.clone();
+        client.on_peer_error(move |code, message| {
+            received.lock().unwrap().push((code, message.to_string()));
+        });
+        let ret = client.get(&local.path().join("a"), "a").await;
+        assert!(matches!(ret, Err(Error::FileNotFound)), "{:?}", ret);
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(1, errors.len());
+        assert_eq!(ErrorCode::FileNotFound, errors[0].0);
+        assert_eq!("missing", errors[0].1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_busy_retry() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            peer.send_to(&packet::error_quiet(&Error::ServerBusy), remote)
+                .await
+                .unwrap();
+
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            sock.send_to(&packet::data(1, &b"abc"[..]), remote)
+                .await
+                .unwrap();
+            sock.recv_from(&mut buf).await.unwrap();
+        });
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_busy_retry(2, Duration::from_millis(10));
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(3, stats.bytes());
+        assert_eq!(b"abc", std::fs::read(local.path().join("a"))?.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_busy_without_retry() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            peer.send_to(&packet::error_quiet(&Error::ServerBusy), remote)
+                .await
+                .unwrap();
+        });
+
+        // 再試行しない場合は ServerBusy として失敗する。
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        let ret = client.get(&local.path().join("a"), "a").await;
+        assert!(matches!(ret, Err(Error::ServerBusy)), "{:?}", ret);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_retryable_error_codes() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let mut disk_full = BytesMut::new();
+            disk_full.put_u16(OpCode::Error as u16);
+            disk_full.put_u16(ErrorCode::DiskFull as u16);
+            disk_full.put_slice(b"full\0");
+            peer.send_to(&disk_full, remote).await.unwrap();
+
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            peer.send_to(&packet::error(&Error::FileNotFound), remote)
+                .await
+                .unwrap();
+
+            // FileNotFound は送り直されない。
+            let ret = time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf)).await;
+            ret.is_err()
+        });
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_busy_retry(3, Duration::from_millis(10));
+        client.set_retryable_error_codes(&[ErrorCode::DiskFull]);
+        let ret = client.get(&local.path().join("a"), "a").await;
+        assert!(matches!(ret, Err(Error::FileNotFound)), "{:?}", ret);
+
+        assert!(handle.await.unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_retryable_error_codes_exhausted() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let mut disk_full = BytesMut::new();
+            disk_full.put_u16(OpCode::Error as u16);
+            disk_full.put_u16(ErrorCode::DiskFull as u16);
+            disk_full.put_slice(b"full\0");
+            loop {
+                let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+                peer.send_to(&disk_full, remote).await.unwrap();
+            }
+        });
+
+        // 送り直しても成功しない場合は、受信したエラーコードを返す。
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_busy_retry(2, Duration::from_millis(10));
+        client.set_retryable_error_codes(&[ErrorCode::DiskFull]);
+        let ret = client.get(&local.path().join("a"), "a").await;
+        match ret {
+            Err(Error::PeerError(ErrorCode::DiskFull, message)) => assert_eq!("full", message),
+            ret => panic!("{:?}", ret),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_many_continue_on_failure() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(root.path().join("a"), &content)?;
+        std::fs::write(root.path().join("c"), &content[..1000])?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        // b はサーバに存在しないため失敗する。
+        let (a, b, c) = (
+            local.path().join("a"),
+            local.path().join("b"),
+            local.path().join("c"),
+        );
+
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_concurrency(2);
+        let results = client.get_many(&[(&a, "a"), (&b, "b"), (&c, "c")]).await;
+
+        assert_eq!(3, results.len());
+        assert_eq!(3000, results[0].as_ref().unwrap().bytes());
+        assert!(
+            matches!(results[1], Err(Error::FileNotFound)),
+            "{:?}",
+            results[1]
+        );
+        assert_eq!(1000, results[2].as_ref().unwrap().bytes());
+        assert_eq!(content, std::fs::read(&a)?);
+        assert_eq!(&content[..1000], std::fs::read(&c)?.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_sack_out_of_order() -> Result<(), Error> {
+        let content = testutil::content(512 * 4 + 10);
+        let options = OptionBuilder::default().windowsize(4).sack().build();
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let sent = content.clone();
+        let oack = options.clone();
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            sock.connect(remote).await.unwrap();
+
+            let data = |n: u16| {
+                let start = (n as usize - 1) * 512;
+                let end = sent.len().min(start + 512);
+                packet::data(n, &sent[start..end])
+            };
+
+            sock.send(&packet::oack(&oack)).await.unwrap();
+            let size = sock.recv(&mut buf).await.unwrap();
+            assert_eq!(packet::ack(0).as_ref(), &buf[..size]);
+
+            // ブロック 2 を欠けさせる。
+            for n in [1, 3, 4] {
+                sock.send(&data(n)).await.unwrap();
+            }
+            let size = sock.recv(&mut buf).await.unwrap();
+            assert_eq!(packet::sack(1, &[0b0110_0000]).as_ref(), &buf[..size]);
+
+            sock.send(&data(2)).await.unwrap();
+            let size = sock.recv(&mut buf).await.unwrap();
+            assert_eq!(packet::ack(4).as_ref(), &buf[..size]);
+
+            sock.send(&data(5)).await.unwrap();
+            let size = sock.recv(&mut buf).await.unwrap();
+            assert_eq!(packet::ack(5).as_ref(), &buf[..size]);
+        });
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", options);
+        client.get(&local.path().join("a"), "a").await?;
+        server.await.unwrap();
+
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    // 127.0.0.1 以外のループバックアドレスを使えるのは Linux のみ。
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn get_ignore_reply_from_other_ip() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let spoofer = UdpSocket::bind("127.0.0.2:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let spoofed = packet::data(1, &b"spoofed"[..]);
+            spoofer.send_to(&spoofed, remote).await.unwrap();
+            let data = packet::data(1, &b"ok"[..]);
+            peer.send_to(&data, remote).await.unwrap();
+        });
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(b"ok", std::fs::read(local.path().join("a"))?.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn probe_ok() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let client = Client::new(addr, "octet", Options::default());
+        let elapsed = client.probe().await?;
+
+        assert!(elapsed < Duration::from_secs(1));
+        assert_eq!(0, std::fs::read_dir(root.path())?.count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn size_ok() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1234))?;
+        std::fs::write(root.path().join("empty"), b"")?;
+
+        let limitations = OptionBuilder::default().tsize().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let client = Client::new(addr, "octet", Options::default());
+        assert_eq!(Some(1234), client.size("a").await?);
+        assert_eq!(Some(0), client.size("empty").await?);
+        assert!(matches!(client.size("b").await, Err(Error::FileNotFound)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn size_without_tsize() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1234))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let client = Client::new(addr, "octet", Options::default());
+        assert_eq!(None, client.size("a").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_resume() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), &content[..1000])?;
+
+        let options = OptionBuilder::default().tsize().build();
+        let mut client = Client::new(addr, "octet", options);
+        client.set_resume(true);
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(2000, stats.bytes());
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_if_modified() -> Result<(), Error> {
+        let local = tempfile::tempdir()?;
+        let local_file = local.path().join("a");
+        std::fs::write(&local_file, b"old")?;
+
+        // 更新日時は秒単位で比較するため、サーバのファイルより確実に古くする。
+        let old = std::time::SystemTime::now() - Duration::from_secs(3600);
+        #[allow(clippy::incompatible_msrv)]
+        std::fs::File::options()
+            .write(true)
+            .open(&local_file)?
+            .set_modified(old)?;
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let client = Client::new(addr, "octet", Options::default());
+        let stats = client.get_if_modified(&local_file, "a").await?;
+
+        assert_eq!(Some(3000), stats.map(|s| s.bytes()));
+        assert_eq!(content, std::fs::read(&local_file)?);
+        assert_eq!(1, std::fs::read_dir(local.path())?.count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_if_not_modified() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(3000))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let local_file = local.path().join("a");
+        std::fs::write(&local_file, b"cached")?;
+
+        let client = Client::new(addr, "octet", Options::default());
+        let stats = client.get_if_modified(&local_file, "a").await?;
+
+        assert!(stats.is_none());
+        assert_eq!(b"cached", &std::fs::read(&local_file)?[..]);
+        assert_eq!(1, std::fs::read_dir(local.path())?.count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_resume_rejected() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            // x-offset を無視して先頭から送信する。
+            for _ in 0..2 {
+                let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+                let data = packet::data(1, &b"ok"[..]);
+                peer.send_to(&data, remote).await.unwrap();
+                let (size, _) = peer.recv_from(&mut buf).await.unwrap();
+                if Bytes::copy_from_slice(&buf[..size]) == packet::ack(1) {
+                    return true;
+                }
+            }
+            false
+        });
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), b"o")?;
+
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_resume(true);
+        client.get(&local.path().join("a"), "a").await?;
+
+        assert!(handle.await.unwrap());
+        assert_eq!(b"ok", &std::fs::read(local.path().join("a"))?[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_oversized_data() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let data = packet::data(1, &[0u8; 600][..]);
+            peer.send_to(&data, remote).await.unwrap();
+
+            let size = peer.recv(&mut buf).await.unwrap();
+            let mut buf = Bytes::from(buf[..size].to_vec());
+            packet::parse_opcode(&mut buf).unwrap()
+        });
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        let ret = client.get(&local.path().join("a"), "a").await;
+
+        assert!(matches!(ret, Err(Error::InvalidPacketLength)));
+        assert!(matches!(handle.await.unwrap(), Some(OpCode::Error)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_trace() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1100))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.with_trace();
+        client.get(&local.path().join("a"), "a").await?;
+
+        let trace: Vec<(Direction, Option<OpCode>, Option<u16>)> = client
+            .trace()
+            .iter()
+            .map(|t| (t.direction().clone(), t.op_code().cloned(), t.blocknum()))
+            .collect();
+        assert_eq!(
+            vec![
+                (Direction::Sent, Some(OpCode::Rrq), None),
+                (Direction::Received, Some(OpCode::Data), Some(1)),
+                (Direction::Sent, Some(OpCode::Ack), Some(1)),
+                (Direction::Received, Some(OpCode::Data), Some(2)),
+                (Direction::Sent, Some(OpCode::Ack), Some(2)),
+                (Direction::Received, Some(OpCode::Data), Some(3)),
+                (Direction::Sent, Some(OpCode::Ack), Some(3)),
+            ],
+            trace
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_trace_payload_len() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), testutil::content(1100))?;
+
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.with_trace();
+        client.put(&local.path().join("a"), "a").await?;
+
+        let payloads: Vec<(u16, usize)> = client
+            .trace()
+            .iter()
+            .filter(|t| t.direction() == &Direction::Sent)
+            .filter_map(|t| Some((t.blocknum()?, t.payload_len()?)))
+            .collect();
+        assert_eq!(vec![(1, 512), (2, 512), (3, 76)], payloads);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_block_timings() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), testutil::content(1100))?;
+
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.with_trace();
+        client.put(&local.path().join("a"), "a").await?;
+
+        let timings = client.block_timings();
+        assert_eq!(
+            vec![1, 2, 3],
+            timings.iter().map(|t| t.block()).collect::<Vec<_>>()
+        );
+        for timing in timings {
+            assert!(timing.acked_at().unwrap() >= timing.sent_at());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_adopts_oack_blksize() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default().blksize(512).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let content = testutil::content(2000);
+        std::fs::write(local.path().join("a"), &content)?;
+
+        // サーバが縮小した blksize で最初のブロックから送信する。
+        let options = OptionBuilder::default().blksize(1024).build();
+        let mut client = Client::new(addr, "octet", options);
+        client.with_trace();
+        let stats = client.put(&local.path().join("a"), "a").await?;
+
+        match stats.negotiation() {
+            OptionNegotiation::Accepted(options) => assert_eq!(512, options.blksize()),
+            n => panic!("{:?}", n),
+        }
+        let blocks: Vec<u16> = client
+            .trace()
+            .iter()
+            .filter(|t| t.op_code() == Some(&OpCode::Data))
+            .filter_map(|t| t.blocknum())
+            .collect();
+        assert_eq!(vec![1, 2, 3, 4], blocks);
+        assert_eq!(content, std::fs::read(root.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_no_progress() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let data = packet::data(1, &[0u8; 512][..]);
+            for _ in 0..1000 {
+                peer.send_to(&data, remote).await.unwrap();
+            }
+        });
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        let ret = client.get(&local.path().join("a"), "a").await;
+
+        assert!(matches!(ret, Err(Error::NoProgress)));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_with_retries() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            // 1 回目の転送には応答せずにタイムアウトさせる。
+            let (_, first) = peer.recv_from(&mut buf).await.unwrap();
+            loop {
+                let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+                if remote != first {
+                    let data = packet::data(1, &b"ok"[..]);
+                    peer.send_to(&data, remote).await.unwrap();
+                    break;
+                }
+            }
+        });
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client
+            .get_with_retries(&local.path().join("a"), "a", 2)
+            .await?;
+
+        assert_eq!(b"ok", std::fs::read(local.path().join("a"))?.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_with_retries_adaptive_window() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            // 1 回目の転送は損失したものとして応答しない。
+            let (_, first) = peer.recv_from(&mut buf).await.unwrap();
+            loop {
+                let (size, remote) = peer.recv_from(&mut buf).await.unwrap();
+                if remote != first {
+                    let mut req = Bytes::from(buf[..size].to_vec());
+                    let req = packet::parse_request(&mut req).unwrap();
+                    let data = packet::data(1, &b"ok"[..]);
+                    peer.send_to(&data, remote).await.unwrap();
+                    return req.options().windowsize();
+                }
+            }
+        });
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().windowsize(8).build();
+        let mut client = Client::new(addr, "octet", options);
+        client.set_adaptive_window(true);
+        client
+            .get_with_retries(&local.path().join("a"), "a", 2)
+            .await?;
+
+        assert_eq!(4, server.await.unwrap());
+        assert_eq!(b"ok", std::fs::read(local.path().join("a"))?.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_high_retransmit_rate() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            // 最初の要求には応答せず、再送された要求に応答する。
+            peer.recv_from(&mut buf).await.unwrap();
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let data = packet::data(1, &b"ok"[..]);
+            peer.send_to(&data, remote).await.unwrap();
+        });
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(
+            &[Warning::HighRetransmitRate {
+                retransmits: 1,
+                blocks: 1
+            }],
+            stats.warnings()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_retries_not_transient() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), b"exists")?;
+        let client = Client::new(addr, "octet", Options::default());
+        let ret = client
+            .get_with_retries(&local.path().join("a"), "a", 3)
+            .await;
+
+        // 一時的な障害ではないためやり直さず、既存のファイルも残す。
+        assert!(matches!(ret, Err(Error::Io(_))));
+        assert_eq!(b"exists", std::fs::read(local.path().join("a"))?.as_slice());
+        drop(peer);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_retries_file_not_found() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            peer.send_to(&packet::error(&Error::FileNotFound), remote)
+                .await
+                .unwrap();
+
+            // 転送はやり直されない。
+            let ret = time::timeout(Duration::from_millis(200), peer.recv_from(&mut buf)).await;
+            ret.is_err()
+        });
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        let ret = client
+            .get_with_retries(&local.path().join("a"), "a", 3)
+            .await;
+
+        assert!(matches!(ret, Err(Error::FileNotFound)), "{:?}", ret);
+        assert!(handle.await.unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_below_min_transfer_rate() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let mut options = OptionBuilder::default().tsize().build();
+            options.set_tsize_len(512 * 8);
+            peer.send_to(&packet::oack(&options), remote).await.unwrap();
+            peer.recv_from(&mut buf).await.unwrap();
+
+            for blocknum in 1..=8 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let data = packet::data(blocknum, &[0u8; 512][..]);
+                if peer.send_to(&data, remote).await.is_err() {
+                    break;
+                }
+                peer.recv_from(&mut buf).await.unwrap();
+            }
+        });
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().tsize().build();
+        let mut client = Client::new(addr, "octet", options);
+        // 4096 bytes を 200ms 以内に転送できなければ中断する。
+        client.set_min_transfer_rate(512 * 8 * 5);
+        let ret = client.get(&local.path().join("a"), "a").await;
+
+        assert!(matches!(ret, Err(Error::Timedout)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_exceeds_max_duration() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+
+            // 受信は進み続けるが、ブロックごとに遅延させる。
+            for blocknum in 1..=20 {
+                let data = packet::data(blocknum, &[0u8; 512][..]);
+                if peer.send_to(&data, remote).await.is_err() {
+                    break;
+                }
+                peer.recv_from(&mut buf).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_max_duration(Duration::from_millis(300));
+        let started_at = Instant::now();
+        let ret = client.get(&local.path().join("a"), "a").await;
+
+        assert!(matches!(ret, Err(Error::Timedout)));
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_min_timeout() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            // 最初の RRQ は失われたものとして応答しない。
+            peer.recv_from(&mut buf).await.unwrap();
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let data = packet::data(1, &b"ok"[..]);
+            peer.send_to(&data, remote).await.unwrap();
+        });
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_min_timeout(Duration::from_millis(50));
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        assert!(*stats.elapsed() < Duration::from_millis(500));
+        assert_eq!(b"ok", std::fs::read(local.path().join("a"))?.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_ack_frequency() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(512 * 16 + 100);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().windowsize(8).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let mut acks = vec![];
+        for (frequency, filename) in [(2, "a2"), (8, "a8")] {
+            let mut client = Client::new(addr, "octet", limitations.clone());
+            client.with_trace();
+            client.set_ack_frequency(frequency);
+            client.get(&local.path().join(filename), "a").await?;
+            assert_eq!(content, std::fs::read(local.path().join(filename))?);
+
+            let count = client
+                .trace()
+                .iter()
+                .filter(|t| t.op_code() == Some(&OpCode::Ack))
+                .count();
+            acks.push(count);
+        }
+
+        assert!(acks[1] < acks[0]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn get_with_first_block_timeout() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            // 通常の待ち時間で再送し尽くすよりも遅れて応答する。
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            let data = packet::data(1, &b"ok"[..]);
+            peer.send_to(&data, remote).await.unwrap();
+        });
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_min_timeout(Duration::from_millis(50));
+        client.set_first_block_timeout(Duration::from_secs(5));
+        client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(b"ok", std::fs::read(local.path().join("a"))?.as_slice());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_dally() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let data = packet::data(1, &b"ok"[..]);
+            peer.send_to(&data, remote).await.unwrap();
+
+            // 最後の ACK が失われたものとして DATA を再送する。
+            peer.recv_from(&mut buf).await.unwrap();
+            peer.send_to(&data, remote).await.unwrap();
+
+            let (size, _) = peer.recv_from(&mut buf).await.unwrap();
+            let mut buf = Bytes::from(buf[..size].to_vec());
+            packet::parse_opcode(&mut buf).unwrap();
+            packet::parse_blocknum(&mut buf).unwrap()
+        });
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_dally(Duration::from_millis(200));
+        client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(1, handle.await.unwrap());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_with_preallocate() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        std::fs::write(root.path().join("a"), &content)?;
+        std::fs::write(root.path().join("b"), "a\nb\r\nc\n")?;
+
+        let limitations = OptionBuilder::default().tsize().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().tsize().build();
+        let mut client = Client::new(addr, "octet", options.clone());
+        client.set_preallocate(true);
+        client.get(&local.path().join("a"), "a").await?;
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+
+        let mut client = Client::new(addr, "netascii", options);
+        client.set_preallocate(true);
+        client.get(&local.path().join("b"), "b").await?;
+        assert_eq!(
+            std::fs::read(root.path().join("b"))?,
+            std::fs::read(local.path().join("b"))?
+        );
+        Ok(())
+    }
+
+    // 127.0.0.1 以外のループバックアドレスを使えるのは Linux のみ。
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn get_with_local_bind() -> Result<(), Error> {
+        let peer = UdpSocket::bind("127.0.0.1:0").await?;
+        let addr = peer.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0; 1024];
+            let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+            let data = packet::data(1, &b"ok"[..]);
+            peer.send_to(&data, remote).await.unwrap();
+            remote
+        });
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_local_bind("127.0.0.2".parse()?);
+        client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!("127.0.0.2".parse::<IpAddr>()?, handle.await.unwrap().ip());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_option_negotiation() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(10))?;
+
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().blksize(4096).build();
+        let client = Client::new(addr, "octet", options.clone());
+        let stats = client.get(&local.path().join("a"), "a").await?;
+        assert_eq!(10, stats.bytes());
+        match stats.negotiation() {
+            OptionNegotiation::Accepted(options) => assert_eq!(1024, options.blksize()),
+            n => panic!("{:?}", n),
+        }
+
+        for reply in [
+            packet::data(1, &b"ok"[..]),
+            Bytes::from(&b"\x00\x05\x00\x08no\x00"[..]),
+        ] {
+            let peer = UdpSocket::bind("127.0.0.1:0").await?;
+            let addr = peer.local_addr()?;
+            let rejected = reply[1] == OpCode::Error as u8;
+            tokio::spawn(async move {
+                let mut buf = vec![0; 1024];
+                let (_, remote) = peer.recv_from(&mut buf).await.unwrap();
+                peer.send_to(&reply, remote).await.unwrap();
+            });
+
+            let client = Client::new(addr, "octet", options.clone());
+            let local_file = local.path().join(format!("{}", rejected));
+            let ret = client.get(&local_file, "a").await;
+            if rejected {
+                // オプションを拒否された場合は転送されずに失敗する。
+                assert!(
+                    matches!(ret, Err(Error::PeerError(ErrorCode::OptionNotSupport, _))),
+                    "{:?}",
+                    ret
+                );
+            } else {
+                assert_eq!(&OptionNegotiation::Ignored, ret?.negotiation());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "digest")]
+    #[tokio::test]
+    async fn get_put_with_digest() -> Result<(), Error> {
+        use sha2::{Digest as _, Sha256};
+
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        std::fs::write(root.path().join("a"), &content)?;
+        let expected: [u8; 32] = Sha256::digest(&content).into();
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().windowsize(4).build();
+        let client = Client::new(addr, "octet", options);
+
+        let (stats, digest) = client.get_with_digest(&local.path().join("a"), "a").await?;
+        assert_eq!(5000, stats.bytes());
+        assert_eq!(expected, digest);
+
+        let (_, digest) = client.put_with_digest(&local.path().join("a"), "b").await?;
+        assert_eq!(expected, digest);
+        assert_eq!(content, std::fs::read(root.path().join("b"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn from_config_ok() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("tftp.conf");
+        std::fs::write(
+            &path,
+            "# comment\nhost = 127.0.0.1\nport=6969\nmode=netascii\n\nblksize=1024\ntimeout=3\ntsize=true\nwindowsize=4\n",
+        )?;
+
+        let client = Client::from_config(&path)?;
+        assert_eq!("127.0.0.1:6969".parse::<SocketAddr>()?, client.remote_addr);
+        assert_eq!("netascii", client.mode);
+        let expected = OptionBuilder::default()
+            .blksize(1024)
+            .timeout(3)
+            .tsize()
+            .windowsize(4)
+            .build();
+        assert_eq!(expected, client.options);
+
+        std::fs::write(&path, "host=127.0.0.1\nblksize=1\n")?;
+        assert!(matches!(
+            Client::from_config(&path),
+            Err(Error::InvalidConfig(key)) if key == "blksize"
+        ));
 
+        std::fs::write(&path, "port=69\n")?;
+        assert!(matches!(
+            Client::from_config(&path),
+            Err(Error::InvalidConfig(key)) if key == "host"
+        ));
         Ok(())
     }
 }
@@ -0,0 +1,109 @@
+use sha2::{Digest as _, Sha256};
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+#[derive(Clone, Default)]
+pub struct Digest {
+    hasher: Arc<Mutex<Sha256>>,
+}
+
+impl Digest {
+    pub fn update(&self, data: &[u8]) {
+        self.hasher.lock().unwrap().update(data);
+    }
+
+    pub fn finalize(&self) -> [u8; 32] {
+        self.hasher.lock().unwrap().clone().finalize().into()
+    }
+}
+
+pub struct DigestReader<R> {
+    inner: R,
+    digest: Digest,
+    pos: u64,
+    // 再送のために同じ位置を読み直しても二重に計算しない。
+    hashed: u64,
+}
+
+impl<R> DigestReader<R> {
+    pub fn new(inner: R, digest: Digest) -> Self {
+        DigestReader {
+            inner,
+            digest,
+            pos: 0,
+            hashed: 0,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DigestReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = ret {
+            let data = &buf.filled()[before..];
+            let start = self.pos;
+            self.pos += data.len() as u64;
+            if start <= self.hashed && self.hashed < self.pos {
+                let skip = (self.hashed - start) as usize;
+                self.digest.update(&data[skip..]);
+                self.hashed = self.pos;
+            }
+        }
+        ret
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncSeek for DigestReader<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let ret = Pin::new(&mut self.inner).poll_complete(cx);
+        if let Poll::Ready(Ok(pos)) = ret {
+            self.pos = pos;
+        }
+        ret
+    }
+}
+
+pub struct DigestWriter<W> {
+    inner: W,
+    digest: Digest,
+}
+
+impl<W> DigestWriter<W> {
+    pub fn new(inner: W, digest: Digest) -> Self {
+        DigestWriter { inner, digest }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for DigestWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(size)) = ret {
+            self.digest.update(&buf[..size]);
+        }
+        ret
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
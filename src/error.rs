@@ -4,18 +4,42 @@ use std::io;
 use std::net;
 use std::string;
 
+// TFTP には混雑を示すエラーコードがないため、NotDefined とこの定型文の組で通知する。
+pub const SERVER_BUSY_MESSAGE: &str = "ServerBusy";
+
 #[derive(Debug)]
 pub enum Error {
+    Aborted,
+    AccessViolation,
     AddrParse(net::AddrParseError),
+    DryRun,
     FileNotFound,
+    InvalidConfig(String),
+    InvalidEnv(String),
     InvalidFileName,
     InvalidMode,
     InvalidOpCode,
     InvalidPacketLength,
     Io(io::Error),
+    // 失敗した操作を付加した入出力エラー。
+    IoContext(&'static str, io::Error),
     MissingErrorMessage,
     MissingFileName,
     MissingMode,
+    NegotiationRequired,
+    NoProgress,
+    // mail モードは netascii として扱うため、現在は使用されていない。
+    NoSuchUser,
+    NotModified,
+    // 相手から受信した ERROR のうち、対応するエラーがないもの。
+    PeerError(ErrorCode, String),
+    ResumeRejected,
+    ServerBusy,
+    ServiceUnavailable,
+    ShuttingDown,
+    SizeMismatch,
+    Socks5Handshake,
+    SourceChanged,
     Timedout,
     Utf8(string::FromUtf8Error),
 }
@@ -24,6 +48,9 @@ impl Error {
     pub fn error_code(&self) -> ErrorCode {
         match self {
             Error::FileNotFound => ErrorCode::FileNotFound,
+            Error::AccessViolation => ErrorCode::AccessViolation,
+            Error::NoSuchUser => ErrorCode::NoSuchUser,
+            Error::PeerError(code, _) => code.clone(),
             Error::InvalidFileName
             | Error::InvalidMode
             | Error::InvalidOpCode
@@ -31,9 +58,49 @@ impl Error {
             | Error::MissingErrorMessage
             | Error::MissingFileName
             | Error::MissingMode => ErrorCode::IllegalTftpOp,
+            Error::Io(e) | Error::IoContext(_, e) => match e.kind() {
+                io::ErrorKind::NotFound => ErrorCode::FileNotFound,
+                io::ErrorKind::PermissionDenied => ErrorCode::AccessViolation,
+                io::ErrorKind::AlreadyExists => ErrorCode::FileAlreadyExists,
+                _ => ErrorCode::NotDefined,
+            },
             _ => ErrorCode::NotDefined,
         }
     }
+
+    // 相手から受信した ERROR を、対応するエラーに変換する。
+    pub fn from_peer(code: ErrorCode, message: &str) -> Self {
+        match code {
+            ErrorCode::FileNotFound => Error::FileNotFound,
+            ErrorCode::AccessViolation => Error::AccessViolation,
+            ErrorCode::NoSuchUser => Error::NoSuchUser,
+            ErrorCode::NotDefined if message == SERVER_BUSY_MESSAGE => Error::ServerBusy,
+            code => Error::PeerError(code, message.to_string()),
+        }
+    }
+
+    pub fn context(self, op: &'static str) -> Self {
+        match self {
+            Error::Io(e) => Error::IoContext(op, e),
+            e => e,
+        }
+    }
+
+    // 転送をやり直すことで成功する可能性のあるエラーかどうか。
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Timedout => true,
+            Error::Io(e) | Error::IoContext(_, e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl From<net::AddrParseError> for Error {
@@ -53,3 +120,39 @@ impl From<string::FromUtf8Error> for Error {
         Error::Utf8(error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_code_no_such_user() {
+        assert_eq!(ErrorCode::NoSuchUser, Error::NoSuchUser.error_code());
+    }
+
+    #[test]
+    fn from_peer() {
+        assert!(matches!(
+            Error::from_peer(ErrorCode::FileNotFound, "missing"),
+            Error::FileNotFound
+        ));
+        assert!(matches!(
+            Error::from_peer(ErrorCode::NotDefined, SERVER_BUSY_MESSAGE),
+            Error::ServerBusy
+        ));
+        match Error::from_peer(ErrorCode::DiskFull, "full") {
+            Error::PeerError(ErrorCode::DiskFull, message) => assert_eq!("full", message),
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn is_transient() {
+        assert!(Error::Timedout.is_transient());
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(Error::from(refused).is_transient());
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(!Error::from(denied).is_transient());
+        assert!(!Error::FileNotFound.is_transient());
+    }
+}
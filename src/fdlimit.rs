@@ -0,0 +1,83 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 1 セッションが使用するファイルディスクリプタ数 (転送用のソケットとファイル)。
+pub const FDS_PER_SESSION: u64 = 2;
+
+// RLIMIT_NOFILE のソフトリミット。
+pub fn soft_limit() -> io::Result<u64> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: rlim は呼び出しの間有効。
+    let ret = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // rlim_t は 32 ビット環境では u32 になる。
+    #[allow(clippy::unnecessary_cast)]
+    let limit = rlim.rlim_cur as u64;
+    Ok(limit)
+}
+
+// ソフトリミットのうち reserve 個をセッション以外 (ログや受付用のソケットなど) のために残す。
+#[derive(Debug)]
+pub struct FdReserve {
+    limit: u64,
+    reserve: u64,
+    active: AtomicU64,
+}
+
+impl FdReserve {
+    pub fn new(reserve: u64) -> io::Result<Self> {
+        Ok(FdReserve {
+            limit: soft_limit()?,
+            reserve,
+            active: AtomicU64::new(0),
+        })
+    }
+
+    // 新しいセッションを加えても予約分が残る場合のみ、セッションを開始できる。
+    pub fn acquire(&self) -> Option<FdGuard<'_>> {
+        let available = self.limit.saturating_sub(self.reserve) / FDS_PER_SESSION;
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < available).then(|| active + 1)
+            })
+            .ok()
+            .map(|_| FdGuard { reserve: self })
+    }
+}
+
+pub struct FdGuard<'a> {
+    reserve: &'a FdReserve,
+}
+
+impl Drop for FdGuard<'_> {
+    fn drop(&mut self) {
+        self.reserve.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acquire_until_reserve() -> io::Result<()> {
+        let limit = soft_limit()?;
+        let reserve = FdReserve::new(limit - FDS_PER_SESSION * 2)?;
+
+        let first = reserve.acquire();
+        let second = reserve.acquire();
+        assert!(first.is_some());
+        assert!(second.is_some());
+        assert!(reserve.acquire().is_none());
+
+        drop(first);
+        assert!(reserve.acquire().is_some());
+        Ok(())
+    }
+}
@@ -1,8 +1,42 @@
 use super::error::Error;
-use std::io::SeekFrom;
-use std::path::Path;
-use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
+use log::warn;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader,
+    BufWriter, ReadBuf,
+};
+
+pub trait FileReader: AsyncRead + AsyncSeek + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncSeek + Unpin + Send> FileReader for T {}
+
+pub trait FileWriter: AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncWrite + Unpin + Send + Sync> FileWriter for T {}
+
+// 転送モード。ブロックごとに文字列を比較しないよう、セッションの開始時に一度だけ解決する。
+// mail モードは netascii として扱う。
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Netascii,
+    Octet,
+}
+
+impl From<&str> for Mode {
+    fn from(mode: &str) -> Self {
+        if mode.eq_ignore_ascii_case("octet") {
+            Mode::Octet
+        } else {
+            Mode::Netascii
+        }
+    }
+}
 
 const NULL: u8 = b'\0';
 const CR: u8 = b'\r';
@@ -17,33 +51,206 @@ pub async fn open_create(path: &Path) -> Result<File, Error> {
     Ok(file)
 }
 
+// 既存のファイルの末尾から書き込む。
+pub async fn open_append(path: &Path) -> Result<File, Error> {
+    let file = OpenOptions::new().append(true).open(path).await?;
+    Ok(file)
+}
+
+// 更新日時を UNIX 時間の秒で返す。
+pub async fn mtime(path: &Path) -> Result<u64, Error> {
+    let modified = fs::metadata(path).await?.modified()?;
+    Ok(modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0))
+}
+
 pub async fn open_read(path: &Path) -> Result<File, Error> {
     let file = OpenOptions::new().read(true).open(&path).await?;
     Ok(file)
 }
 
+// read_netascii で変換した後のバイト数を求める。
+pub async fn netascii_len(path: &Path) -> Result<u64, Error> {
+    let mut reader = BufReader::new(open_read(path).await?);
+    let mut buf = vec![0u8; 8192];
+    let mut len = 0;
+    let mut lastch = None;
+
+    loop {
+        let size = reader.read(&mut buf).await?;
+        if size == 0 {
+            break;
+        }
+
+        for &ch in &buf[..size] {
+            len += if cfg!(target_family = "windows") {
+                // CR LF はそのまま、CR -> CR NULL
+                match lastch {
+                    Some(CR) if ch != LF => 2,
+                    _ => 1,
+                }
+            } else {
+                // LF -> CR LF, CR -> CR NULL
+                match ch {
+                    CR | LF => 2,
+                    _ => 1,
+                }
+            };
+            lastch = Some(ch);
+        }
+    }
+
+    Ok(len)
+}
+
+// 同じディレクトリから大文字小文字を区別せずに一致するファイルを探す。
+pub async fn find_ignore_case(path: &Path) -> Result<Option<PathBuf>, Error> {
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => (parent, name.to_string_lossy()),
+        _ => return Ok(None),
+    };
+
+    let mut entries = match fs::read_dir(parent).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::from(e)),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .eq_ignore_ascii_case(&name)
+        {
+            return Ok(Some(entry.path()));
+        }
+    }
+
+    Ok(None)
+}
+
+// 同じディレクトリで受信中の他のファイルと重ならない一時ファイルの名前。
+pub fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(
+        ".{}.{}.{}.partial",
+        name,
+        std::process::id(),
+        count
+    ))
+}
+
+pub async fn persist(from: &Path, to: &Path) -> Result<(), Error> {
+    if let Err(e) = fs::rename(from, to).await {
+        // ファイルシステムが異なる場合は atomic に移動できない。
+        warn!("failed to rename {:?}, fallback to copy: {:?}", from, e);
+        fs::copy(from, to).await?;
+        fs::remove_file(from).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path).await {
+        warn!("failed to remove {:?}: {:?}", path, e);
+    }
+}
+
+// 先頭の offset バイトを除いたファイルとして読み込む。
+pub struct OffsetReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R> OffsetReader<R> {
+    pub fn new(inner: R, offset: u64) -> Self {
+        OffsetReader { inner, offset }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for OffsetReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<R: AsyncSeek + Unpin> AsyncSeek for OffsetReader<R> {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(pos) => SeekFrom::Start(pos + self.offset),
+            pos => pos,
+        };
+        Pin::new(&mut self.inner).start_seek(position)
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let offset = self.offset;
+        Pin::new(&mut self.inner)
+            .poll_complete(cx)
+            .map(|ret| ret.map(|pos| pos.saturating_sub(offset)))
+    }
+}
+
+// 転送が完了しなかった場合に、書きかけのファイルを削除する。
+// async Drop がないため、削除は同期的に行う。成功時は defuse で解除する。
+pub struct RemoveGuard {
+    path: Option<PathBuf>,
+}
+
+impl RemoveGuard {
+    pub fn new(path: &Path) -> Self {
+        RemoveGuard {
+            path: Some(path.to_path_buf()),
+        }
+    }
+
+    pub fn defuse(mut self) {
+        self.path = None;
+    }
+}
+
+impl Drop for RemoveGuard {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("failed to remove {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
+
 pub async fn read(
-    reader: &mut BufReader<File>,
+    reader: &mut BufReader<Box<dyn FileReader>>,
     buf: &mut [u8],
     reader_pos: u64,
-    mode: &str,
+    mode: Mode,
     lastch: Option<u8>,
 ) -> Result<(usize, usize, Option<u8>), Error> {
     let offset = SeekFrom::Start(reader_pos);
-    reader.seek(offset).await?;
+    reader
+        .seek(offset)
+        .await
+        .map_err(|e| Error::from(e).context("seek file"))?;
 
-    let ret = if mode == "octet" {
-        read_octet(reader, lastch, buf).await?
-    } else {
-        read_netascii(reader, lastch, buf).await?
+    let ret = match mode {
+        Mode::Octet => read_octet(reader, buf).await,
+        Mode::Netascii => read_netascii(reader, lastch, buf).await,
     };
 
-    Ok(ret)
+    ret.map_err(|e| e.context("read file"))
 }
 
 #[cfg(target_family = "windows")]
 async fn read_netascii(
-    reader: &mut BufReader<File>,
+    reader: &mut BufReader<Box<dyn FileReader>>,
     lastch: Option<u8>,
     buf: &mut [u8],
 ) -> Result<(usize, usize, Option<u8>), Error> {
@@ -82,7 +289,7 @@ async fn read_netascii(
 
 #[cfg(target_family = "unix")]
 async fn read_netascii(
-    reader: &mut BufReader<File>,
+    reader: &mut BufReader<Box<dyn FileReader>>,
     lastch: Option<u8>,
     buf: &mut [u8],
 ) -> Result<(usize, usize, Option<u8>), Error> {
@@ -128,67 +335,78 @@ async fn read_netascii(
     Ok((reader_pos, index, lastch))
 }
 
+// 短いブロックは転送の終わりを示すため、終端に達するまでは buf を埋める。
 async fn read_octet(
-    reader: &mut BufReader<File>,
-    _: Option<u8>,
+    reader: &mut BufReader<Box<dyn FileReader>>,
     buf: &mut [u8],
 ) -> Result<(usize, usize, Option<u8>), Error> {
-    let size = reader.read(buf).await?;
+    let mut size = 0;
+    while size < buf.len() {
+        match reader.read(&mut buf[size..]).await? {
+            0 => break,
+            n => size += n,
+        }
+    }
     Ok((size, size, None))
 }
 
 pub async fn write(
-    writer: &mut BufWriter<File>,
+    writer: &mut BufWriter<Box<dyn FileWriter>>,
     buf: &[u8],
-    mode: &str,
+    mode: Mode,
     lastch: Option<u8>,
 ) -> Result<(usize, Option<u8>), Error> {
-    let offset = SeekFrom::End(0);
-    writer.seek(offset).await?;
-
-    let ret = if mode == "octet" {
-        write_octet(writer, lastch, buf).await?
-    } else {
-        write_netascii(writer, lastch, buf).await?
-    };
+    let ret = match mode {
+        Mode::Octet => write_octet(writer, buf).await,
+        Mode::Netascii => write_netascii(writer, lastch, buf).await,
+    }
+    .map_err(|e| e.context("write file"))?;
 
-    writer.flush().await?;
+    writer
+        .flush()
+        .await
+        .map_err(|e| Error::from(e).context("flush file"))?;
 
     Ok(ret)
 }
 
 async fn write_netascii(
-    writer: &mut BufWriter<File>,
+    writer: &mut BufWriter<Box<dyn FileWriter>>,
     lastch: Option<u8>,
     buf: &[u8],
 ) -> Result<(usize, Option<u8>), Error> {
     let mut size = 0;
     let mut lastch = lastch;
 
+    // シークできない書き込み先もあるため CR は次の文字まで保留する。
     for &ch in buf {
-        match ch {
-            NULL if lastch.is_some() => {
-                // CR NULL -> CR
-                lastch = None;
-                continue;
-            }
-            CR => {
-                lastch = Some(ch);
-            }
-            LF if lastch.is_some() => {
-                // CR LF -> LF
-                if !cfg!(windows) {
-                    let pre_pos = SeekFrom::Current(-1);
-                    writer.seek(pre_pos).await?;
+        if let Some(pre) = lastch.take() {
+            match ch {
+                NULL => {
+                    // CR NULL -> CR
+                    writer.write_u8(pre).await?;
+                    size += 1;
+                    continue;
+                }
+                LF => {
+                    // CR LF -> LF
+                    if cfg!(windows) {
+                        writer.write_u8(pre).await?;
+                        size += 1;
+                    }
+                }
+                _ => {
+                    writer.write_u8(pre).await?;
+                    size += 1;
                 }
-
-                lastch = None;
-            }
-            _ => {
-                lastch = None;
             }
         }
 
+        if ch == CR {
+            lastch = Some(ch);
+            continue;
+        }
+
         writer.write_u8(ch).await?;
         size += 1;
     }
@@ -196,11 +414,193 @@ async fn write_netascii(
     Ok((size, lastch))
 }
 
+pub async fn write_pending(
+    writer: &mut BufWriter<Box<dyn FileWriter>>,
+    lastch: Option<u8>,
+) -> Result<usize, Error> {
+    // 保留した CR が最後の文字の場合はそのまま書き込む。
+    match lastch {
+        Some(ch) => {
+            writer.write_u8(ch).await?;
+            writer.flush().await?;
+            Ok(1)
+        }
+        None => Ok(0),
+    }
+}
+
 async fn write_octet(
-    writer: &mut BufWriter<File>,
-    _: Option<u8>,
+    writer: &mut BufWriter<Box<dyn FileWriter>>,
     buf: &[u8],
 ) -> Result<(usize, Option<u8>), Error> {
-    let size = writer.write(buf).await?;
-    Ok((size, None))
+    writer.write_all(buf).await?;
+    Ok((buf.len(), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_path_unique() {
+        let a = temp_path(Path::new("/cache/a.tar.gz"));
+        let b = temp_path(Path::new("/cache/a.tar.bz2"));
+        assert_ne!(a, b);
+        assert_eq!(Some(Path::new("/cache")), a.parent());
+        assert_ne!(a, temp_path(Path::new("/cache/a.tar.gz")));
+    }
+
+    #[tokio::test]
+    async fn offset_reader() -> Result<(), Error> {
+        let inner = std::io::Cursor::new(b"0123456789".to_vec());
+        let reader = OffsetReader::new(inner, 4);
+        let mut reader = BufReader::new(Box::new(reader) as Box<dyn FileReader>);
+        let mut buf = [0u8; 4];
+        let (len, _, _) = read(&mut reader, &mut buf, 2, Mode::Octet, None).await?;
+        assert_eq!(4, len);
+        assert_eq!(b"6789", &buf);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_guard() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let removed = dir.path().join("removed");
+        let kept = dir.path().join("kept");
+        std::fs::write(&removed, b"partial")?;
+        std::fs::write(&kept, b"complete")?;
+
+        drop(RemoveGuard::new(&removed));
+        RemoveGuard::new(&kept).defuse();
+
+        assert!(!removed.exists());
+        assert!(kept.exists());
+        Ok(())
+    }
+
+    struct BrokenReader;
+
+    impl AsyncRead for BrokenReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::Error::from_raw_os_error(5)))
+        }
+    }
+
+    impl AsyncSeek for BrokenReader {
+        fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            Poll::Ready(Ok(0))
+        }
+    }
+
+    // 1 回の読み込みで chunk バイトずつしか返さない。
+    struct ChunkedReader {
+        inner: std::io::Cursor<Vec<u8>>,
+        chunk: usize,
+    }
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let len = buf.remaining().min(self.chunk);
+            let mut chunk = tokio::io::ReadBuf::new(buf.initialize_unfilled_to(len));
+            let ret = Pin::new(&mut self.inner).poll_read(cx, &mut chunk);
+            let filled = chunk.filled().len();
+            buf.advance(filled);
+            ret
+        }
+    }
+
+    impl AsyncSeek for ChunkedReader {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+
+        fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn read_octet_fills_block() -> Result<(), Error> {
+        let content: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        let reader = ChunkedReader {
+            inner: std::io::Cursor::new(content.clone()),
+            chunk: 3,
+        };
+        let mut reader = BufReader::new(Box::new(reader) as Box<dyn FileReader>);
+
+        // 短く読めた場合も終端でなければブロックを埋める。
+        let mut buf = vec![0u8; 16384];
+        let (pos_len, size, ch) = read(&mut reader, &mut buf, 0, Mode::Octet, None).await?;
+        assert_eq!((16384, 16384, None), (pos_len, size, ch));
+        assert_eq!(&content[..16384], &buf[..]);
+
+        let (_, size, _) = read(&mut reader, &mut buf, 16384, Mode::Octet, None).await?;
+        assert_eq!(20000 - 16384, size);
+        assert_eq!(&content[16384..], &buf[..size]);
+        Ok(())
+    }
+
+    #[test]
+    fn mode_from_str() {
+        assert_eq!(Mode::Octet, Mode::from("octet"));
+        assert_eq!(Mode::Octet, Mode::from("OCTET"));
+        assert_eq!(Mode::Netascii, Mode::from("netascii"));
+        assert_eq!(Mode::Netascii, Mode::from("mail"));
+    }
+
+    #[tokio::test]
+    async fn read_error_with_context() {
+        let mut reader = BufReader::new(Box::new(BrokenReader) as Box<dyn FileReader>);
+        let mut buf = [0u8; 512];
+        let ret = read(&mut reader, &mut buf, 0, Mode::Octet, None).await;
+
+        match ret {
+            Err(Error::IoContext(op, e)) => {
+                assert_eq!("read file", op);
+                assert_eq!(Some(5), e.raw_os_error());
+            }
+            _ => panic!("{:?}", ret),
+        }
+    }
+
+    #[tokio::test]
+    async fn netascii_len_same_as_read() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("a");
+        let mut content = b"a\nb\r\nc\rd\r".repeat(300);
+        content.extend_from_slice(b"\n\r");
+        std::fs::write(&filepath, &content)?;
+
+        let file: Box<dyn FileReader> = Box::new(open_read(&filepath).await?);
+        let mut reader = BufReader::new(file);
+        let mut buf = vec![0u8; 512];
+        let mut reader_pos = 0;
+        let mut lastch = None;
+        let mut total = 0;
+        loop {
+            let (pos_len, size, ch) =
+                read(&mut reader, &mut buf, reader_pos, Mode::Netascii, lastch).await?;
+            if size == 0 {
+                break;
+            }
+            reader_pos += pos_len as u64;
+            lastch = ch;
+            total += size as u64;
+        }
+
+        assert_eq!(total, netascii_len(&filepath).await?);
+        Ok(())
+    }
 }
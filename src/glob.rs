@@ -0,0 +1,54 @@
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&ch) if ch == b'?' || ch == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    // '*' が 1 文字多く一致するものとして再試行する。
+                    backtrack = Some((bp, bn + 1));
+                    p = bp + 1;
+                    n = bn + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&ch| ch == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_ok() {
+        assert!(matches("*.img", "boot.img"));
+        assert!(matches("*.img", ".img"));
+        assert!(matches("a?c", "abc"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(matches("shadow", "shadow"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn matches_ng() {
+        assert!(!matches("*.img", "boot.cfg"));
+        assert!(!matches("a?c", "ac"));
+        assert!(!matches("a*b*c", "axxbyy"));
+        assert!(!matches("shadow", "shadow.bak"));
+    }
+}
@@ -1,21 +1,45 @@
 pub mod client;
 pub mod error;
 pub mod options;
+pub mod recorder;
 pub mod server;
 
+#[cfg(all(feature = "batch-io", target_os = "linux"))]
+mod batch;
+#[cfg(feature = "cache")]
+mod cache;
+#[cfg(feature = "digest")]
+mod digest;
+#[cfg(all(feature = "fd-reserve", target_os = "linux"))]
+mod fdlimit;
 mod file;
+mod glob;
+mod limiter;
 mod packet;
+mod pool;
 mod session;
+#[cfg(feature = "socks5")]
+mod socks5;
+#[cfg(feature = "embedded")]
+pub mod store;
+#[cfg(test)]
+mod testutil;
 
 use self::error::Error;
 use bytes::Bytes;
-use log::{error, trace};
+use log::{error, trace, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use std::cmp::Ordering;
+use std::io;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
 
 const HEADER_LEN: usize = 4;
+const NO_PROGRESS_LIMIT: u32 = 100;
 const ROLLOVER: u16 = 0;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum OpCode {
     Rrq = 1,
     Wrq = 2,
@@ -25,7 +49,7 @@ pub enum OpCode {
     Oack = 6,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ErrorCode {
     NotDefined = 0,
     FileNotFound = 1,
@@ -38,6 +62,134 @@ pub enum ErrorCode {
     OptionNotSupport = 8,
 }
 
+impl From<u16> for ErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => ErrorCode::FileNotFound,
+            2 => ErrorCode::AccessViolation,
+            3 => ErrorCode::DiskFull,
+            4 => ErrorCode::IllegalTftpOp,
+            5 => ErrorCode::UnknownTId,
+            6 => ErrorCode::FileAlreadyExists,
+            7 => ErrorCode::NoSuchUser,
+            8 => ErrorCode::OptionNotSupport,
+            _ => ErrorCode::NotDefined,
+        }
+    }
+}
+
+impl ErrorCode {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::NotDefined => "Not defined",
+            ErrorCode::FileNotFound => "File not found",
+            ErrorCode::AccessViolation => "Access violation",
+            ErrorCode::DiskFull => "Disk full or allocation exceeded",
+            ErrorCode::IllegalTftpOp => "Illegal TFTP operation",
+            ErrorCode::UnknownTId => "Unknown transfer ID",
+            ErrorCode::FileAlreadyExists => "File already exists",
+            ErrorCode::NoSuchUser => "No such user",
+            ErrorCode::OptionNotSupport => "Option negotiation failed",
+        }
+    }
+}
+
+type SocketConfig = Arc<dyn Fn(&Socket) -> io::Result<()> + Send + Sync>;
+
+// バインドする前にソケットの設定を呼び出し側に任せる。
+fn bind_socket(addr: SocketAddr, config: Option<&SocketConfig>) -> io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(config) = config {
+        config(&sock)?;
+    }
+    sock.set_nonblocking(true)?;
+    sock.bind(&addr.into())?;
+    UdpSocket::from_std(sock.into())
+}
+
+// IPv6 のソケットには同じ値を hop limit として設定する。
+fn apply_ttl(sock: &UdpSocket, ttl: Option<u32>) -> Result<(), Error> {
+    if let Some(ttl) = ttl {
+        match sock.local_addr()? {
+            SocketAddr::V4(_) => sock.set_ttl(ttl)?,
+            SocketAddr::V6(_) => SockRef::from(sock).set_unicast_hops_v6(ttl)?,
+        }
+    }
+
+    Ok(())
+}
+
+// IPv6 のソケットには同じ値を traffic class として設定する。
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+)))]
+fn apply_tos(sock: &UdpSocket, tos: Option<u8>) -> Result<(), Error> {
+    if let Some(tos) = tos {
+        match sock.local_addr()? {
+            SocketAddr::V4(_) => sock.set_tos(tos as u32)?,
+            SocketAddr::V6(addr) => apply_tclass(sock, &addr, tos)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn apply_tclass(sock: &UdpSocket, _: &SocketAddrV6, tos: u8) -> io::Result<()> {
+    SockRef::from(sock).set_tclass_v6(tos as u32)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+)))]
+fn apply_tclass(_: &UdpSocket, addr: &SocketAddrV6, _: u8) -> io::Result<()> {
+    warn!("[{}] ignored: traffic class is not supported", addr);
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+))]
+fn apply_tos(sock: &UdpSocket, tos: Option<u8>) -> Result<(), Error> {
+    if tos.is_some() {
+        warn!("[{}] ignored: tos is not supported", sock.local_addr()?);
+    }
+
+    Ok(())
+}
+
+// ブロック番号に value を加える。65535 を超えた場合は rollover (0 または 1) から数え直す。
+fn blocknum_add(blocknum: u16, value: u16, rollover: u16) -> u16 {
+    match blocknum.checked_add(value) {
+        Some(v) => v,
+        _ => blocknum.wrapping_add(value).wrapping_add(rollover),
+    }
+}
+
 async fn handle_ack(
     session: &mut session::TftpSession,
     ack: &mut Bytes,
@@ -51,7 +203,21 @@ async fn handle_ack(
         session.blocknum_ack()
     );
 
-    if blocknum != 0 || session.rollover() != 0 {
+    // 受信済みのブロックが通知された場合は、欠けたブロックのみを再送する。
+    if session.options().sack()
+        && !ack.is_empty()
+        && (blocknum == session.blocknum_ack() || session.blocknum_expect(blocknum))
+    {
+        session.progress_ack(blocknum);
+        session.set_blocknum_ack(blocknum);
+
+        let (_, buf) = session.send_missing_recv_ack(ack).await?;
+        return Ok(Some(buf));
+    }
+
+    // ブロック番号 0 の ACK は、ロールオーバー前は OACK に対する ACK か古い ACK として扱う。
+    // ロールオーバー後 (rollover != 0) は DATA ブロック 0 に対する ACK として扱う。
+    if blocknum != 0 || session.rollover() != 0 || session.blocknum_ack() != 0 {
         if !session.blocknum_expect(blocknum) {
             // 期待したブロックでなければ再度待ち受ける。
             let rev_buf = session
@@ -60,9 +226,13 @@ async fn handle_ack(
             return Ok(Some(rev_buf));
         }
 
+        session.progress_ack(blocknum);
         session.set_blocknum_ack(blocknum);
 
         if session.sent_completed() {
+            if session.options().confirm() {
+                session.confirm_sent().await?;
+            }
             return Ok(None);
         }
     }
@@ -71,12 +241,48 @@ async fn handle_ack(
     Ok(Some(buf))
 }
 
+// データを受信する側かどうか。受信側に届く ACK は相手の誤りによるもの。
+fn is_receiver(session: &session::TftpSession, req_code: &OpCode) -> bool {
+    matches!(
+        (session.role(), req_code),
+        (session::Role::Server, OpCode::Wrq) | (session::Role::Client, OpCode::Rrq)
+    )
+}
+
+// WRQ の OACK には DATA 1 で応答するが、RRQ と同様に ACK 0 を返すクライアントがある。
+// 既定では ACK を無視して DATA を待ち続け、拒否する設定の場合は不正な操作として中断する。
+async fn handle_stray_ack(
+    session: &mut session::TftpSession,
+    ack: &mut Bytes,
+) -> Result<Option<Bytes>, Error> {
+    let blocknum = packet::parse_blocknum(ack)?;
+    warn!(
+        "[{}] unexpected ACK block num #{} (#{})",
+        session.remote_addr(),
+        blocknum,
+        session.blocknum_ack()
+    );
+
+    if session.reject_stray_ack() {
+        return Err(Error::InvalidOpCode);
+    }
+
+    let buf = session
+        .recv_with_timeout(session.options().blksize() + HEADER_LEN)
+        .await?;
+    Ok(Some(buf))
+}
+
 async fn handle_data(
     session: &mut session::TftpSession,
     data: &mut Bytes,
 ) -> Result<Option<Bytes>, Error> {
     let blocknum = packet::parse_blocknum(data)?;
 
+    if data.len() > session.options().blksize() {
+        return Err(Error::InvalidPacketLength);
+    }
+
     trace!(
         "[{}] received: DATA block num #{} (#{})",
         session.remote_addr(),
@@ -86,28 +292,77 @@ async fn handle_data(
 
     let blocknum_expect = session.blocknum_ack_add(1);
     match blocknum_expect.cmp(&blocknum) {
+        Ordering::Less if session.options().sack() => {
+            // 期待したブロックよりも先のブロックを受け取った。
+            // 欠けたブロックのみを再送させるため、受信したブロックを保持して受信済みのブロックを通知する。
+            let last = data.len() < session.options().blksize() || blocknum == session.window_end();
+            session.sack_insert(blocknum, data.clone());
+
+            let buf = if last {
+                let (_, buf) = session.send_ack_recv_data().await?;
+                buf
+            } else {
+                session
+                    .recv_with_timeout(session.options().blksize() + HEADER_LEN)
+                    .await?
+            };
+            Ok(Some(buf))
+        }
         Ordering::Less => {
             // 期待したブロックよりも先のブロックを受け取った。
+            // 受信したブロックは順に書き込むだけで並べ替えないため、
+            // ウィンドウ内で順序が入れ替わった場合は最後に書き込んだブロックを再度 ACK して再送させる。
             let (_, buf) = session.send_ack_recv_data().await?;
             session.received_data_clear();
             Ok(Some(buf))
         }
         Ordering::Equal => {
-            session.received_data_inc();
+            let mut data = data.clone();
+            let mut blocknum = blocknum;
+            loop {
+                session.received_data_inc();
 
-            if blocknum_expect == ROLLOVER {
-                session.rollover_add(1);
-            }
+                // 直前に ACK したブロックより小さければ 65535 から戻っている。
+                if blocknum < session.blocknum_ack() {
+                    session.rollover_add(1);
+                }
 
-            let (_, lastch) = session.write(data.as_ref()).await?;
-            session.set_lastch(lastch);
+                let (size, lastch) = match session.write(data.as_ref()).await {
+                    Ok(ret) => ret,
+                    Err(e) => {
+                        error!(
+                            "[{}] failed to write DATA block #{}: {:?}",
+                            session.remote_addr(),
+                            blocknum,
+                            e
+                        );
+                        return Err(e);
+                    }
+                };
+                session.set_lastch(lastch);
+                session.progress_data(size);
 
-            // データの保存が成功したら ACK を更新する。
-            session.set_blocknum_ack(blocknum);
+                // データの保存が成功したら ACK を更新する。
+                session.set_blocknum_ack(blocknum);
 
-            if data.len() < session.options().blksize() {
-                session.send_ack().await?;
-                return Ok(None);
+                if data.len() < session.options().blksize() {
+                    // 最後のブロックはファイルを確定してから ACK を送信する。
+                    session.finish_write().await?;
+                    session.send_ack().await?;
+                    if session.options().confirm() {
+                        session.confirm_received().await?;
+                    } else {
+                        session.dally().await?;
+                    }
+                    return Ok(None);
+                }
+
+                // 先に受信して保持していたブロックを順に書き込む。
+                blocknum = session.blocknum_ack_add(1);
+                match session.sack_take(blocknum) {
+                    Some(next) => data = next,
+                    _ => break,
+                }
             }
 
             if session.received_data_last() {
@@ -122,6 +377,12 @@ async fn handle_data(
                 Ok(Some(buf))
             }
         }
+        Ordering::Greater if blocknum == session.blocknum_ack() => {
+            // 最後に受信したブロックの再送は ACK が失われたことを示すため、再度 ACK する。
+            let (_, buf) = session.send_ack_recv_data().await?;
+            session.received_data_clear();
+            Ok(Some(buf))
+        }
         Ordering::Greater => {
             // 期待したブロックよりも前のブロックの場合は無視する。
             let buf = session
@@ -143,7 +404,15 @@ fn handle_error(
         error.error_code(),
         error.message()
     );
-    Ok(None)
+
+    let code = ErrorCode::from(error.error_code());
+    if let Some(handler) = session.peer_error_handler() {
+        handler(code.clone(), error.message());
+    }
+
+    // ERROR を受信した時点で転送は終了しているため、ERROR を送り返さない。
+    session.set_peer_errored();
+    Err(Error::from_peer(code, error.message()))
 }
 
 async fn handle_oack(
@@ -152,6 +421,7 @@ async fn handle_oack(
     oack: &mut Bytes,
 ) -> Result<Option<Bytes>, Error> {
     // クライアントのみ。
+    // サーバが縮小したオプション (blksize など) を以降の送受信に使用する。
     let options = packet::parse_oack(oack)?;
     session.set_options(options);
 
@@ -160,6 +430,9 @@ async fn handle_oack(
         _ => {
             if session.options().tsize() != 0 {
                 // TODO: check ErrorCode::DiskFull
+                if session.preallocate() {
+                    session.allocate(session.options().tsize()).await?;
+                }
             }
 
             session.send_ack_recv_data().await
@@ -174,12 +447,21 @@ async fn handle_packet(
     session: &mut session::TftpSession,
     mut buf: Bytes,
 ) -> Result<(), Error> {
+    let mut progress = (session.blocknum_ack(), session.rollover());
+    let mut no_progress = 0;
     loop {
         let op_code = packet::parse_opcode(&mut buf)?.ok_or(Error::InvalidOpCode)?;
 
         let ret = match op_code {
+            OpCode::Ack if is_receiver(session, req_code) => {
+                handle_stray_ack(session, &mut buf).await
+            }
             OpCode::Ack => handle_ack(session, &mut buf).await,
             OpCode::Data => handle_data(session, &mut buf).await,
+            OpCode::Oack if *session.role() == session::Role::Server => {
+                // サーバが OACK を受信することはない。
+                return Err(Error::InvalidOpCode);
+            }
             OpCode::Oack => handle_oack(session, req_code, &mut buf).await,
             OpCode::Error => handle_error(session, &mut buf),
             _ => return Err(Error::InvalidOpCode),
@@ -189,9 +471,91 @@ async fn handle_packet(
             Some(tmp) => buf = tmp,
             _ => break,
         }
+
+        // tsize と最低転送レートから求めた期限を過ぎた場合は中断する。
+        if session.deadline_exceeded() {
+            return Err(Error::Timedout);
+        }
+
+        // ブロック番号が進まないパケットを受信し続ける場合は中断する。
+        let current = (session.blocknum_ack(), session.rollover());
+        if current == progress {
+            no_progress += 1;
+            let limit = NO_PROGRESS_LIMIT.max(session.options().windowsize() as u32 * 2);
+            if no_progress > limit {
+                return Err(Error::NoProgress);
+            }
+        } else {
+            progress = current;
+            no_progress = 0;
+        }
     }
 
     trace!("[{}] completed: {:?}", session.remote_addr(), req_code,);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocknum_add_rollover() {
+        assert_eq!(65535, blocknum_add(65534, 1, 0));
+        assert_eq!(0, blocknum_add(65535, 1, 0));
+        assert_eq!(4, blocknum_add(65530, 10, 0));
+
+        assert_eq!(65535, blocknum_add(65534, 1, 1));
+        assert_eq!(1, blocknum_add(65535, 1, 1));
+        assert_eq!(5, blocknum_add(65530, 10, 1));
+    }
+
+    #[tokio::test]
+    async fn apply_ttl_v4() -> Result<(), Error> {
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        apply_ttl(&sock, Some(1))?;
+        assert_eq!(1, sock.ttl()?);
+
+        apply_ttl(&sock, None)?;
+        assert_eq!(1, sock.ttl()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn apply_ttl_v6() -> Result<(), Error> {
+        // IPv6 を使えない環境では確認しない。
+        let sock = match UdpSocket::bind("[::1]:0").await {
+            Ok(sock) => sock,
+            Err(_) => return Ok(()),
+        };
+        apply_ttl(&sock, Some(1))?;
+        assert_eq!(1, SockRef::from(&sock).unicast_hops_v6()?);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn apply_tos_v4() -> Result<(), Error> {
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        apply_tos(&sock, Some(0xb8))?;
+        assert_eq!(0xb8, sock.tos()?);
+
+        apply_tos(&sock, None)?;
+        assert_eq!(0xb8, sock.tos()?);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn apply_tos_v6() -> Result<(), Error> {
+        // IPv6 を使えない環境では確認しない。
+        let sock = match UdpSocket::bind("[::1]:0").await {
+            Ok(sock) => sock,
+            Err(_) => return Ok(()),
+        };
+        apply_tos(&sock, Some(0xb8))?;
+        assert_eq!(0xb8, SockRef::from(&sock).tclass_v6()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// 送信元 IP アドレスごとの要求数をトークンバケットで制限する。
+// 送信元を詐称した大量の要求で管理用の表が肥大化しないよう、
+// 追跡するアドレス数を max_clients までとし、最も長く使用されていないものから破棄する。
+#[derive(Debug)]
+pub struct ClientLimiter {
+    rate: f64,
+    max_clients: usize,
+    clients: Mutex<Clients>,
+}
+
+#[derive(Debug, Default)]
+struct Clients {
+    buckets: HashMap<IpAddr, Bucket>,
+    // 最後に使用した順序 -> アドレス。先頭が最も古い。
+    lru: BTreeMap<u64, IpAddr>,
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    seq: u64,
+}
+
+impl ClientLimiter {
+    // rate は 1 秒あたりに受け付ける要求数で、同時に rate 個までの要求を受け付ける。
+    pub fn new(rate: u32, max_clients: usize) -> Self {
+        ClientLimiter {
+            rate: rate.max(1) as f64,
+            max_clients: max_clients.max(1),
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        clients.seq += 1;
+        let seq = clients.seq;
+
+        let (tokens, last_seq) = match clients.buckets.get(&ip) {
+            Some(bucket) => {
+                let elapsed = now.saturating_duration_since(bucket.updated_at);
+                let tokens = bucket.tokens + elapsed.as_secs_f64() * self.rate;
+                (tokens.min(self.rate), Some(bucket.seq))
+            }
+            _ => (self.rate, None),
+        };
+
+        match last_seq {
+            Some(last_seq) => {
+                clients.lru.remove(&last_seq);
+            }
+            _ => {
+                if clients.buckets.len() >= self.max_clients {
+                    let oldest = clients.lru.keys().next().copied();
+                    if let Some(oldest) = oldest.and_then(|s| clients.lru.remove(&s)) {
+                        clients.buckets.remove(&oldest);
+                    }
+                }
+            }
+        }
+
+        let allowed = tokens >= 1.0;
+        clients.buckets.insert(
+            ip,
+            Bucket {
+                tokens: if allowed { tokens - 1.0 } else { tokens },
+                updated_at: now,
+                seq,
+            },
+        );
+        clients.lru.insert(seq, ip);
+        allowed
+    }
+
+    #[cfg(test)]
+    fn tracked(&self) -> usize {
+        self.clients.lock().unwrap().buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn allow_within_rate() {
+        let limiter = ClientLimiter::new(2, 16);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(ip, now));
+        assert!(!limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), now));
+
+        assert!(limiter.allow_at(ip, now + Duration::from_millis(500)));
+        assert!(!limiter.allow_at(ip, now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn tracked_clients_bounded() {
+        let limiter = ClientLimiter::new(1, 100);
+        let now = Instant::now();
+        for i in 0..10000u32 {
+            assert!(limiter.allow_at(IpAddr::V4(Ipv4Addr::from(i)), now));
+            assert!(limiter.tracked() <= 100);
+        }
+        assert_eq!(100, limiter.tracked());
+
+        // 最近使用したアドレスは破棄されずに制限が続く。
+        let recent = IpAddr::V4(Ipv4Addr::from(9999));
+        assert!(!limiter.allow_at(recent, now));
+        let evicted = IpAddr::V4(Ipv4Addr::from(0));
+        assert!(limiter.allow_at(evicted, now));
+    }
+}
@@ -1,12 +1,64 @@
+use super::error::Error;
+use super::file;
+use super::ROLLOVER;
 use bytes::{BufMut, Bytes, BytesMut};
 use std::path::Path;
+use tokio::fs;
+
+// x- で始まるオプションは独自拡張のため、同じ実装のクライアントとサーバ間でのみ有効になる。
+const SUPPORTED_OPTIONS: [&str; 11] = [
+    "blksize",
+    "timeout",
+    "tsize",
+    "windowsize",
+    "rollover",
+    "x-sack",
+    "x-head",
+    "x-offset",
+    "x-window",
+    "x-mtime",
+    "x-confirm",
+];
+
+pub fn supported_options() -> &'static [&'static str] {
+    &SUPPORTED_OPTIONS
+}
+
+// cut_off によって要求より小さい値に制限されたオプション。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clamp {
+    option: &'static str,
+    requested: u64,
+    granted: u64,
+}
 
-#[derive(Clone, Debug, Default)]
+impl Clamp {
+    pub fn option(&self) -> &str {
+        self.option
+    }
+
+    pub fn requested(&self) -> u64 {
+        self.requested
+    }
+
+    pub fn granted(&self) -> u64 {
+        self.granted
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Options {
     blksize: Option<u16>,
     timeout: Option<u8>,
     tsize: Option<u64>,
     windowsize: Option<u16>,
+    rollover: Option<u8>,
+    sack: Option<u8>,
+    head: Option<u8>,
+    offset: Option<u64>,
+    window: Option<u16>,
+    mtime: Option<u64>,
+    confirm: Option<u8>,
 }
 
 impl Options {
@@ -22,48 +74,169 @@ impl Options {
         self.tsize.unwrap_or(0)
     }
 
+    pub fn set_blksize(&mut self, blksize: u16) {
+        if self.blksize.is_some() {
+            self.blksize = Some(blksize.clamp(8, 65464));
+        }
+    }
+
     pub fn windowsize(&self) -> u16 {
         self.windowsize.unwrap_or(1)
     }
 
-    pub fn as_bytes(&self) -> Bytes {
-        let mut bytes = BytesMut::new();
+    pub fn set_windowsize(&mut self, windowsize: u16) {
+        if self.windowsize.is_some() {
+            self.windowsize = Some(windowsize.max(1));
+        }
+    }
 
-        if let Some(blksize) = self.blksize {
-            bytes.put("blksize".as_bytes());
-            bytes.put_u8(0);
+    // ブロック番号が 65535 を超えたときに戻る値 (0 または 1)。
+    pub fn rollover(&self) -> u16 {
+        self.rollover.map(u16::from).unwrap_or(ROLLOVER)
+    }
 
-            bytes.put(blksize.to_string().as_bytes());
-            bytes.put_u8(0);
-        }
+    pub fn sack(&self) -> bool {
+        self.sack.is_some()
+    }
 
-        if let Some(timeout) = self.timeout {
-            bytes.put("timeout".as_bytes());
-            bytes.put_u8(0);
+    // tsize を返すだけで転送しない (HEAD 相当)。
+    pub fn head(&self) -> bool {
+        self.head.is_some()
+    }
 
-            bytes.put(timeout.to_string().as_bytes());
-            bytes.put_u8(0);
-        }
+    // RRQ の転送を開始するバイト位置 (octet モードのみ)。
+    pub fn offset(&self) -> u64 {
+        self.offset.unwrap_or(0)
+    }
 
-        if let Some(tsize) = self.tsize {
-            bytes.put("tsize".as_bytes());
-            bytes.put_u8(0);
+    pub fn set_offset(&mut self, offset: u64) {
+        self.offset = Some(offset).filter(|&o| o > 0);
+    }
 
-            bytes.put(tsize.to_string().as_bytes());
-            bytes.put_u8(0);
-        }
+    // 手元のファイルの更新日時 (UNIX 時間の秒)。サーバは変更がなければ x-mtime のみの OACK を返す。
+    pub fn mtime(&self) -> Option<u64> {
+        self.mtime
+    }
 
-        if let Some(windowsize) = self.windowsize {
-            bytes.put("windowsize".as_bytes());
-            bytes.put_u8(0);
+    pub fn set_mtime(&mut self, mtime: Option<u64>) {
+        self.mtime = mtime;
+    }
+
+    // 最後の ACK の後に、受信側と送信側で転送したバイト数を確認し合う。
+    pub fn confirm(&self) -> bool {
+        self.confirm.is_some()
+    }
 
-            bytes.put(windowsize.to_string().as_bytes());
-            bytes.put_u8(0);
+    pub fn as_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::new();
+
+        for key in supported_options() {
+            if let Some(value) = self.value(key) {
+                bytes.put(key.as_bytes());
+                bytes.put_u8(0);
+
+                bytes.put(value.as_bytes());
+                bytes.put_u8(0);
+            }
         }
 
         bytes.freeze()
     }
 
+    fn value(&self, key: &str) -> Option<String> {
+        match key {
+            "blksize" => self.blksize.map(|v| v.to_string()),
+            "timeout" => self.timeout.map(|v| v.to_string()),
+            "tsize" => self.tsize.map(|v| v.to_string()),
+            "windowsize" => self.windowsize.map(|v| v.to_string()),
+            "rollover" => self.rollover.map(|v| v.to_string()),
+            "x-sack" => self.sack.map(|v| v.to_string()),
+            "x-head" => self.head.map(|v| v.to_string()),
+            "x-offset" => self.offset.map(|v| v.to_string()),
+            "x-window" => self.window.map(|v| v.to_string()),
+            "x-mtime" => self.mtime.map(|v| v.to_string()),
+            "x-confirm" => self.confirm.map(|v| v.to_string()),
+            _ => None,
+        }
+    }
+
+    fn set_value(&mut self, key: &str, value: &str) {
+        match key {
+            "blksize" => {
+                if let Ok(blksize) = value.parse::<u16>() {
+                    if (8..=65464).contains(&blksize) {
+                        self.blksize = Some(blksize);
+                    }
+                }
+            }
+            "timeout" => {
+                if let Ok(timeout) = value.parse::<u8>() {
+                    if 1 <= timeout {
+                        self.timeout = Some(timeout);
+                    }
+                }
+            }
+            "tsize" => {
+                if let Ok(tsize) = value.parse::<u64>() {
+                    self.tsize = Some(tsize);
+                }
+            }
+            "windowsize" => {
+                if let Ok(windowsize) = value.parse::<u16>() {
+                    if 1 <= windowsize {
+                        self.windowsize = Some(windowsize);
+                    }
+                }
+            }
+            "rollover" => {
+                if let Ok(rollover) = value.parse::<u8>() {
+                    if rollover <= 1 {
+                        self.rollover = Some(rollover);
+                    }
+                }
+            }
+            "x-sack" => {
+                if let Ok(sack) = value.parse::<u8>() {
+                    if 1 <= sack {
+                        self.sack = Some(sack);
+                    }
+                }
+            }
+            "x-head" => {
+                if let Ok(head) = value.parse::<u8>() {
+                    if 1 <= head {
+                        self.head = Some(head);
+                    }
+                }
+            }
+            "x-offset" => {
+                if let Ok(offset) = value.parse::<u64>() {
+                    self.set_offset(offset);
+                }
+            }
+            "x-window" => {
+                if let Ok(window) = value.parse::<u16>() {
+                    if 1 <= window {
+                        self.window = Some(window);
+                    }
+                }
+            }
+            "x-mtime" => {
+                if let Ok(mtime) = value.parse::<u64>() {
+                    self.mtime = Some(mtime);
+                }
+            }
+            "x-confirm" => {
+                if let Ok(confirm) = value.parse::<u8>() {
+                    if 1 <= confirm {
+                        self.confirm = Some(confirm);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
     pub fn cut_off(&mut self, limitations: &Options) {
         if let Some(blksize) = self.blksize {
             if limitations.blksize.map(|b| b < blksize).unwrap_or(false) {
@@ -71,6 +244,7 @@ impl Options {
             }
         }
 
+        // 受け付けないオプションは OACK に含めないよう取り除く。
         if limitations.timeout.is_none() {
             self.timeout = None;
         }
@@ -79,6 +253,24 @@ impl Options {
             self.tsize = None;
         }
 
+        // tsize を返せない場合は x-head も受け付けない。
+        if self.tsize.is_none() {
+            self.head = None;
+        }
+
+        // rollover は受け付ける場合、要求された値をそのまま使用する。
+        if limitations.rollover.is_none() {
+            self.rollover = None;
+        }
+
+        if limitations.sack.is_none() {
+            self.sack = None;
+        }
+
+        if limitations.confirm.is_none() {
+            self.confirm = None;
+        }
+
         if let Some(windowsize) = self.windowsize {
             if limitations
                 .windowsize
@@ -88,6 +280,40 @@ impl Options {
                 self.windowsize = limitations.windowsize;
             }
         }
+
+        // x-window を要求された場合は、その値を上限にサーバの windowsize まで拡大する。
+        if limitations.window.is_none() {
+            self.window = None;
+        }
+
+        if let (Some(window), Some(windowsize), Some(preferred)) =
+            (self.window, self.windowsize, limitations.windowsize)
+        {
+            if windowsize < preferred {
+                self.windowsize = Some(preferred.min(window).max(windowsize));
+            }
+        }
+    }
+
+    // 要求したオプションと cut_off 後のオプションを比較し、値が縮小されたものを返す。
+    // 受け付けられずに取り除かれたオプションは含まない。
+    pub fn clamps(&self, granted: &Options) -> Vec<Clamp> {
+        let pairs = [
+            ("blksize", self.blksize, granted.blksize),
+            ("windowsize", self.windowsize, granted.windowsize),
+        ];
+
+        pairs
+            .iter()
+            .filter_map(|&(option, requested, granted)| match (requested, granted) {
+                (Some(requested), Some(granted)) if granted < requested => Some(Clamp {
+                    option,
+                    requested: requested as u64,
+                    granted: granted as u64,
+                }),
+                _ => None,
+            })
+            .collect()
     }
 
     pub fn has_option(&self) -> bool {
@@ -95,11 +321,31 @@ impl Options {
             || self.timeout.is_some()
             || self.tsize.is_some()
             || self.windowsize.is_some()
+            || self.rollover.is_some()
+            || self.sack.is_some()
+            || self.head.is_some()
+            || self.offset.is_some()
+            || self.window.is_some()
+            || self.mtime.is_some()
+            || self.confirm.is_some()
     }
 
-    pub fn set_tsize(&mut self, filepath: &Path) {
+    pub async fn set_tsize(&mut self, filepath: &Path, mode: &str) -> Result<(), Error> {
         if self.tsize.is_some() {
-            self.tsize = Some(filepath.metadata().unwrap().len());
+            let len = if file::Mode::from(mode) == file::Mode::Octet {
+                fs::metadata(filepath).await?.len()
+            } else {
+                file::netascii_len(filepath).await?
+            };
+            self.set_tsize_len(len);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_tsize_len(&mut self, len: u64) {
+        if self.tsize.is_some() {
+            self.tsize = Some(len);
         }
     }
 }
@@ -121,37 +367,11 @@ impl From<&mut Bytes> for Options {
                 break;
             }
 
-            let k = String::from_utf8_lossy(key.unwrap());
+            let k = String::from_utf8_lossy(key.unwrap()).to_lowercase();
             let v = String::from_utf8_lossy(value.unwrap());
 
-            if k.to_lowercase() == "blksize" {
-                if let Ok(blksize) = v.parse::<u16>() {
-                    if (8..=65464).contains(&blksize) {
-                        options.blksize = Some(blksize);
-                    }
-                }
-            }
-
-            if k.to_lowercase() == "timeout" {
-                if let Ok(timeout) = v.parse::<u8>() {
-                    if 1 <= timeout {
-                        options.timeout = Some(timeout);
-                    }
-                }
-            }
-
-            if k.to_lowercase() == "tsize" {
-                if let Ok(tsize) = v.parse::<u64>() {
-                    options.tsize = Some(tsize);
-                }
-            }
-
-            if k.to_lowercase() == "windowsize" {
-                if let Ok(windowsize) = v.parse::<u16>() {
-                    if 1 <= windowsize {
-                        options.windowsize = Some(windowsize);
-                    }
-                }
+            if supported_options().contains(&k.as_str()) {
+                options.set_value(&k, &v);
             }
         }
 
@@ -201,7 +421,119 @@ impl OptionBuilder {
         }
     }
 
+    pub fn rollover(self, rollover: u8) -> Self {
+        OptionBuilder {
+            options: Options {
+                rollover: Some(rollover.min(1)),
+                ..self.options
+            },
+        }
+    }
+
+    pub fn sack(self) -> Self {
+        OptionBuilder {
+            options: Options {
+                sack: Some(1),
+                ..self.options
+            },
+        }
+    }
+
+    pub fn confirm(self) -> Self {
+        OptionBuilder {
+            options: Options {
+                confirm: Some(1),
+                ..self.options
+            },
+        }
+    }
+
+    pub fn head(self) -> Self {
+        OptionBuilder {
+            options: Options {
+                tsize: Some(0),
+                head: Some(1),
+                ..self.options
+            },
+        }
+    }
+
+    // サーバが windowsize を window まで拡大することを許可する (サーバ側は有効にする)。
+    pub fn window_upgrade(self, window: u16) -> Self {
+        OptionBuilder {
+            options: Options {
+                window: Some(window),
+                ..self.options
+            },
+        }
+    }
+
     pub fn build(self) -> Options {
         self.options
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn set_tsize_ok() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("a");
+        std::fs::write(&filepath, [0u8; 1234])?;
+
+        let mut options = OptionBuilder::default().tsize().build();
+        options.set_tsize(&filepath, "octet").await?;
+        assert_eq!(1234, options.tsize());
+
+        let mut options = Options::default();
+        options.set_tsize(&filepath, "octet").await?;
+        assert!(!options.has_option());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn set_tsize_mode_case() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let filepath = dir.path().join("a");
+        std::fs::write(&filepath, b"a\nb\n")?;
+
+        // モード名は大文字と小文字を区別しない。
+        let mut options = OptionBuilder::default().tsize().build();
+        options.set_tsize(&filepath, "OCTET").await?;
+        assert_eq!(4, options.tsize());
+
+        options.set_tsize(&filepath, "NetASCII").await?;
+        assert_eq!(6, options.tsize());
+        Ok(())
+    }
+
+    #[test]
+    fn rollover_parsed() {
+        let mut buf = Bytes::from("rollover\x001\x00");
+        assert_eq!(1, Options::from(&mut buf).rollover());
+
+        // 0 と 1 以外は無視して既定値を使用する。
+        let mut buf = Bytes::from("rollover\x002\x00");
+        let options = Options::from(&mut buf);
+        assert!(!options.has_option());
+        assert_eq!(0, options.rollover());
+    }
+
+    #[test]
+    fn supported_options_parsed() {
+        for key in supported_options() {
+            // rollover は 0 か 1 のみを受け付ける。
+            let value = if *key == "rollover" { 1 } else { 8 };
+            let mut buf = Bytes::from(format!("{}\x00{}\x00", key.to_uppercase(), value));
+            let options = Options::from(&mut buf);
+
+            assert!(options.has_option(), "{}", key);
+            assert_eq!(
+                Bytes::from(format!("{}\x00{}\x00", key, value)),
+                options.as_bytes()
+            );
+        }
+    }
+}
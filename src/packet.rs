@@ -2,6 +2,11 @@ use super::error;
 use super::options::Options;
 use super::OpCode;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::warn;
+
+// 1 リクエストあたりに受け付けるオプションの組数と、オプション部の最大長。
+const MAX_OPTION_PAIRS: usize = 64;
+const MAX_OPTIONS_LEN: usize = 512;
 
 #[derive(Debug)]
 pub struct Request {
@@ -96,6 +101,17 @@ pub fn parse_oack(buf: &mut Bytes) -> Result<Options, error::Error> {
     Ok(Options::from(buf))
 }
 
+// x-confirm の確認で通知されたバイト数を返す。
+pub fn parse_confirm(buf: &mut Bytes) -> Result<Option<u64>, error::Error> {
+    let mut parameters = buf.split(|&b| b == 0);
+    if parameters.next() != Some(&b"x-confirm"[..]) {
+        return Ok(None);
+    }
+
+    let value = parameters.next().ok_or(error::Error::InvalidPacketLength)?;
+    Ok(std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()))
+}
+
 pub fn parse_opcode<T: Buf>(buf: &mut T) -> Result<Option<OpCode>, error::Error> {
     if buf.remaining() < 2 {
         return Err(error::Error::InvalidPacketLength);
@@ -136,6 +152,14 @@ pub fn parse_request(buf: &mut Bytes) -> Result<Request, error::Error> {
         }
     }
 
+    // オプションを解析する前に、組数と長さを検査する。
+    let section = &buf[(filename.len() + mode.len() + 2).min(buf.len())..];
+    let pairs = section.iter().filter(|&&b| b == 0).count() / 2;
+    if pairs > MAX_OPTION_PAIRS || section.len() > MAX_OPTIONS_LEN {
+        warn!("too many options: {} pairs, {} bytes", pairs, section.len());
+        return Err(error::Error::InvalidPacketLength);
+    }
+
     let options = Options::from(buf);
 
     Ok(Request {
@@ -153,6 +177,15 @@ pub fn ack(blocknum_ack: u16) -> Bytes {
     bytes.freeze()
 }
 
+// 受信済みのブロックを blocknum_ack + 1 を先頭ビットとするビットマップで通知する。
+pub fn sack(blocknum_ack: u16, bitmap: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::new();
+    bytes.put_u16(OpCode::Ack as u16);
+    bytes.put_u16(blocknum_ack);
+    bytes.put(bitmap);
+    bytes.freeze()
+}
+
 pub fn data<T: Buf>(num: u16, data: T) -> Bytes {
     let mut bytes = BytesMut::new();
     bytes.put_u16(OpCode::Data as u16);
@@ -161,11 +194,27 @@ pub fn data<T: Buf>(num: u16, data: T) -> Bytes {
     bytes.freeze()
 }
 
-pub fn error(err: error::Error) -> Bytes {
+pub fn error(err: &error::Error) -> Bytes {
+    match err {
+        error::Error::ServerBusy => error_message(err, error::SERVER_BUSY_MESSAGE),
+        _ => error_message(err, &format!("{:?}", err)),
+    }
+}
+
+// 内部の詳細を含めず、エラーコードに対応する定型文を送信する。
+// 混雑はクライアントが再試行を判断できるよう、定型文のまま通知する。
+pub fn error_quiet(err: &error::Error) -> Bytes {
+    match err {
+        error::Error::ServerBusy => error_message(err, error::SERVER_BUSY_MESSAGE),
+        _ => error_message(err, err.error_code().message()),
+    }
+}
+
+fn error_message(err: &error::Error, message: &str) -> Bytes {
     let mut bytes = BytesMut::new();
     bytes.put_u16(OpCode::Error as u16);
     bytes.put_u16(err.error_code() as u16);
-    bytes.put(format!("{:?}", err).as_bytes());
+    bytes.put(message.as_bytes());
     bytes.put_u8(0);
     bytes.freeze()
 }
@@ -177,6 +226,17 @@ pub fn oack(options: &Options) -> Bytes {
     bytes.freeze()
 }
 
+// 転送後の確認として、転送したバイト数を x-confirm のみの OACK で通知する。
+pub fn confirm(bytes: u64) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_u16(OpCode::Oack as u16);
+    buf.put(&b"x-confirm"[..]);
+    buf.put_u8(0);
+    buf.put(bytes.to_string().as_bytes());
+    buf.put_u8(0);
+    buf.freeze()
+}
+
 pub fn request(req: &Request) -> Bytes {
     let mut bytes = BytesMut::new();
     bytes.put_u16(req.op_code().clone() as u16);
@@ -192,6 +252,37 @@ pub fn request(req: &Request) -> Bytes {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_request_too_many_options() {
+        let mut buf = BytesMut::new();
+        buf.put_u16(1);
+        buf.put_slice(b"file\0octet\0");
+        for i in 0..(MAX_OPTION_PAIRS + 1) {
+            buf.put_slice(format!("{}\0{}\0", i % 10, i % 10).as_bytes());
+        }
+        let ret = parse_request(&mut buf.freeze());
+        assert!(matches!(ret, Err(error::Error::InvalidPacketLength)));
+    }
+
+    #[test]
+    fn parse_request_options_too_long() {
+        let mut buf = BytesMut::new();
+        buf.put_u16(1);
+        buf.put_slice(b"file\0octet\0blksize\0");
+        buf.put_slice(&[b'1'; MAX_OPTIONS_LEN]);
+        buf.put_u8(0);
+        let ret = parse_request(&mut buf.freeze());
+        assert!(matches!(ret, Err(error::Error::InvalidPacketLength)));
+    }
+
+    #[test]
+    fn parse_request_with_options() -> Result<(), error::Error> {
+        let mut buf = Bytes::from(&b"\0\x01file\0octet\0blksize\x001024\0tsize\x000\0"[..]);
+        let ret = parse_request(&mut buf)?;
+        assert_eq!(1024, ret.options().blksize());
+        Ok(())
+    }
+
     #[test]
     fn parse_blocknum_less_len() {
         let mut buf = Bytes::from(&[0][..]);
@@ -255,4 +346,15 @@ mod tests {
         assert_eq!(8, ret.options().blksize());
         Ok(())
     }
+
+    #[test]
+    fn parse_confirm_ok() -> Result<(), error::Error> {
+        let mut buf = confirm(1234);
+        assert_eq!(Some(OpCode::Oack), parse_opcode(&mut buf)?);
+        assert_eq!(Some(1234), parse_confirm(&mut buf)?);
+
+        let mut buf = Bytes::from(&b"tsize\x001234\x00"[..]);
+        assert_eq!(None, parse_confirm(&mut buf)?);
+        Ok(())
+    }
 }
@@ -0,0 +1,147 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    // blksize がセッションごとに異なるため、サイズ別に保持する。
+    buckets: Mutex<HashMap<usize, Vec<Vec<u8>>>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        BufferPool {
+            buckets: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    pub fn take(self: &Arc<Self>, size: usize) -> PooledBuffer {
+        let buf = self
+            .buckets
+            .lock()
+            .unwrap()
+            .get_mut(&size)
+            .and_then(|bucket| bucket.pop())
+            .unwrap_or_else(|| vec![0u8; size]);
+
+        PooledBuffer {
+            buf,
+            pool: Some(self.clone()),
+        }
+    }
+
+    fn give_back(&self, buf: Vec<u8>) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(buf.len()).or_default();
+        // 保持数を超えた分は解放する。
+        if bucket.len() < self.capacity {
+            bucket.push(buf);
+        }
+    }
+}
+
+pub struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl PooledBuffer {
+    pub fn alloc(size: usize) -> Self {
+        PooledBuffer {
+            buf: vec![0u8; size],
+            pool: None,
+        }
+    }
+
+    // 共有のバッファは返却するため先頭 len バイトを複製し、そうでなければそのまま渡す。
+    pub fn freeze(mut self, len: usize) -> Bytes {
+        match self.pool {
+            Some(_) => Bytes::copy_from_slice(&self.buf[..len]),
+            _ => {
+                let mut buf = std::mem::take(&mut self.buf);
+                buf.truncate(len);
+                Bytes::from(buf)
+            }
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give_back(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_reused() {
+        let pool = Arc::new(BufferPool::new(4));
+
+        let buf = pool.take(516);
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        let buf = pool.take(516);
+        assert_eq!(ptr, buf.as_ptr());
+
+        let other = pool.take(1028);
+        assert_eq!(1028, other.len());
+        assert_ne!(ptr, other.as_ptr());
+    }
+
+    #[test]
+    fn freeze_without_pool() {
+        let mut buf = PooledBuffer::alloc(516);
+        buf[..3].copy_from_slice(b"abc");
+        let ptr = buf.as_ptr();
+
+        let bytes = buf.freeze(3);
+        assert_eq!(b"abc", &bytes[..]);
+        assert_eq!(ptr, bytes.as_ptr());
+    }
+
+    #[test]
+    fn freeze_with_pool() {
+        let pool = Arc::new(BufferPool::new(4));
+        let mut buf = pool.take(516);
+        buf[..3].copy_from_slice(b"abc");
+
+        let bytes = buf.freeze(3);
+        assert_eq!(b"abc", &bytes[..]);
+        assert_eq!(1, pool.buckets.lock().unwrap()[&516].len());
+    }
+
+    #[test]
+    fn give_back_capacity() {
+        let pool = Arc::new(BufferPool::new(1));
+
+        let a = pool.take(8);
+        let b = pool.take(8);
+        drop(a);
+        drop(b);
+
+        assert_eq!(1, pool.buckets.lock().unwrap()[&8].len());
+    }
+}
@@ -0,0 +1,188 @@
+use super::packet;
+use super::OpCode;
+use bytes::Buf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Debug)]
+pub struct PacketTrace {
+    timestamp: Instant,
+    direction: Direction,
+    op_code: Option<OpCode>,
+    blocknum: Option<u16>,
+    payload_len: Option<usize>,
+}
+
+impl PacketTrace {
+    pub fn timestamp(&self) -> &Instant {
+        &self.timestamp
+    }
+
+    pub fn direction(&self) -> &Direction {
+        &self.direction
+    }
+
+    pub fn op_code(&self) -> Option<&OpCode> {
+        self.op_code.as_ref()
+    }
+
+    pub fn blocknum(&self) -> Option<u16> {
+        self.blocknum
+    }
+
+    // DATA の場合は実際に送受信したデータの長さ。最後のブロックは blksize より短くなる。
+    pub fn payload_len(&self) -> Option<usize> {
+        self.payload_len
+    }
+}
+
+// 送信した DATA ブロックと、それを確認した ACK の受信時刻。
+#[derive(Clone, Debug)]
+pub struct BlockTiming {
+    block: u16,
+    sent_at: Instant,
+    acked_at: Option<Instant>,
+}
+
+impl BlockTiming {
+    pub fn block(&self) -> u16 {
+        self.block
+    }
+
+    pub fn sent_at(&self) -> &Instant {
+        &self.sent_at
+    }
+
+    pub fn acked_at(&self) -> Option<&Instant> {
+        self.acked_at.as_ref()
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.acked_at.map(|acked_at| acked_at - self.sent_at)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Recorder {
+    packets: Arc<Mutex<Vec<PacketTrace>>>,
+    timings: Arc<Mutex<Vec<BlockTiming>>>,
+}
+
+impl Recorder {
+    pub fn packets(&self) -> Vec<PacketTrace> {
+        self.packets.lock().unwrap().clone()
+    }
+
+    pub fn timings(&self) -> Vec<BlockTiming> {
+        self.timings.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.packets.lock().unwrap().clear();
+        self.timings.lock().unwrap().clear();
+    }
+
+    pub fn record(&self, direction: Direction, mut buf: &[u8]) {
+        let op_code = packet::parse_opcode(&mut buf).ok().flatten();
+        let blocknum = match op_code {
+            Some(OpCode::Data) | Some(OpCode::Ack) if buf.remaining() >= 2 => Some(buf.get_u16()),
+            _ => None,
+        };
+        let payload_len = match (&op_code, blocknum) {
+            (Some(OpCode::Data), Some(_)) => Some(buf.remaining()),
+            _ => None,
+        };
+
+        let timestamp = Instant::now();
+        match (&direction, &op_code, blocknum) {
+            (Direction::Sent, Some(OpCode::Data), Some(block)) => self.sent(block, timestamp),
+            (Direction::Received, Some(OpCode::Ack), Some(block)) => self.acked(block, timestamp),
+            _ => {}
+        }
+
+        self.packets.lock().unwrap().push(PacketTrace {
+            timestamp,
+            direction,
+            op_code,
+            blocknum,
+            payload_len,
+        });
+    }
+
+    fn unacked(timings: &[BlockTiming], block: u16) -> Option<usize> {
+        timings
+            .iter()
+            .rev()
+            .take_while(|t| t.acked_at.is_none())
+            .position(|t| t.block == block)
+            .map(|i| timings.len() - 1 - i)
+    }
+
+    fn sent(&self, block: u16, timestamp: Instant) {
+        let mut timings = self.timings.lock().unwrap();
+        // 再送した場合は最後に送信した時刻で計測する。
+        match Self::unacked(&timings, block) {
+            Some(i) => timings[i].sent_at = timestamp,
+            _ => timings.push(BlockTiming {
+                block,
+                sent_at: timestamp,
+                acked_at: None,
+            }),
+        }
+    }
+
+    fn acked(&self, block: u16, timestamp: Instant) {
+        let mut timings = self.timings.lock().unwrap();
+        // ACK はそれ以前に送信したウィンドウ内のブロックもまとめて確認する。
+        if let Some(i) = Self::unacked(&timings, block) {
+            for timing in timings[..=i]
+                .iter_mut()
+                .rev()
+                .take_while(|t| t.acked_at.is_none())
+            {
+                timing.acked_at = Some(timestamp);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings_window() {
+        let recorder = Recorder::default();
+        for block in 1..=3 {
+            recorder.record(Direction::Sent, &packet::data(block, &b"a"[..]));
+        }
+        recorder.record(Direction::Sent, &packet::data(3, &b"a"[..]));
+        recorder.record(Direction::Received, &packet::ack(2));
+
+        let timings = recorder.timings();
+        assert_eq!(3, timings.len());
+        assert!(timings[0].acked_at().is_some());
+        assert!(timings[1].acked_at().is_some());
+        assert!(timings[2].acked_at().is_none());
+
+        recorder.record(Direction::Received, &packet::ack(3));
+        assert!(recorder.timings().iter().all(|t| t.rtt().is_some()));
+    }
+
+    #[test]
+    fn payload_len() {
+        let recorder = Recorder::default();
+        recorder.record(Direction::Received, &packet::data(1, &b"abc"[..]));
+        recorder.record(Direction::Sent, &packet::ack(1));
+
+        let packets = recorder.packets();
+        assert_eq!(Some(3), packets[0].payload_len());
+        assert_eq!(None, packets[1].payload_len());
+    }
+}
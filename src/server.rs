@@ -1,133 +1,3819 @@
+#[cfg(feature = "cache")]
+use super::cache::FileCache;
 use super::error::Error;
-use super::file;
-use super::options::Options;
+#[cfg(all(feature = "fd-reserve", target_os = "linux"))]
+use super::fdlimit::FdReserve;
+use super::file::{self, FileReader, FileWriter};
+use super::glob;
+use super::limiter::ClientLimiter;
+use super::options::{Clamp, OptionBuilder, Options};
 use super::packet;
+use super::pool::BufferPool;
 use super::session;
-use super::{handle_packet, OpCode};
+#[cfg(feature = "embedded")]
+use super::store::FileStore;
+use super::{apply_tos, apply_ttl, bind_socket, handle_packet, OpCode, SocketConfig};
 use bytes::Bytes;
-use log::{error, trace};
-use std::net::SocketAddr;
+use log::{error, info, trace, warn};
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::future::Future;
+use std::io;
+#[cfg(any(feature = "cache", feature = "embedded"))]
+use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::{Path, PathBuf};
-use tokio::net::UdpSocket;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
+
+const ACCEPT_QUEUE: usize = 64;
+
+// 書き込みを待っているアクセスログの行数の上限。
+const ACCESS_LOG_QUEUE: usize = 1024;
+
+// 到達性を確認する前に送信できる、要求の大きさに対する倍率。
+const AMPLIFICATION_FACTOR: usize = 3;
 
-#[derive(Debug)]
 pub struct Server {
     service_addr: SocketAddr,
+    extra_addrs: Vec<SocketAddr>,
     root: PathBuf,
     options: Options,
+    temp_dir: Option<PathBuf>,
+    policies: Vec<(String, Options, Option<u64>)>,
+    denied: Vec<String>,
+    filename_max_depth: Option<usize>,
+    filename_chars: Option<CharFilter>,
+    allowed_modes: Option<Vec<String>>,
+    #[cfg(feature = "cache")]
+    cache: Option<FileCache>,
+    progress_handler: Option<(u64, session::ProgressHandler)>,
+    read_source: Option<ReadSource>,
+    write_sink: Option<WriteSink>,
+    tcp_forward: Option<SocketAddr>,
+    min_transfer_rate: Option<u64>,
+    max_duration: Option<Duration>,
+    min_timeout: Option<Duration>,
+    quiet_errors: bool,
+    drop_garbage: bool,
+    dry_run: bool,
+    client_limiter: Option<ClientLimiter>,
+    anti_amplification: bool,
+    batch_io: bool,
+    read_ahead: bool,
+    socket_config: Option<SocketConfig>,
+    ack_frequency: Option<u16>,
+    upload_complete_handler: Option<UploadCompleteHandler>,
+    access_log: Option<mpsc::Sender<String>>,
+    access_log_writer: Mutex<Option<AccessLogWriter>>,
+    negotiate_handler: Option<NegotiateHandler>,
+    case_insensitive: bool,
+    root_check: bool,
+    allow_outside_root: bool,
+    fallback_file: Option<String>,
+    filename_mapper: Option<FilenameMapper>,
+    aborter: SessionAborter,
+    registry: SessionRegistry,
+    first_block_timeout: Option<Duration>,
+    oack_retransmit: Option<(Duration, u32)>,
+    dally: Option<Duration>,
+    reject_stray_ack: bool,
+    accept_queue: usize,
+    ttl: Option<u32>,
+    tos: Option<u8>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    #[cfg(all(feature = "fd-reserve", target_os = "linux"))]
+    fd_reserve: Option<FdReserve>,
+    #[cfg(feature = "embedded")]
+    store: Option<Arc<dyn FileStore>>,
+}
+
+type CharFilter = Arc<dyn Fn(char) -> bool + Send + Sync>;
+
+type FilenameMapper = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+type UploadCompleteHandler = Arc<dyn Fn(&UploadInfo) + Send + Sync>;
+
+type NegotiateHandler = Arc<dyn Fn(&SocketAddr, &Options, &mut Options) + Send + Sync>;
+
+type ReadSource = Arc<dyn Fn(&str) -> Option<Box<dyn FileReader>> + Send + Sync>;
+
+type WriteSink = Arc<dyn Fn(&str) -> Option<Box<dyn FileWriter>> + Send + Sync>;
+
+type AccessLogWriter = (mpsc::Receiver<String>, Box<dyn AsyncWrite + Unpin + Send>);
+
+#[derive(Clone, Debug)]
+pub struct Progress {
+    remote_addr: SocketAddr,
+    blocks: u64,
+    bytes: u64,
+}
+
+impl Progress {
+    pub fn new(remote_addr: SocketAddr, blocks: u64, bytes: u64) -> Self {
+        Progress {
+            remote_addr,
+            blocks,
+            bytes,
+        }
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+// 実行中のセッションを接続先アドレスで中断する。serve_forever の実行後も使用できる。
+// 同じアドレスからのセッションが重なっても取り違えないよう、セッションごとの ID で管理する。
+#[derive(Clone, Debug, Default)]
+pub struct SessionAborter {
+    next_id: Arc<AtomicU64>,
+    sessions: Arc<Mutex<HashMap<u64, AbortHandle>>>,
+}
+
+#[derive(Debug)]
+struct AbortHandle {
+    remote_addr: SocketAddr,
+    notify: Arc<Notify>,
+}
+
+impl SessionAborter {
+    // 同じアドレスのセッションが複数ある場合はすべて中断する。
+    pub fn abort(&self, remote_addr: &SocketAddr) -> bool {
+        let mut aborted = false;
+        for handle in self.sessions.lock().unwrap().values() {
+            if &handle.remote_addr == remote_addr {
+                handle.notify.notify_one();
+                aborted = true;
+            }
+        }
+        aborted
+    }
+
+    fn register(&self, remote_addr: SocketAddr) -> AbortGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let notify = Arc::new(Notify::new());
+        self.sessions.lock().unwrap().insert(
+            id,
+            AbortHandle {
+                remote_addr,
+                notify: notify.clone(),
+            },
+        );
+        AbortGuard {
+            aborter: self.clone(),
+            id,
+            notify,
+        }
+    }
+}
+
+// 破棄時に自身の登録のみを取り除く。
+struct AbortGuard {
+    aborter: SessionAborter,
+    id: u64,
+    notify: Arc<Notify>,
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        self.aborter.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+// 実行中のセッションの状態。
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    remote_addr: SocketAddr,
+    filename: String,
+    op_code: OpCode,
+    bytes: u64,
+    started_at: Instant,
+}
+
+impl SessionInfo {
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn op_code(&self) -> &OpCode {
+        &self.op_code
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn started_at(&self) -> &Instant {
+        &self.started_at
+    }
+}
+
+#[derive(Debug)]
+struct ActiveSession {
+    remote_addr: SocketAddr,
+    filename: String,
+    op_code: OpCode,
+    bytes: Arc<AtomicU64>,
+    started_at: Instant,
+}
+
+// 実行中のセッションの一覧。serve_forever の実行後も使用できる。
+// 転送中は転送量のカウンタのみを更新するため、一覧の取得は転送を妨げない。
+// 同じアドレスからのセッションが重なっても取り違えないよう、セッションごとの ID で管理する。
+#[derive(Clone, Debug, Default)]
+pub struct SessionRegistry {
+    next_id: Arc<AtomicU64>,
+    sessions: Arc<Mutex<HashMap<u64, ActiveSession>>>,
+}
+
+impl SessionRegistry {
+    pub fn active(&self) -> Vec<SessionInfo> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|session| SessionInfo {
+                remote_addr: session.remote_addr,
+                filename: session.filename.clone(),
+                op_code: session.op_code.clone(),
+                bytes: session.bytes.load(Ordering::Relaxed),
+                started_at: session.started_at,
+            })
+            .collect()
+    }
+
+    fn register(&self, remote_addr: SocketAddr, filename: &str, op_code: &OpCode) -> ActiveGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let bytes = Arc::new(AtomicU64::new(0));
+        self.sessions.lock().unwrap().insert(
+            id,
+            ActiveSession {
+                remote_addr,
+                filename: filename.to_string(),
+                op_code: op_code.clone(),
+                bytes: bytes.clone(),
+                started_at: Instant::now(),
+            },
+        );
+        ActiveGuard {
+            registry: self.clone(),
+            id,
+            bytes,
+        }
+    }
+}
+
+// 破棄時に一覧から取り除く。
+struct ActiveGuard {
+    registry: SessionRegistry,
+    id: u64,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionSummary {
+    remote_addr: SocketAddr,
+    op_code: OpCode,
+    filename: String,
+    bytes: u64,
+    elapsed: Duration,
+    clamps: Vec<Clamp>,
+    confirmed: bool,
+    batched_packets: u64,
+}
+
+impl SessionSummary {
+    fn new(session: &session::TftpSession, op_code: &OpCode, filename: &str) -> Self {
+        SessionSummary {
+            remote_addr: *session.remote_addr(),
+            op_code: op_code.clone(),
+            filename: filename.to_string(),
+            bytes: session.transferred_bytes(),
+            elapsed: session.elapsed(),
+            clamps: session.clamps().to_vec(),
+            confirmed: session.confirmed(),
+            batched_packets: session.batched_packets(),
+        }
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+
+    pub fn op_code(&self) -> &OpCode {
+        &self.op_code
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn elapsed(&self) -> &Duration {
+        &self.elapsed
+    }
+
+    // サーバの制限によって縮小されたオプション。
+    pub fn clamps(&self) -> &[Clamp] {
+        &self.clamps
+    }
+
+    // x-confirm によってクライアントと転送完了を確認できたか。
+    pub fn confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    // sendmmsg と recvmmsg でまとめて送受信したパケットの数。
+    pub fn batched_packets(&self) -> u64 {
+        self.batched_packets
+    }
+}
+
+// アクセスログの 1 行分の形式。
+// <アドレス> <要求> "<ファイル名>" <バイト数> <経過時間 (ms)> <confirmed> <clamps>
+impl fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:?} {:?} {} {} {}",
+            self.remote_addr,
+            self.op_code,
+            self.filename,
+            self.bytes,
+            self.elapsed.as_millis(),
+            self.confirmed
+        )?;
+
+        if self.clamps.is_empty() {
+            return write!(f, " -");
+        }
+        for (i, clamp) in self.clamps.iter().enumerate() {
+            let sep = if i == 0 { ' ' } else { ',' };
+            write!(
+                f,
+                "{}{}={}>{}",
+                sep,
+                clamp.option(),
+                clamp.requested(),
+                clamp.granted()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UploadInfo {
+    path: PathBuf,
+    remote_addr: SocketAddr,
+    bytes: u64,
+    mode: String,
+}
+
+impl UploadInfo {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn remote_addr(&self) -> &SocketAddr {
+        &self.remote_addr
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn mode(&self) -> &str {
+        &self.mode
+    }
 }
 
 impl Server {
     pub fn new(service_addr: SocketAddr, root: &Path, options: Options) -> Result<Server, Error> {
         Ok(Server {
             service_addr,
+            extra_addrs: vec![],
             root: root.canonicalize()?,
             options,
+            temp_dir: None,
+            policies: vec![],
+            denied: vec![],
+            filename_max_depth: None,
+            filename_chars: None,
+            allowed_modes: None,
+            #[cfg(feature = "cache")]
+            cache: None,
+            progress_handler: None,
+            read_source: None,
+            write_sink: None,
+            tcp_forward: None,
+            min_transfer_rate: None,
+            max_duration: None,
+            min_timeout: None,
+            quiet_errors: false,
+            drop_garbage: false,
+            dry_run: false,
+            client_limiter: None,
+            anti_amplification: false,
+            batch_io: false,
+            read_ahead: false,
+            socket_config: None,
+            ack_frequency: None,
+            upload_complete_handler: None,
+            access_log: None,
+            access_log_writer: Mutex::new(None),
+            negotiate_handler: None,
+            case_insensitive: false,
+            root_check: false,
+            allow_outside_root: false,
+            fallback_file: None,
+            filename_mapper: None,
+            aborter: SessionAborter::default(),
+            registry: SessionRegistry::default(),
+            first_block_timeout: None,
+            oack_retransmit: None,
+            dally: None,
+            reject_stray_ack: false,
+            accept_queue: ACCEPT_QUEUE,
+            ttl: None,
+            tos: None,
+            buffer_pool: None,
+            #[cfg(all(feature = "fd-reserve", target_os = "linux"))]
+            fd_reserve: None,
+            #[cfg(feature = "embedded")]
+            store: None,
         })
     }
 
-    pub async fn serve_forever(self) -> Result<(), Error> {
-        let service_sock = UdpSocket::bind(self.service_addr).await?;
+    // 受信中のファイルは temp_dir に作成し、完了後に移動する。
+    // 別のファイルシステムの場合は rename できないため、コピーして削除する。
+    pub fn with_temp_dir(self, temp_dir: &Path) -> Self {
+        Server {
+            temp_dir: Some(temp_dir.to_path_buf()),
+            ..self
+        }
+    }
 
-        trace!("serving: {:?}", &self);
+    // ファイル名に一致した最初のポリシーの制限を使用する。
+    pub fn with_policy(mut self, pattern: &str, limitations: Options) -> Self {
+        self.policies.push((pattern.to_string(), limitations, None));
+        self
+    }
 
-        loop {
-            let mut buf = vec![0; 1024];
-            let (size, remote_addr) = service_sock.recv_from(buf.as_mut_slice()).await?;
-            buf.resize(size, 0);
+    // with_policy に加えて、一致したファイルの RRQ の送信速度を max_rate (bytes/sec) までに抑える。
+    pub fn with_rate_limited_policy(
+        mut self,
+        pattern: &str,
+        limitations: Options,
+        max_rate: u64,
+    ) -> Self {
+        self.policies
+            .push((pattern.to_string(), limitations, Some(max_rate)));
+        self
+    }
 
-            let root = self.root.clone();
-            let options = self.options.clone();
-            tokio::spawn(async move {
-                match UdpSocket::bind((self.service_addr.ip(), 0)).await {
-                    Ok(sock) => {
-                        if let Err(e) = sock.connect(remote_addr).await {
-                            eprint!("[{}] {:?}", remote_addr, e);
-                            return;
-                        }
+    // ファイル名、またはその末尾の要素がパターンに一致する要求は AccessViolation とする。
+    pub fn with_denied(mut self, pattern: &str) -> Self {
+        self.denied.push(pattern.to_string());
+        self
+    }
 
-                        let mut session = session::TftpSession::new(sock, remote_addr);
-                        if let Err(e) =
-                            handle_request(&mut session, Bytes::from(buf), root.as_path(), options)
-                                .await
-                        {
-                            if let Err(e) = session.send_error(e).await {
-                                error!("failed to send error: [{}] {:?}", remote_addr, e);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("failed to bind: [{}] {:?}", remote_addr, e);
-                    }
-                }
-            });
+    // WRQ のファイル名に制御文字を含むもの、max_depth より深いサブディレクトリを指すものを拒否する。
+    // 0 の場合はルートディレクトリ直下のファイルのみを受け付ける。
+    pub fn with_filename_validation(self, max_depth: usize) -> Self {
+        Server {
+            filename_max_depth: Some(max_depth),
+            ..self
         }
     }
-}
 
-async fn handle_request(
-    session: &mut session::TftpSession,
-    mut buf: Bytes,
-    root: &Path,
-    limitations: Options,
-) -> Result<(), Error> {
-    let req = packet::parse_request(&mut buf)?;
-    session.set_mode(req.mode());
+    // WRQ のファイル名に使用できる文字を f が true を返すものに制限する。パス区切り文字は対象外。
+    pub fn with_filename_chars(self, f: impl Fn(char) -> bool + Send + Sync + 'static) -> Self {
+        Server {
+            filename_chars: Some(Arc::new(f)),
+            ..self
+        }
+    }
 
-    trace!("requested: {:?}", &req);
+    pub fn with_allowed_modes(self, modes: &[&str]) -> Self {
+        Server {
+            allowed_modes: Some(modes.iter().map(|m| m.to_lowercase()).collect()),
+            ..self
+        }
+    }
 
-    let mut filepath = PathBuf::from(root);
-    filepath.push(req.filename());
+    // 同じファイルへの RRQ はメモリ上の内容を共有する。
+    #[cfg(feature = "cache")]
+    pub fn with_file_cache(self, capacity: usize) -> Self {
+        Server {
+            cache: Some(FileCache::new(capacity)),
+            ..self
+        }
+    }
 
-    match req.op_code() {
-        OpCode::Rrq => {
-            let local_file = filepath.canonicalize()?;
-            if !local_file.starts_with(root) {
-                return Err(Error::InvalidFileName);
-            }
+    // 転送中のセッションについて interval ブロックごとに進捗を通知する。
+    pub fn with_progress(
+        self,
+        interval: u64,
+        f: impl Fn(&Progress) + Send + Sync + 'static,
+    ) -> Self {
+        Server {
+            progress_handler: Some((interval, Arc::new(f))),
+            ..self
+        }
+    }
 
-            let local = file::open_read(&local_file).await?;
-            session.set_reader(local);
+    // 転送速度 (bytes/sec) がこれを下回るセッションは中断する。
+    pub fn with_min_transfer_rate(self, rate: u64) -> Self {
+        Server {
+            min_transfer_rate: Some(rate),
+            ..self
+        }
+    }
 
-            let mut options = req.options().clone();
-            options.cut_off(&limitations);
-            options.set_tsize(&local_file);
-            session.set_options(options);
+    // 受信が続いていても、この時間を超えるセッションは中断する。
+    pub fn with_max_duration(self, duration: Duration) -> Self {
+        Server {
+            max_duration: Some(duration),
+            ..self
+        }
+    }
 
-            let (_, buf) = if session.options().has_option() {
-                session.send_oack_recv_data().await?
-            } else {
-                session.send_data_recv_ack(0).await?
-            };
+    // 再送までの待ち時間を timeout オプションより短くする。
+    pub fn with_min_timeout(self, timeout: Duration) -> Self {
+        Server {
+            min_timeout: Some(timeout),
+            ..self
+        }
+    }
 
-            handle_packet(req.op_code(), session, buf).await?;
+    // ERROR のメッセージを定型文にして、ファイルパスなどの内部情報を送信しない。
+    pub fn with_quiet_errors(self) -> Self {
+        Server {
+            quiet_errors: true,
+            ..self
         }
-        OpCode::Wrq => {
-            if (!filepath.starts_with(root)) || filepath.iter().any(|i| i == "..") {
-                return Err(Error::InvalidFileName);
+    }
+
+    // オペコードとして解釈できないデータグラムは、転送用のソケットを作成せず応答もしない。
+    // オペコードが正しい不正な要求には、これまでどおり ERROR を返す。
+    pub fn with_drop_garbage(self) -> Self {
+        Server {
+            drop_garbage: true,
+            ..self
+        }
+    }
+
+    // 要求の解析、パスの解決、オプションの交渉までを行い、転送する代わりに内容を記録して ERROR を返す。
+    // ファイルは読み書きしないため、新しい設定を実際の要求で安全に確認できる。
+    pub fn with_dry_run(self) -> Self {
+        Server {
+            dry_run: true,
+            ..self
+        }
+    }
+
+    // 送信元 IP アドレスごとに 1 秒あたり rate 個まで要求を受け付け、超えた要求は応答せずに破棄する。
+    // 追跡するアドレスは max_clients 個までとし、最も長く要求のないものから忘れる。
+    pub fn with_client_rate_limit(self, rate: u32, max_clients: usize) -> Self {
+        Server {
+            client_limiter: Some(ClientLimiter::new(rate, max_clients)),
+            ..self
+        }
+    }
+
+    // 送信元を詐称した RRQ による反射・増幅攻撃を緩和する。
+    // RRQ には必ず OACK を返して ACK を待ち、クライアントの到達性を確認するまで DATA を送信しない。
+    // OACK の再送を含めて、確認前に送信するのは要求の 3 倍のバイト数までとする。
+    // OACK を返せない (オプションのない) RRQ は NegotiationRequired で拒否する。
+    pub fn with_anti_amplification(self) -> Self {
+        Server {
+            anti_amplification: true,
+            ..self
+        }
+    }
+
+    // RRQ のウィンドウ内の DATA を sendmmsg でまとめて送信する (batch-io フィーチャ、Linux のみ)。
+    pub fn with_batch_io(self) -> Self {
+        Server {
+            batch_io: true,
+            ..self
+        }
+    }
+
+    // RRQ で ACK を待つ間に次のウィンドウを先読みする。読み込みの遅いディスク向け。
+    pub fn with_read_ahead(self) -> Self {
+        Server {
+            read_ahead: true,
+            ..self
+        }
+    }
+
+    // 受付用と転送用のソケットをバインドする前に呼び出し、任意のソケットオプションを設定する。
+    pub fn with_socket_config(
+        self,
+        f: impl Fn(&socket2::Socket) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        Server {
+            socket_config: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    // WRQ の受信時に frequency ブロックごとに ACK を送信する。
+    pub fn with_ack_frequency(self, frequency: u16) -> Self {
+        Server {
+            ack_frequency: Some(frequency),
+            ..self
+        }
+    }
+
+    // 要求ごとにルートディレクトリが存在することを確認する。
+    // マウントが外れた場合などに、個別のファイルのエラーではなく ServiceUnavailable を返す。
+    pub fn with_root_check(self) -> Self {
+        Server {
+            root_check: true,
+            ..self
+        }
+    }
+
+    // 危険: ルートディレクトリ外のパス (絶対パスや ..) の読み書きを許可する。
+    // コンテナ内で読み取り専用のファイルシステム全体を公開する場合など、信頼できる環境でのみ使用する。
+    pub fn with_allow_outside_root(self) -> Self {
+        Server {
+            allow_outside_root: true,
+            ..self
+        }
+    }
+
+    // RRQ のファイルが存在しない場合、大文字小文字を区別せずに探す。
+    pub fn with_case_insensitive(self) -> Self {
+        Server {
+            case_insensitive: true,
+            ..self
+        }
+    }
+
+    // 要求されたファイル名を変換してからパスを解決する。None を返した場合は FileNotFound とする。
+    // 変換後のファイル名もルートディレクトリ外は参照できない。
+    pub fn with_filename_mapper(
+        self,
+        f: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        Server {
+            filename_mapper: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    // RRQ のファイルが存在しない場合、代わりに filename の内容を送信する。
+    pub fn with_fallback_file(self, filename: &str) -> Self {
+        Server {
+            fallback_file: Some(filename.to_string()),
+            ..self
+        }
+    }
+
+    // WRQ が成功し、ファイルを確定した後に呼び出す。
+    pub fn on_upload_complete(self, f: impl Fn(&UploadInfo) + Send + Sync + 'static) -> Self {
+        Server {
+            upload_complete_handler: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    // 制限を適用した後、OACK を作成する前に呼び出す。
+    // 要求されたオプションを参照して、許可するオプション (granted) をさらに変更できる。
+    pub fn on_negotiate(
+        self,
+        f: impl Fn(&SocketAddr, &Options, &mut Options) + Send + Sync + 'static,
+    ) -> Self {
+        Server {
+            negotiate_handler: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    // ファイルディスクリプタのソフトリミットのうち reserve 個を残し、
+    // 転送中のセッションがそれを使い切りそうな場合は新しい要求を ServerBusy で拒否する。
+    // ログの出力や終了処理に必要なディスクリプタまで枯渇しないようにする (fd-reserve フィーチャ、Linux のみ)。
+    #[cfg(all(feature = "fd-reserve", target_os = "linux"))]
+    pub fn with_fd_reserve(self, reserve: u64) -> Self {
+        let fd_reserve = match FdReserve::new(reserve) {
+            Ok(fd_reserve) => Some(fd_reserve),
+            Err(e) => {
+                warn!("ignored: failed to get file descriptor limit: {:?}", e);
+                None
             }
+        };
 
-            let local = file::open_create(&filepath).await?;
-            session.set_writer(local);
+        Server { fd_reserve, ..self }
+    }
 
-            let mut options = req.options().clone();
-            options.cut_off(&limitations);
-            session.set_options(options);
+    // OACK を送信してから最初の応答を待つ時間。
+    pub fn with_first_block_timeout(self, timeout: Duration) -> Self {
+        Server {
+            first_block_timeout: Some(timeout),
+            ..self
+        }
+    }
 
-            // TODO: check ErrorCode::DiskFull
+    // OACK の再送間隔と回数を DATA とは別に設定する。
+    // OACK は小さく再送の負担が少ないため、短い間隔で多めに再送して交渉の停滞を避ける。
+    pub fn with_oack_retransmit(self, timeout: Duration, retries: u32) -> Self {
+        Server {
+            oack_retransmit: Some((timeout, retries)),
+            ..self
+        }
+    }
 
-            let (_, buf) = if session.options().has_option() {
-                session.send_oack_recv_data().await?
-            } else {
-                session.send_ack_recv_data().await?
-            };
+    // WRQ の OACK に DATA 1 ではなく ACK を返すクライアントを、無視せずに不正な操作として中断する。
+    pub fn with_reject_stray_ack(self) -> Self {
+        Server {
+            reject_stray_ack: true,
+            ..self
+        }
+    }
 
-            handle_packet(req.op_code(), session, buf).await?;
+    // WRQ の最後の ACK を送信した後、再送された最後の DATA を待つ時間。
+    pub fn with_dally(self, dally: Duration) -> Self {
+        Server {
+            dally: Some(dally),
+            ..self
         }
-        _ => {
-            return Err(Error::InvalidOpCode);
+    }
+
+    // 送信するパケットの TTL。ルーティングさせない場合は 1 を指定する。
+    pub fn with_ttl(self, ttl: u32) -> Self {
+        Server {
+            ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    // 送信するパケットの ToS。DSCP を指定する場合は 2 ビット左にシフトした値を指定する。
+    pub fn with_tos(self, tos: u8) -> Self {
+        Server {
+            tos: Some(tos),
+            ..self
+        }
+    }
+
+    // セッション間で送受信のバッファを使い回す。capacity はサイズごとに保持する数。
+    pub fn with_buffer_pool(self, capacity: usize) -> Self {
+        Server {
+            buffer_pool: Some(Arc::new(BufferPool::new(capacity))),
+            ..self
+        }
+    }
+
+    // service_addr に加えて待ち受けるアドレス。
+    pub fn with_service_addrs(self, addrs: &[SocketAddr]) -> Self {
+        Server {
+            extra_addrs: addrs.to_vec(),
+            ..self
+        }
+    }
+
+    // 受信した要求を処理するまで保持する数。
+    pub fn with_accept_queue(self, capacity: usize) -> Self {
+        Server {
+            accept_queue: capacity.max(1),
+            ..self
+        }
+    }
+
+    // RRQ のファイル名に対して読み込み元を返した場合はファイルを開かずに読み込む。
+    // tsize には読み込み元の末尾までの長さを通知する。
+    pub fn with_read_source<R>(self, f: impl Fn(&str) -> Option<R> + Send + Sync + 'static) -> Self
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + 'static,
+    {
+        let source: ReadSource =
+            Arc::new(move |filename| f(filename).map(|r| Box::new(r) as Box<dyn FileReader>));
+        Server {
+            read_source: Some(source),
+            ..self
+        }
+    }
+
+    // WRQ のファイル名に対して書き込み先を返した場合はファイルを作成せずに書き込む。
+    pub fn with_write_sink<W>(self, f: impl Fn(&str) -> Option<W> + Send + Sync + 'static) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let sink: WriteSink =
+            Arc::new(move |filename| f(filename).map(|w| Box::new(w) as Box<dyn FileWriter>));
+        Server {
+            write_sink: Some(sink),
+            ..self
+        }
+    }
+
+    // 完了した転送ごとに SessionSummary を 1 行ずつ書き込む。log の設定とは関係なく出力される。
+    // 書き込みは別のタスクで行い、書き込みが詰まって溜まった行が上限を超えた場合は捨てる。
+    pub fn with_access_log(self, w: impl AsyncWrite + Unpin + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel(ACCESS_LOG_QUEUE);
+        Server {
+            access_log: Some(tx),
+            access_log_writer: Mutex::new(Some((rx, Box::new(w)))),
+            ..self
+        }
+    }
+
+    // WRQ ごとに addr へ TCP で接続し、受信したデータをファイルを作成せずに転送する。
+    // with_write_sink が書き込み先を返した場合はそちらを優先する。
+    pub fn with_tcp_forward(self, addr: SocketAddr) -> Self {
+        Server {
+            tcp_forward: Some(addr),
+            ..self
+        }
+    }
+
+    // ファイルシステムの代わりに store のファイルを提供する。WRQ は拒否する。
+    #[cfg(feature = "embedded")]
+    pub fn with_file_store(self, store: impl FileStore + 'static) -> Self {
+        Server {
+            store: Some(Arc::new(store)),
+            ..self
         }
     }
 
-    Ok(())
+    pub fn from_env() -> Result<ServerBuilder, Error> {
+        let mut builder = ServerBuilder::default();
+
+        if let Some(bind) = env_var::<IpAddr>("TFTP_BIND")? {
+            builder = builder.bind(bind);
+        }
+
+        if let Some(port) = env_var::<u16>("TFTP_PORT")? {
+            builder = builder.port(port);
+        }
+
+        if let Some(root) = env_var::<PathBuf>("TFTP_ROOT")? {
+            if !root.is_dir() {
+                return Err(Error::InvalidEnv("TFTP_ROOT".to_string()));
+            }
+            builder = builder.root(&root);
+        }
+
+        if let Some(blksize) = env_var::<u16>("TFTP_BLKSIZE")? {
+            if !(8..=65464).contains(&blksize) {
+                return Err(Error::InvalidEnv("TFTP_BLKSIZE".to_string()));
+            }
+            builder = builder.blksize(blksize);
+        }
+
+        if env_var::<bool>("TFTP_TIMEOUT")?.unwrap_or(false) {
+            builder = builder.timeout();
+        }
+
+        if env_var::<bool>("TFTP_TSIZE")?.unwrap_or(false) {
+            builder = builder.tsize();
+        }
+
+        if let Some(windowsize) = env_var::<u16>("TFTP_WINDOWSIZE")? {
+            if windowsize < 1 {
+                return Err(Error::InvalidEnv("TFTP_WINDOWSIZE".to_string()));
+            }
+            builder = builder.windowsize(windowsize);
+        }
+
+        Ok(builder)
+    }
+
+    pub fn aborter(&self) -> SessionAborter {
+        self.aborter.clone()
+    }
+
+    // 接続先の転送を中断し、ERROR を送信する。該当するセッションがなければ false を返す。
+    pub fn abort_session(&self, remote_addr: &SocketAddr) -> bool {
+        self.aborter.abort(remote_addr)
+    }
+
+    pub fn session_registry(&self) -> SessionRegistry {
+        self.registry.clone()
+    }
+
+    pub fn active_sessions(&self) -> Vec<SessionInfo> {
+        self.registry.active()
+    }
+
+    pub fn service_addr(&self) -> &SocketAddr {
+        &self.service_addr
+    }
+
+    fn limitations(&self, filename: &str) -> (&Options, Option<u64>) {
+        self.policies
+            .iter()
+            .find(|(pattern, ..)| glob::matches(pattern, filename))
+            .map(|(_, limitations, max_rate)| (limitations, *max_rate))
+            .unwrap_or((&self.options, None))
+    }
+
+    // 作成するファイルの名前として受け付けられるか。
+    fn is_valid_filename(&self, filename: &str) -> bool {
+        let is_separator = |c: char| c == '/' || c == '\\';
+
+        if let Some(max_depth) = self.filename_max_depth {
+            // 要求の解析で NUL は区切りとして扱われるが、念のため制御文字と同様に拒否する。
+            if filename.chars().any(|c| c.is_control()) {
+                return false;
+            }
+
+            let depth = filename
+                .split(is_separator)
+                .filter(|p| !p.is_empty())
+                .count();
+            if depth > max_depth + 1 {
+                return false;
+            }
+        }
+
+        match self.filename_chars.as_ref() {
+            Some(f) => filename.chars().all(|c| is_separator(c) || f(c)),
+            _ => true,
+        }
+    }
+
+    // 大文字と小文字を区別しない場合は、どちらも小文字にして比較する。
+    fn is_denied(&self, filename: &str) -> bool {
+        let filename = if self.case_insensitive {
+            filename.to_lowercase()
+        } else {
+            filename.to_string()
+        };
+        let basename = filename.rsplit(['/', '\\']).next().unwrap_or(&filename);
+        self.denied.iter().any(|pattern| {
+            let pattern = if self.case_insensitive {
+                pattern.to_lowercase()
+            } else {
+                pattern.to_string()
+            };
+            glob::matches(&pattern, &filename) || glob::matches(&pattern, basename)
+        })
+    }
+
+    pub async fn serve_forever(self) -> Result<(), Error> {
+        self.serve_until(std::future::pending()).await
+    }
+
+    // shutdown が完了すると新しい転送を開始せず、実行中の転送の完了を待って終了する。
+    // 待っている間も受付用のソケットは読み続け、新しい要求には ShuttingDown の ERROR を返す。
+    pub async fn serve_until(self, shutdown: impl Future<Output = ()>) -> Result<(), Error> {
+        let mut service_socks = vec![];
+        for service_addr in std::iter::once(&self.service_addr).chain(self.extra_addrs.iter()) {
+            let service_sock = bind_socket(*service_addr, self.socket_config.as_ref())?;
+            apply_ttl(&service_sock, self.ttl)?;
+            apply_tos(&service_sock, self.tos)?;
+            service_socks.push(Arc::new(service_sock));
+        }
+
+        self.warn_outside_root();
+        self.spawn_access_log();
+        trace!("serving: {:?}", &self);
+
+        let server = Arc::new(self);
+
+        // 同時に多数の要求を受信してもソケットの受信バッファが溢れないよう、
+        // 受信だけを行うタスクから要求を受け取って処理する。
+        let (tx, mut rx) = mpsc::channel(server.accept_queue);
+        let mut readers = JoinSet::new();
+        for service_sock in service_socks {
+            let tx = tx.clone();
+            readers.spawn(async move {
+                loop {
+                    let mut buf = vec![0; 1024];
+                    let (size, request_addr) = service_sock.recv_from(buf.as_mut_slice()).await?;
+                    buf.resize(size, 0);
+
+                    if tx
+                        .send((buf, request_addr, service_sock.clone()))
+                        .await
+                        .is_err()
+                    {
+                        return Ok::<(), Error>(());
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        // 各転送のタスクが送信側を保持し、すべて終了すると受信側が None を返す。
+        let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+        let mut done_tx = Some(done_tx);
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                _ = &mut shutdown, if done_tx.is_some() => {
+                    info!("shutting down: waiting for sessions");
+                    done_tx = None;
+                }
+                None = done_rx.recv(), if done_tx.is_none() => {
+                    info!("shut down");
+                    return Ok(());
+                }
+                Some((buf, request_addr, service_sock)) = rx.recv() => {
+                    match done_tx.clone() {
+                        Some(done) => {
+                            let server = server.clone();
+                            tokio::spawn(async move {
+                                serve_request(server, service_sock, buf, request_addr).await;
+                                drop(done);
+                            });
+                        }
+                        _ => {
+                            if server.drop_garbage && !has_opcode(&buf) {
+                                continue;
+                            }
+                            trace!("[{}] rejected: shutting down", request_addr);
+                            reply_error(&server, &service_sock, request_addr, &Error::ShuttingDown)
+                                .await;
+                        }
+                    }
+                }
+                Some(ret) = readers.join_next() => {
+                    return match ret {
+                        Ok(ret) => ret,
+                        Err(e) => Err(Error::from(io::Error::new(io::ErrorKind::Other, e))),
+                    };
+                }
+                else => return Ok(()),
+            }
+        }
+    }
+
+    // 要求を 1 つだけ受信し、転送が完了するまで現在のタスクで処理する。
+    pub async fn serve_one(self) -> Result<SessionSummary, Error> {
+        let service_sock = bind_socket(self.service_addr, self.socket_config.as_ref())?;
+        apply_ttl(&service_sock, self.ttl)?;
+        apply_tos(&service_sock, self.tos)?;
+
+        self.warn_outside_root();
+        self.spawn_access_log();
+        trace!("serving one: {:?}", &self);
+
+        let (buf, request_addr) = loop {
+            let mut buf = vec![0; 1024];
+            let (size, request_addr) = service_sock.recv_from(buf.as_mut_slice()).await?;
+            buf.resize(size, 0);
+
+            if self.drop_garbage && !has_opcode(&buf) {
+                trace!("[{}] dropped: invalid opcode", request_addr);
+                continue;
+            }
+            break (buf, request_addr);
+        };
+
+        let remote_addr = normalize_addr(request_addr);
+
+        let local_ip = reply_ip(self.service_addr.ip(), &remote_addr);
+        let sock = bind_socket(SocketAddr::new(local_ip, 0), self.socket_config.as_ref())?;
+        sock.connect(remote_addr).await?;
+
+        let mut session = self.new_session(sock, remote_addr);
+        match handle_request(&mut session, Bytes::from(buf), &self).await {
+            Ok(summary) => Ok(summary),
+            Err(e) => {
+                if let Err(e) = session.send_error(&e).await {
+                    error!("failed to send error: [{}] {:?}", remote_addr, e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // アクセスログを書き込むタスクを開始する。Server が破棄されると残りを書き込んで終了する。
+    fn spawn_access_log(&self) {
+        if let Some((mut rx, mut w)) = self.access_log_writer.lock().unwrap().take() {
+            tokio::spawn(async move {
+                while let Some(line) = rx.recv().await {
+                    if let Err(e) = async {
+                        w.write_all(line.as_bytes()).await?;
+                        w.flush().await
+                    }
+                    .await
+                    {
+                        error!("failed to write access log: {:?}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    fn warn_outside_root(&self) {
+        if self.allow_outside_root {
+            warn!(
+                "DANGER: root confinement is disabled, any path is accessible: {:?}",
+                self.root
+            );
+        }
+    }
+
+    fn new_session(&self, sock: UdpSocket, remote_addr: SocketAddr) -> session::TftpSession {
+        if let Err(e) = apply_ttl(&sock, self.ttl) {
+            error!("failed to set ttl: [{}] {:?}", remote_addr, e);
+        }
+
+        if let Err(e) = apply_tos(&sock, self.tos) {
+            error!("failed to set tos: [{}] {:?}", remote_addr, e);
+        }
+
+        let mut session = session::TftpSession::new(sock, remote_addr);
+        session.set_role(session::Role::Server);
+        session.set_quiet_errors(self.quiet_errors);
+        session.set_batch_io(self.batch_io);
+        session.set_read_ahead(self.read_ahead);
+        session.set_buffer_pool(self.buffer_pool.clone());
+        session
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("service_addr", &self.service_addr)
+            .field("extra_addrs", &self.extra_addrs)
+            .field("root", &self.root)
+            .field("options", &self.options)
+            .field("temp_dir", &self.temp_dir)
+            .field("policies", &self.policies)
+            .field("denied", &self.denied)
+            .field("allowed_modes", &self.allowed_modes)
+            .finish_non_exhaustive()
+    }
+}
+
+pub struct ServerBuilder {
+    service_addr: SocketAddr,
+    root: PathBuf,
+    options: OptionBuilder,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            service_addr: SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), 69),
+            root: PathBuf::from("."),
+            options: OptionBuilder::default(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    pub fn bind(self, ip: IpAddr) -> Self {
+        ServerBuilder {
+            service_addr: SocketAddr::new(ip, self.service_addr.port()),
+            ..self
+        }
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        ServerBuilder {
+            service_addr: SocketAddr::new(self.service_addr.ip(), port),
+            ..self
+        }
+    }
+
+    pub fn root(self, root: &Path) -> Self {
+        ServerBuilder {
+            root: root.to_path_buf(),
+            ..self
+        }
+    }
+
+    pub fn blksize(self, blksize: u16) -> Self {
+        ServerBuilder {
+            options: self.options.blksize(blksize),
+            ..self
+        }
+    }
+
+    pub fn timeout(self) -> Self {
+        ServerBuilder {
+            options: self.options.timeout(0),
+            ..self
+        }
+    }
+
+    pub fn tsize(self) -> Self {
+        ServerBuilder {
+            options: self.options.tsize(),
+            ..self
+        }
+    }
+
+    pub fn windowsize(self, windowsize: u16) -> Self {
+        ServerBuilder {
+            options: self.options.windowsize(windowsize),
+            ..self
+        }
+    }
+
+    pub fn build(self) -> Result<Server, Error> {
+        Server::new(self.service_addr, &self.root, self.options.build())
+    }
+}
+
+fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match to_ipv4_mapped(v6.ip()) {
+            Some(ip) => SocketAddr::new(IpAddr::from(ip), v6.port()),
+            _ => addr,
+        },
+        _ => addr,
+    }
+}
+
+fn reply_ip(service_ip: IpAddr, remote_addr: &SocketAddr) -> IpAddr {
+    // デュアルスタックで受信した IPv4 クライアントには IPv4 のソケットで応答する。
+    match (service_ip, remote_addr) {
+        (IpAddr::V6(ip), SocketAddr::V4(_)) => match to_ipv4_mapped(&ip) {
+            Some(ip) => IpAddr::from(ip),
+            _ => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        },
+        _ => service_ip,
+    }
+}
+
+fn to_ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+// ファイルディスクリプタの上限 (EMFILE, ENFILE) に達したかどうか。
+#[cfg(unix)]
+fn is_fd_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+#[cfg(windows)]
+fn is_fd_exhausted(e: &io::Error) -> bool {
+    const WSAEMFILE: i32 = 10024;
+    e.raw_os_error() == Some(WSAEMFILE)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_fd_exhausted(_: &io::Error) -> bool {
+    false
+}
+
+fn env_var<T: FromStr>(key: &str) -> Result<Option<T>, Error> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| Error::InvalidEnv(key.to_string())),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(_) => Err(Error::InvalidEnv(key.to_string())),
+    }
+}
+
+async fn resolve_read_path(server: &Server, filepath: &Path) -> Result<PathBuf, Error> {
+    let err = match filepath.canonicalize() {
+        Ok(path) => return Ok(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => e,
+        Err(e) => return Err(Error::from(e)),
+    };
+
+    if server.case_insensitive {
+        if let Some(found) = file::find_ignore_case(filepath).await? {
+            return Ok(found.canonicalize()?);
+        }
+    }
+
+    if let Some(fallback) = server.fallback_file.as_ref() {
+        return Ok(server.root.join(fallback).canonicalize()?);
+    }
+
+    Err(Error::from(err))
+}
+
+async fn serve_request(
+    server: Arc<Server>,
+    service_sock: Arc<UdpSocket>,
+    buf: Vec<u8>,
+    request_addr: SocketAddr,
+) {
+    let remote_addr = normalize_addr(request_addr);
+
+    if server.drop_garbage && !has_opcode(&buf) {
+        trace!("[{}] dropped: invalid opcode", remote_addr);
+        return;
+    }
+
+    if let Some(limiter) = server.client_limiter.as_ref() {
+        if !limiter.allow(remote_addr.ip()) {
+            trace!("[{}] dropped: rate limited", remote_addr);
+            return;
+        }
+    }
+
+    // 要求を受信したアドレスから応答する。
+    let service_ip = service_sock
+        .local_addr()
+        .map(|a| a.ip())
+        .unwrap_or_else(|_| server.service_addr.ip());
+    let local_ip = reply_ip(service_ip, &remote_addr);
+
+    #[cfg(all(feature = "fd-reserve", target_os = "linux"))]
+    let _guard = match server.fd_reserve.as_ref().map(|r| r.acquire()) {
+        Some(None) => {
+            warn!("[{}] rejected: file descriptors are reserved", remote_addr);
+            reply_error(&server, &service_sock, request_addr, &Error::ServerBusy).await;
+            return;
+        }
+        guard => guard,
+    };
+
+    match bind_socket(SocketAddr::new(local_ip, 0), server.socket_config.as_ref()) {
+        Ok(sock) => {
+            if let Err(e) = sock.connect(remote_addr).await {
+                eprint!("[{}] {:?}", remote_addr, e);
+                return;
+            }
+
+            let mut session = server.new_session(sock, remote_addr);
+            let abort = server.aborter.register(remote_addr);
+            let ret = tokio::select! {
+                ret = handle_request(&mut session, Bytes::from(buf), &server) => ret,
+                _ = abort.notify.notified() => Err(Error::Aborted),
+            };
+            drop(abort);
+
+            if let Err(e) = ret {
+                if let Err(e) = session.send_error(&e).await {
+                    error!("failed to send error: [{}] {:?}", remote_addr, e);
+                }
+            }
+        }
+        Err(e) => {
+            error!("failed to bind: [{}] {:?}", remote_addr, e);
+
+            // セッションのソケットを作成できない場合もクライアントに応答する。
+            let err = if is_fd_exhausted(&e) {
+                Error::ServerBusy
+            } else {
+                Error::from(e)
+            };
+            reply_error(&server, &service_sock, request_addr, &err).await;
+        }
+    }
+}
+
+// 転送用のソケットを作成せず、受付用のソケットから ERROR を返す。
+async fn reply_error(
+    server: &Server,
+    service_sock: &UdpSocket,
+    request_addr: SocketAddr,
+    err: &Error,
+) {
+    let buf = if server.quiet_errors {
+        packet::error_quiet(err)
+    } else {
+        packet::error(err)
+    };
+    if let Err(e) = service_sock.send_to(&buf, request_addr).await {
+        error!("failed to send error: [{}] {:?}", request_addr, e);
+    }
+}
+
+// ファイルを読み書きせずに、行うはずだった転送を記録する。
+fn dry_run(remote_addr: &SocketAddr, action: &str, target: &Path, options: &Options) -> Error {
+    info!(
+        "[{}] dry-run: would {} {:?} with {:?}",
+        remote_addr, action, target, options
+    );
+    Error::DryRun
+}
+
+fn has_opcode(buf: &[u8]) -> bool {
+    matches!(packet::parse_opcode(&mut &buf[..]), Ok(Some(_)))
+}
+
+fn set_clamps(session: &mut session::TftpSession, requested: &Options, granted: &Options) {
+    let clamps = requested.clamps(granted);
+    for clamp in clamps.iter() {
+        info!(
+            "[{}] clamped {}: {} -> {}",
+            session.remote_addr(),
+            clamp.option(),
+            clamp.requested(),
+            clamp.granted()
+        );
+    }
+    session.set_clamps(clamps);
+}
+
+async fn handle_request(
+    session: &mut session::TftpSession,
+    mut buf: Bytes,
+    server: &Server,
+) -> Result<SessionSummary, Error> {
+    let root = server.root.as_path();
+
+    if server.root_check
+        && !fs::metadata(root)
+            .await
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+    {
+        return Err(Error::ServiceUnavailable);
+    }
+
+    if server.anti_amplification {
+        session.set_amplification_limit(Some(buf.len() * AMPLIFICATION_FACTOR));
+    }
+
+    let req = packet::parse_request(&mut buf)?;
+    session.set_progress_handler(server.progress_handler.clone());
+    session.set_min_transfer_rate(server.min_transfer_rate);
+    session.set_max_duration(server.max_duration);
+    session.set_min_timeout(server.min_timeout);
+    session.set_ack_frequency(server.ack_frequency);
+    session.set_first_block_timeout(server.first_block_timeout);
+    session.set_oack_retransmit(server.oack_retransmit);
+    session.set_dally(server.dally);
+    session.set_reject_stray_ack(server.reject_stray_ack);
+
+    if let Some(modes) = server.allowed_modes.as_ref() {
+        if !modes.contains(&req.mode().to_lowercase()) {
+            return Err(Error::InvalidMode);
+        }
+    }
+
+    session.set_mode(req.mode());
+
+    let filename = match server.filename_mapper.as_ref() {
+        Some(mapper) => mapper(req.filename()).ok_or(Error::FileNotFound)?,
+        _ => req.filename().to_string(),
+    };
+
+    if server.is_denied(&filename) {
+        return Err(Error::AccessViolation);
+    }
+
+    let active = server
+        .registry
+        .register(*session.remote_addr(), &filename, req.op_code());
+    session.set_progress_counter(Some(active.bytes.clone()));
+
+    let (limitations, max_rate) = server.limitations(&filename);
+    session.set_max_rate(max_rate);
+
+    trace!("requested: {:?}", &req);
+
+    let mut filepath = PathBuf::from(root);
+    filepath.push(&filename);
+
+    match req.op_code() {
+        OpCode::Rrq => {
+            let mut options = req.options().clone();
+            options.cut_off(limitations);
+            if let Some(handler) = server.negotiate_handler.as_ref() {
+                handler(session.remote_addr(), req.options(), &mut options);
+            }
+            set_clamps(session, req.options(), &options);
+            // x-mtime は比較にのみ使用し、転送する場合は OACK に含めない。
+            let mtime = options.mtime();
+            options.set_mtime(None);
+
+            #[cfg(feature = "embedded")]
+            if let Some(store) = server.store.as_ref() {
+                if server.dry_run {
+                    let target = Path::new(&filename);
+                    return Err(dry_run(session.remote_addr(), "send", target, &options));
+                }
+                // 取得に時間のかかる store もあるため、他の転送を止めないよう別のスレッドで読み込む。
+                let (store, name) = (store.clone(), filename.clone());
+                let content = tokio::task::spawn_blocking(move || store.read(&name))
+                    .await
+                    .map_err(io::Error::from)??;
+                options.set_tsize_len(content.len() as u64);
+                session.set_reader(Cursor::new(content));
+            }
+
+            if let Some(mut source) = server.read_source.as_ref().and_then(|f| f(&filename)) {
+                if server.dry_run {
+                    let target = Path::new(&filename);
+                    return Err(dry_run(session.remote_addr(), "send", target, &options));
+                }
+                let len = source.seek(io::SeekFrom::End(0)).await?;
+                source.seek(io::SeekFrom::Start(0)).await?;
+                options.set_tsize_len(len);
+                session.set_reader(source);
+            }
+
+            if !session.has_file() {
+                let local_file = resolve_read_path(server, &filepath).await?;
+                if !server.allow_outside_root && !local_file.starts_with(root) {
+                    return Err(Error::InvalidFileName);
+                }
+
+                // 大文字と小文字の違いや代替のファイルに解決された場合も、実際に送るファイルで確認する。
+                let relative = local_file.strip_prefix(root).unwrap_or(&local_file);
+                if server.is_denied(&relative.to_string_lossy()) {
+                    return Err(Error::AccessViolation);
+                }
+
+                if server.dry_run {
+                    let addr = session.remote_addr();
+                    return Err(dry_run(addr, "send", &local_file, &options));
+                }
+
+                if let Some(mtime) = mtime {
+                    // 変更されていなければ x-mtime のみを通知し、転送せずに終了する。
+                    if file::mtime(&local_file).await? <= mtime {
+                        let mut oack = Options::default();
+                        oack.set_mtime(Some(mtime));
+                        session.set_options(oack);
+                        session.send_oack().await?;
+                        return Ok(SessionSummary::new(session, req.op_code(), &filename));
+                    }
+                }
+
+                #[cfg(feature = "cache")]
+                if let Some(cache) = server.cache.as_ref() {
+                    let content = cache.get(&local_file).await?;
+                    session.set_reader(Cursor::new(content));
+                }
+
+                if !session.has_file() {
+                    let local = file::open_read(&local_file).await?;
+                    session.set_reader(local);
+                    session.set_source(&local_file).await?;
+                }
+
+                options.set_tsize(&local_file, req.mode()).await?;
+            }
+
+            if options.offset() > 0 {
+                // 再開位置はバイト単位のため、変換でサイズが変わる netascii では受け付けない。
+                let offset = options.offset();
+                if req.mode().eq_ignore_ascii_case("octet")
+                    && session.set_read_offset(offset).await?
+                {
+                    options.set_tsize_len(options.tsize().saturating_sub(offset));
+                } else {
+                    options.set_offset(0);
+                }
+            }
+
+            session.set_options(options);
+
+            if session.options().head() {
+                // tsize のみを通知し、クライアントの中断を待たずに終了する。
+                session.send_oack().await?;
+                return Ok(SessionSummary::new(session, req.op_code(), &filename));
+            }
+
+            if server.anti_amplification && !session.options().has_option() {
+                // OACK を返せない場合は、到達性を確認する前に DATA を送信することになる。
+                return Err(Error::NegotiationRequired);
+            }
+
+            let (_, buf) = if session.options().has_option() {
+                session.send_oack_recv_data().await?
+            } else {
+                session.send_data_recv_ack(0).await?
+            };
+
+            handle_packet(req.op_code(), session, buf).await?;
+        }
+        OpCode::Wrq => {
+            #[cfg(feature = "embedded")]
+            if server.store.is_some() {
+                return Err(Error::AccessViolation);
+            }
+
+            if !server.allow_outside_root
+                && ((!filepath.starts_with(root)) || filepath.iter().any(|i| i == ".."))
+            {
+                return Err(Error::InvalidFileName);
+            }
+
+            if !server.is_valid_filename(&filename) {
+                warn!(
+                    "[{}] rejected filename: {:?}",
+                    session.remote_addr(),
+                    filename
+                );
+                return Err(Error::InvalidFileName);
+            }
+
+            let mut options = req.options().clone();
+            options.cut_off(limitations);
+            if let Some(handler) = server.negotiate_handler.as_ref() {
+                handler(session.remote_addr(), req.options(), &mut options);
+            }
+            set_clamps(session, req.options(), &options);
+            // 書き込みの途中からの再開には対応しない。
+            options.set_offset(0);
+            options.set_mtime(None);
+            session.set_options(options);
+
+            if server.dry_run {
+                return Err(dry_run(
+                    session.remote_addr(),
+                    "receive",
+                    &filepath,
+                    session.options(),
+                ));
+            }
+
+            let mut sink = server.write_sink.as_ref().and_then(|f| f(&filename));
+            if let (None, Some(addr)) = (sink.as_ref(), server.tcp_forward) {
+                let stream = TcpStream::connect(addr).await?;
+                sink = Some(Box::new(stream));
+            }
+
+            let temppath = server
+                .temp_dir
+                .as_ref()
+                .filter(|_| sink.is_none())
+                .map(|dir| file::temp_path(&dir.join(filepath.file_name().unwrap_or_default())));
+
+            // ディレクトリには書き込めないため、汎用のエラーではなく AccessViolation を返す。
+            if sink.is_none()
+                && fs::metadata(&filepath)
+                    .await
+                    .map(|m| m.is_dir())
+                    .unwrap_or(false)
+            {
+                return Err(Error::AccessViolation);
+            }
+
+            // 移動時に上書きしないよう、temp_dir を使わない場合と同様に既存のファイルを拒否する。
+            if temppath.is_some() && filepath.exists() {
+                return Err(Error::from(std::io::Error::from(
+                    std::io::ErrorKind::AlreadyExists,
+                )));
+            }
+
+            match sink {
+                Some(sink) => session.set_writer(sink),
+                None => {
+                    let path = temppath.as_ref().unwrap_or(&filepath);
+                    let local = file::open_create(path).await?;
+                    session.set_writer(Box::new(local));
+                    session.set_remove_guard(file::RemoveGuard::new(path));
+                }
+            }
+            if let Some(temppath) = temppath.as_ref() {
+                session.set_persist_path(temppath, &filepath);
+            }
+
+            // TODO: check ErrorCode::DiskFull
+
+            let ret = async {
+                let (_, buf) = if session.options().has_option() {
+                    session.send_oack_recv_data().await?
+                } else {
+                    session.send_ack_recv_data().await?
+                };
+
+                handle_packet(req.op_code(), session, buf).await
+            }
+            .await;
+            if ret.is_err() {
+                session.discard_file();
+            }
+
+            ret?;
+
+            if let Some(handler) = server.upload_complete_handler.as_ref() {
+                handler(&UploadInfo {
+                    path: filepath,
+                    remote_addr: *session.remote_addr(),
+                    bytes: session.transferred_bytes(),
+                    mode: req.mode().to_string(),
+                });
+            }
+        }
+        _ => {
+            return Err(Error::InvalidOpCode);
+        }
+    }
+
+    info!(
+        "[{}] completed: {:?} {} ({} bytes, {:?})",
+        session.remote_addr(),
+        req.op_code(),
+        filename,
+        session.transferred_bytes(),
+        session.elapsed()
+    );
+
+    let summary = SessionSummary::new(session, req.op_code(), &filename);
+    if let Some(access_log) = server.access_log.as_ref() {
+        if access_log.try_send(format!("{}\n", summary)).is_err() {
+            warn!("dropped access log: [{}]", session.remote_addr());
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Client, OptionNegotiation};
+    use crate::testutil;
+    use crate::ErrorCode;
+
+    #[tokio::test]
+    async fn put_with_temp_dir() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let temp = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_temp_dir(temp.path());
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let content = testutil::content(2000);
+        std::fs::write(local.path().join("a"), &content)?;
+
+        let client = Client::new(addr, "octet", Options::default());
+        client.put(&local.path().join("a"), "a").await?;
+
+        assert_eq!(content, std::fs::read(root.path().join("a"))?);
+        assert_eq!(0, std::fs::read_dir(temp.path())?.count());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_existing_file() -> Result<(), Error> {
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), testutil::content(2000))?;
+
+        for use_temp_dir in [false, true] {
+            let root = tempfile::tempdir()?;
+            let temp = tempfile::tempdir()?;
+            std::fs::write(root.path().join("a"), b"old")?;
+            let mut server = Server::new(testutil::addr(), root.path(), Options::default())?;
+            if use_temp_dir {
+                server = server.with_temp_dir(temp.path());
+            }
+            let addr = testutil::serve(server).await;
+
+            // temp_dir の有無に関わらず、既存のファイルは上書きしない。
+            let client = Client::new(addr, "octet", Options::default());
+            let ret = client.put(&local.path().join("a"), "a").await;
+
+            assert!(matches!(
+                ret,
+                Err(Error::PeerError(ErrorCode::FileAlreadyExists, _))
+            ));
+            assert_eq!(b"old".to_vec(), std::fs::read(root.path().join("a"))?);
+            assert_eq!(0, std::fs::read_dir(temp.path())?.count());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_empty_file() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("empty"), b"")?;
+
+        for mode in ["octet", "netascii"] {
+            let options = OptionBuilder::default().tsize().build();
+            let client = Client::new(addr, mode, options);
+            let stats = client.put(&local.path().join("empty"), mode).await?;
+
+            assert_eq!(0, stats.bytes());
+            assert_eq!(0, std::fs::metadata(root.path().join(mode))?.len());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_size_mismatch() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default().tsize().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let mut options = OptionBuilder::default().tsize().build();
+        options.set_tsize_len(2000);
+        let req = packet::Request::wrq("a", "octet", &options);
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        // tsize より短いブロックで転送を終える。
+        sock.send(&packet::data(1, &testutil::content(100)[..]))
+            .await?;
+
+        let mut buf = vec![0; 1024];
+        let size = sock.recv(&mut buf).await?;
+        let mut buf = Bytes::from(buf[..size].to_vec());
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        assert!(!root.path().join("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_to_directory() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir(root.path().join("a"))?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::wrq("a", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::AccessViolation as u16, error.error_code());
+        assert!(root.path().join("a").is_dir());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_aborted_removes_partial_file() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let aborter = server.aborter();
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::wrq("a", "octet", &Options::default());
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        sock.send(&packet::data(1, &testutil::content(512)[..]))
+            .await?;
+        let mut buf = vec![0; 1024];
+        sock.recv(&mut buf).await?;
+        assert!(root.path().join("a").exists());
+
+        // 転送途中で中断すると、書きかけのファイルは削除される。
+        assert!(aborter.abort(&sock.local_addr()?));
+
+        let size = sock.recv(&mut buf).await?;
+        let mut buf = Bytes::from(buf[..size].to_vec());
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        assert!(!root.path().join("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_on_upload_complete() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .on_upload_complete(move |info| tx.send(info.clone()).unwrap());
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), testutil::content(2000))?;
+
+        let client = Client::new(addr, "octet", Options::default());
+        client.put(&local.path().join("a"), "b").await?;
+
+        let info = rx.recv().await.unwrap();
+        assert_eq!(root.path().canonicalize()?.join("b"), info.path());
+        assert_eq!(2000, info.bytes());
+        assert_eq!("octet", info.mode());
+        assert!(info.path().exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_read_source() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        let options = OptionBuilder::default().tsize().build();
+        let source = content.clone();
+        let server = Server::new(testutil::addr(), root.path(), options.clone())?.with_read_source(
+            move |filename| match filename {
+                "a" => Some(std::io::Cursor::new(source.clone())),
+                _ => None,
+            },
+        );
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", options);
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        match stats.negotiation() {
+            OptionNegotiation::Accepted(options) => assert_eq!(5000, options.tsize()),
+            n => panic!("{:?}", n),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_with_write_sink() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let (sink, mut received) = tokio::io::duplex(1024);
+        let sink = std::sync::Mutex::new(Some(sink));
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_write_sink(move |filename| match filename {
+                "a" => sink.lock().unwrap().take(),
+                _ => None,
+            });
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        let localpath = local.path().join("a");
+        std::fs::write(&localpath, &content)?;
+
+        let client = Client::new(addr, "octet", Options::default());
+        let mut buf = vec![];
+        let (ret, _) = tokio::join!(
+            client.put(&localpath, "a"),
+            tokio::io::AsyncReadExt::read_to_end(&mut received, &mut buf)
+        );
+        ret?;
+
+        assert_eq!(content, buf);
+        assert!(!root.path().join("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn put_with_tcp_forward() -> Result<(), Error> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let backend = listener.local_addr()?;
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buf = vec![];
+            tokio::io::AsyncReadExt::read_to_end(&mut stream, &mut buf).await?;
+            Ok::<_, io::Error>(buf)
+        });
+
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_tcp_forward(backend);
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        let localpath = local.path().join("a");
+        std::fs::write(&localpath, &content)?;
+
+        let client = Client::new(addr, "octet", Options::default());
+        client.put(&localpath, "a").await?;
+
+        assert_eq!(content, handle.await.unwrap()?);
+        assert!(!root.path().join("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_oack_acked_with_data() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default().blksize(1024).windowsize(4).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        // OACK に対しては ACK 0 ではなく DATA 1 で応答する。
+        let content = testutil::content(100);
+        let req = packet::Request::wrq("a", "octet", &limitations);
+        let (sock, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+        sock.send(&packet::data(1, &content[..])).await?;
+
+        let mut buf = vec![0; 1024];
+        let recv = tokio::time::timeout(Duration::from_secs(1), sock.recv(&mut buf)).await;
+        let size = recv.expect("no reply within 1s")?;
+        let mut buf = Bytes::from(buf[..size].to_vec());
+        assert_eq!(Some(OpCode::Ack), packet::parse_opcode(&mut buf)?);
+        assert_eq!(1, packet::parse_blocknum(&mut buf)?);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(content, std::fs::read(root.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_stray_ack_before_data() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default().blksize(1024).windowsize(4).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        // 誤って ACK 0 を返した後に DATA 1 を送信しても受信できる。
+        let content = testutil::content(100);
+        let req = packet::Request::wrq("a", "octet", &limitations);
+        let (sock, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+        sock.send(&packet::ack(0)).await?;
+        sock.send(&packet::data(1, &content[..])).await?;
+
+        let mut buf = vec![0; 1024];
+        let recv = tokio::time::timeout(Duration::from_secs(1), sock.recv(&mut buf)).await;
+        let size = recv.expect("no reply within 1s")?;
+        let mut buf = Bytes::from(buf[..size].to_vec());
+        assert_eq!(Some(OpCode::Ack), packet::parse_opcode(&mut buf)?);
+        assert_eq!(1, packet::parse_blocknum(&mut buf)?);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(content, std::fs::read(root.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_reject_stray_ack() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?
+            .with_reject_stray_ack();
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::wrq("a", "octet", &limitations);
+        let (sock, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+        sock.send(&packet::ack(0)).await?;
+
+        let mut buf = vec![0; 1024];
+        let recv = tokio::time::timeout(Duration::from_secs(1), sock.recv(&mut buf)).await;
+        let size = recv.expect("no reply within 1s")?;
+        let mut buf = Bytes::from(buf[..size].to_vec());
+        assert_eq!(Some(OpCode::Error), packet::parse_opcode(&mut buf)?);
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::IllegalTftpOp as u16, error.error_code());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!root.path().join("a").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_duplicate_block_reacked() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let content = testutil::content(512 + 100);
+        let req = packet::Request::wrq("a", "octet", &Options::default());
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        let recv_ack = || async {
+            let mut buf = vec![0; 1024];
+            let size = sock.recv(&mut buf).await.unwrap();
+            let mut buf = Bytes::from(buf[..size].to_vec());
+            assert_eq!(Some(OpCode::Ack), packet::parse_opcode(&mut buf).unwrap());
+            packet::parse_blocknum(&mut buf).unwrap()
+        };
+
+        let block1 = packet::data(1, &content[..512]);
+        sock.send(&block1).await?;
+        assert_eq!(1, recv_ack().await);
+
+        // ACK 1 が失われたものとしてブロック 1 を再送すると、待たずに再度 ACK が返る。
+        sock.send(&block1).await?;
+        let ack = tokio::time::timeout(Duration::from_millis(500), recv_ack()).await;
+        assert_eq!(Ok(1), ack);
+
+        sock.send(&packet::data(2, &content[512..])).await?;
+        assert_eq!(2, recv_ack().await);
+        assert_eq!(content, std::fs::read(root.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_out_of_order_in_window() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default().windowsize(4).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        let content = testutil::content(512 * 5 + 100);
+        let req = packet::Request::wrq("a", "octet", &limitations);
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        let send_data = |blocknum: u16| {
+            let start = 512 * (blocknum as usize - 1);
+            let end = content.len().min(start + 512);
+            packet::data(blocknum, &content[start..end])
+        };
+        let recv_ack = || async {
+            let mut buf = vec![0; 1024];
+            let size = sock.recv(&mut buf).await.unwrap();
+            let mut buf = Bytes::from(buf[..size].to_vec());
+            packet::parse_opcode(&mut buf).unwrap();
+            packet::parse_blocknum(&mut buf).unwrap()
+        };
+
+        // ブロック 1 より先にブロック 2 が届いた場合は ACK 0 で再送を求める。
+        sock.send(&send_data(2)).await?;
+        assert_eq!(0, recv_ack().await);
+
+        for blocknum in 1..=4 {
+            sock.send(&send_data(blocknum)).await?;
+        }
+        assert_eq!(4, recv_ack().await);
+
+        for blocknum in 5..=6 {
+            sock.send(&send_data(blocknum)).await?;
+        }
+        assert_eq!(6, recv_ack().await);
+
+        assert_eq!(content, std::fs::read(root.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_burst() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_accept_queue(4);
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let mut handles = vec![];
+        for i in 0..32 {
+            let localpath = local.path().join(i.to_string());
+            handles.push(tokio::spawn(async move {
+                let client = Client::new(addr, "octet", Options::default());
+                client.get(&localpath, "a").await
+            }));
+        }
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            handle.await.unwrap()?;
+            assert_eq!(content, std::fs::read(local.path().join(i.to_string()))?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_buffer_pool() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_buffer_pool(8);
+        let addr = testutil::serve(server).await;
+
+        // blksize の異なるセッションを同時に処理する。
+        let local = tempfile::tempdir()?;
+        let mut handles = vec![];
+        for (i, blksize) in [512, 1024, 700, 512].into_iter().enumerate() {
+            let localpath = local.path().join(i.to_string());
+            handles.push(tokio::spawn(async move {
+                let options = OptionBuilder::default()
+                    .blksize(blksize)
+                    .windowsize(4)
+                    .build();
+                let client = Client::new(addr, "octet", options);
+                client.get(&localpath, "a").await
+            }));
+        }
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            handle.await.unwrap()?;
+            assert_eq!(content, std::fs::read(local.path().join(i.to_string()))?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_rate_limited_policy() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(10000);
+        std::fs::write(root.path().join("a.img"), &content)?;
+        std::fs::write(root.path().join("a.cfg"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_rate_limited_policy("*.img", Options::default(), 20000)
+            .with_policy("*.cfg", Options::default());
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        let timed = |filename: &'static str| {
+            let client = &client;
+            let local_file = local.path().join(filename);
+            async move {
+                let started_at = Instant::now();
+                client.get(&local_file, filename).await?;
+                Ok::<_, Error>(started_at.elapsed())
+            }
+        };
+        let (img, cfg) = tokio::join!(timed("a.img"), timed("a.cfg"));
+
+        // 10000 bytes を 20000 bytes/sec に抑えると約 0.5 秒かかる。
+        // 抑えていない転送の所要時間は負荷に左右されるため、上限は確認しない。
+        assert!(img? >= Duration::from_millis(400));
+        cfg?;
+        assert_eq!(content, std::fs::read(local.path().join("a.img"))?);
+        assert_eq!(content, std::fs::read(local.path().join("a.cfg"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_policy() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a.img"), testutil::content(10))?;
+        std::fs::write(root.path().join("a.cfg"), testutil::content(10))?;
+
+        let img = OptionBuilder::default().windowsize(8).build();
+        let cfg = OptionBuilder::default().windowsize(1).build();
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_policy("*.img", img)
+            .with_policy("*.cfg", cfg);
+        let addr = testutil::serve(server).await;
+
+        let options = OptionBuilder::default().windowsize(16).build();
+        let mut windowsizes = vec![];
+        for filename in ["a.img", "a.cfg"] {
+            let req = packet::Request::rrq(filename, "octet", &options);
+            let (_, mut buf) = testutil::request(addr, &req).await;
+            assert!(matches!(
+                packet::parse_opcode(&mut buf)?,
+                Some(OpCode::Oack)
+            ));
+            windowsizes.push(packet::parse_oack(&mut buf)?.windowsize());
+        }
+
+        assert_eq!(vec![8, 1], windowsizes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_ignore_stale_ack() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(512 * 8);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().windowsize(2).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::rrq("a", "octet", &limitations);
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        let recv_data = || async {
+            let mut buf = vec![0; 1024];
+            let size = sock.recv(&mut buf).await.unwrap();
+            let mut data = Bytes::from(buf[..size].to_vec());
+            packet::parse_opcode(&mut data).unwrap();
+            let blocknum = packet::parse_blocknum(&mut data).unwrap();
+            (blocknum, data)
+        };
+
+        for blocknum in [0, 2] {
+            sock.send(&packet::ack(blocknum)).await?;
+            recv_data().await;
+            recv_data().await;
+        }
+
+        // 転送開始後に届いた ACK 0 は無視し、ウィンドウを送り直さない。
+        sock.send(&packet::ack(0)).await?;
+        let mut buf = vec![0; 1024];
+        let ret = tokio::time::timeout(Duration::from_millis(200), sock.recv(&mut buf)).await;
+        assert!(ret.is_err());
+
+        sock.send(&packet::ack(4)).await?;
+        let (blocknum, data) = recv_data().await;
+        assert_eq!(5, blocknum);
+        assert_eq!(&content[512 * 4..512 * 5], data.as_ref());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_serve_one() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = *server.service_addr();
+        let handle = tokio::spawn(server.serve_one());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "a").await?;
+
+        let summary = handle.await.unwrap()?;
+        assert_eq!(3000, summary.bytes());
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_completed_log() -> Result<(), Error> {
+        testutil::capture_logs();
+
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("completed-log"), testutil::content(1000))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "completed-log").await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let logs: Vec<String> = testutil::logs()
+            .into_iter()
+            .filter(|l| l.contains("completed: Rrq completed-log"))
+            .collect();
+        assert_eq!(1, logs.len());
+        assert!(logs[0].starts_with("INFO"));
+        assert!(logs[0].contains("(1000 bytes"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_filename_with_newline() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_filename_validation(1);
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::wrq("a\nb", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Error), packet::parse_opcode(&mut buf)?);
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::IllegalTftpOp as u16, error.error_code());
+        assert!(!root.path().join("a\nb").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_filename_too_deep() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("a/b/c"))?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_filename_validation(1)
+            .with_filename_chars(|c| c.is_ascii_alphanumeric() || c == '.');
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        std::fs::write(local.path().join("a"), testutil::content(100))?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.put(&local.path().join("a"), "a/x.bin").await?;
+        assert!(root.path().join("a/x.bin").exists());
+
+        for filename in ["a/b/c/x.bin", "a/b/x.bin", "a\\b\\x.bin", "x-y.bin"] {
+            let req = packet::Request::wrq(filename, "octet", &Options::default());
+            let (_, mut buf) = testutil::request(addr, &req).await;
+            assert_eq!(
+                Some(OpCode::Error),
+                packet::parse_opcode(&mut buf)?,
+                "{}",
+                filename
+            );
+            let error = packet::parse_error(&mut buf)?;
+            assert_eq!(ErrorCode::IllegalTftpOp as u16, error.error_code());
+        }
+        assert!(!root.path().join("a/b/x.bin").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_dry_run() -> Result<(), Error> {
+        testutil::capture_logs();
+
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?.with_dry_run();
+        let addr = testutil::serve(server).await;
+
+        let options = OptionBuilder::default().blksize(1428).build();
+        let req = packet::Request::wrq("dry-run", "octet", &options);
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Error), packet::parse_opcode(&mut buf)?);
+        assert!(!root.path().join("dry-run").exists());
+
+        let logs: Vec<String> = testutil::logs()
+            .into_iter()
+            .filter(|l| l.contains("dry-run: would receive"))
+            .collect();
+        assert_eq!(1, logs.len());
+        assert!(logs[0].contains("dry-run\""));
+        assert!(logs[0].contains("blksize: Some(1024)"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_access_log() -> Result<(), Error> {
+        use tokio::io::AsyncReadExt;
+
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a b"), testutil::content(1000))?;
+
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let limitations = OptionBuilder::default().blksize(512).build();
+        let server =
+            Server::new(testutil::addr(), root.path(), limitations)?.with_access_log(writer);
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().blksize(1024).build();
+        let client = Client::new(addr, "octet", options);
+        client.get(&local.path().join("a"), "a b").await?;
+
+        let mut buf = vec![0; 1024];
+        let size = reader.read(&mut buf).await?;
+        let line = String::from_utf8(buf[..size].to_vec())?;
+        assert!(line.ends_with('\n'));
+
+        let fields: Vec<&str> = line.trim_end().split(' ').collect();
+        assert!(fields[0].starts_with("127.0.0.1:"));
+        assert_eq!("Rrq", fields[1]);
+        assert_eq!(["\"a", "b\""], fields[2..4]);
+        assert_eq!("1000", fields[4]);
+        assert!(fields[5].parse::<u64>().is_ok());
+        assert_eq!("false", fields[6]);
+        assert_eq!("blksize=1024>512", fields[7]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_access_log_stalled() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1000))?;
+
+        // 読み出さないため、1 byte 書き込んだところで書き込みが止まる。
+        let (writer, _reader) = tokio::io::duplex(1);
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_access_log(writer);
+        let addr = *server.service_addr();
+        let handle = tokio::spawn(server.serve_one());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "a").await?;
+
+        // ログの書き込みを待たずに転送を終える。
+        let summary = tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap()?;
+        assert_eq!(1000, summary.bytes());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_socket_config() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1000))?;
+
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = count.clone();
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_socket_config(move |sock| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                sock.set_reuse_address(true)
+            });
+        let addr = testutil::serve(server).await;
+
+        // SO_REUSEADDR が設定されていれば、同じアドレスにバインドできる。
+        let other = socket2::Socket::new(
+            socket2::Domain::IPV4,
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        other.set_reuse_address(true)?;
+        other.bind(&addr.into())?;
+        drop(other);
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "a").await?;
+
+        // 受付用と転送用のソケット。
+        assert_eq!(2, count.load(std::sync::atomic::Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rrq_fd_exhausted() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1000))?;
+
+        // 受付用のソケットのみ作成でき、転送用のソケットは上限に達したものとする。
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_socket_config(move |_| {
+                match count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 => Ok(()),
+                    _ => Err(io::Error::from_raw_os_error(libc::EMFILE)),
+                }
+            });
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        let ret = client.get(&local.path().join("a"), "a").await;
+        assert!(matches!(ret, Err(Error::ServerBusy)), "{:?}", ret);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drop_garbage() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = count.clone();
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_drop_garbage()
+            .with_socket_config(move |_| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            });
+        let addr = testutil::serve(server).await;
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        sock.send_to(&[0xde, 0xad, 0xbe, 0xef, 0x00], addr).await?;
+        sock.send_to(&[0x7f], addr).await?;
+
+        let mut buf = vec![0; 1024];
+        let ret = tokio::time::timeout(Duration::from_millis(300), sock.recv_from(&mut buf)).await;
+        assert!(ret.is_err());
+        // 受付用のソケットのみ。
+        assert_eq!(1, count.load(std::sync::atomic::Ordering::SeqCst));
+
+        // オペコードが正しい不正な要求には ERROR を返す。
+        sock.send_to(&packet::ack(1), addr).await?;
+        let (size, _) = tokio::time::timeout(Duration::from_secs(1), sock.recv_from(&mut buf))
+            .await
+            .unwrap()?;
+        let mut reply = Bytes::copy_from_slice(&buf[..size]);
+        assert_eq!(Some(OpCode::Error), packet::parse_opcode(&mut reply)?);
+        assert_eq!(2, count.load(std::sync::atomic::Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_confirm() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().confirm().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?
+            .with_dally(Duration::from_millis(100));
+        let addr = *server.service_addr();
+        let handle = tokio::spawn(server.serve_one());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().confirm().build();
+        let client = Client::new(addr, "octet", options);
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        let summary = handle.await.unwrap()?;
+        assert!(stats.confirmed());
+        assert!(summary.confirmed());
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_confirm() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+
+        let limitations = OptionBuilder::default().confirm().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = *server.service_addr();
+        let handle = tokio::spawn(server.serve_one());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let local = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(local.path().join("a"), &content)?;
+
+        let options = OptionBuilder::default().confirm().build();
+        let mut client = Client::new(addr, "octet", options);
+        client.set_dally(Duration::from_millis(100));
+        let stats = client.put(&local.path().join("a"), "a").await?;
+
+        let summary = handle.await.unwrap()?;
+        assert!(stats.confirmed());
+        assert!(summary.confirmed());
+        assert_eq!(content, std::fs::read(root.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_confirm_timeout_keeps_file() -> Result<(), Error> {
+        for use_temp_dir in [false, true] {
+            let root = tempfile::tempdir()?;
+            let temp = tempfile::tempdir()?;
+            let limitations = OptionBuilder::default().confirm().build();
+            let mut server = Server::new(testutil::addr(), root.path(), limitations.clone())?
+                .with_min_timeout(Duration::from_millis(50));
+            if use_temp_dir {
+                server = server.with_temp_dir(temp.path());
+            }
+            let addr = *server.service_addr();
+            let handle = tokio::spawn(server.serve_one());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let content = testutil::content(100);
+            let req = packet::Request::wrq("a", "octet", &limitations);
+            let (sock, mut buf) = testutil::request(addr, &req).await;
+            assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+            sock.send(&packet::data(1, &content[..])).await?;
+
+            // x-confirm に応答しないため、ファイルの確定後に転送が失敗する。
+            assert!(handle.await.unwrap().is_err());
+            assert_eq!(content, std::fs::read(root.path().join("a"))?);
+            assert_eq!(0, std::fs::read_dir(temp.path())?.count());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_anti_amplification() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(3000))?;
+
+        let limitations = OptionBuilder::default().blksize(1468).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?
+            .with_anti_amplification()
+            .with_min_timeout(Duration::from_millis(100));
+        let addr = testutil::serve(server).await;
+
+        let options = OptionBuilder::default().blksize(1468).build();
+        let req = packet::Request::rrq("a", "octet", &options);
+        let (sock, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+
+        // ACK を返すまでは OACK の再送のみで、その量も要求の大きさで制限される。
+        let mut received = vec![0; 2048];
+        let mut oacks = 1;
+        while let Ok(ret) =
+            tokio::time::timeout(Duration::from_millis(300), sock.recv(&mut received)).await
+        {
+            let mut reply = Bytes::copy_from_slice(&received[..ret?]);
+            assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut reply)?);
+            oacks += 1;
+        }
+        let limit = packet::request(&req).len() * AMPLIFICATION_FACTOR;
+        assert!(oacks * buf.len() <= limit);
+
+        sock.send(&packet::ack(0)).await?;
+        let size = sock.recv(&mut received).await?;
+        let mut reply = Bytes::copy_from_slice(&received[..size]);
+        assert_eq!(Some(OpCode::Data), packet::parse_opcode(&mut reply)?);
+        assert_eq!(1, packet::parse_blocknum(&mut reply)?);
+
+        // OACK を返せない要求には DATA を送信しない。
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Error), packet::parse_opcode(&mut buf)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_oack_retransmit() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1000))?;
+
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?
+            .with_oack_retransmit(Duration::from_millis(50), 20);
+        let addr = testutil::serve(server).await;
+
+        let started_at = Instant::now();
+        let options = OptionBuilder::default().blksize(1024).build();
+        let req = packet::Request::rrq("a", "octet", &options);
+        let (sock, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+
+        // 最初の 2 つの OACK は失われたものとして応答しない。
+        let mut received = vec![0; 2048];
+        for _ in 0..2 {
+            let size = sock.recv(&mut received).await?;
+            let mut reply = Bytes::copy_from_slice(&received[..size]);
+            assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut reply)?);
+        }
+        assert!(started_at.elapsed() < Duration::from_millis(500));
+
+        sock.send(&packet::ack(0)).await?;
+        let size = sock.recv(&mut received).await?;
+        let mut reply = Bytes::copy_from_slice(&received[..size]);
+        assert_eq!(Some(OpCode::Data), packet::parse_opcode(&mut reply)?);
+        assert_eq!(1, packet::parse_blocknum(&mut reply)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_rate_limit() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_client_rate_limit(1, 16);
+        let addr = testutil::serve(server).await;
+
+        let req = packet::request(&packet::Request::rrq(
+            "missing",
+            "octet",
+            &Options::default(),
+        ));
+        let sock = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut buf = vec![0; 1024];
+
+        sock.send_to(&req, addr).await?;
+        sock.recv_from(&mut buf).await?;
+
+        // 制限を超えた要求には応答しない。
+        sock.send_to(&req, addr).await?;
+        let ret = tokio::time::timeout(Duration::from_millis(300), sock.recv_from(&mut buf)).await;
+        assert!(ret.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_serve_one_clamps() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(3000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = *server.service_addr();
+        let handle = tokio::spawn(server.serve_one());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default().blksize(1468).build();
+        let client = Client::new(addr, "octet", options);
+        client.get(&local.path().join("a"), "a").await?;
+
+        let summary = handle.await.unwrap()?;
+        assert_eq!(1, summary.clamps().len());
+        let clamp = &summary.clamps()[0];
+        assert_eq!("blksize", clamp.option());
+        assert_eq!(1468, clamp.requested());
+        assert_eq!(1024, clamp.granted());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_batch_io() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(512 * 40 + 100);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().windowsize(8).build();
+        let local = tempfile::tempdir()?;
+        for (batch_io, filename) in [(false, "a"), (true, "b")] {
+            let mut server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+            if batch_io {
+                server = server.with_batch_io();
+            }
+            let addr = *server.service_addr();
+            let handle = tokio::spawn(server.serve_one());
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let options = OptionBuilder::default().windowsize(8).build();
+            let client = Client::new(addr, "octet", options);
+            let stats = client.get(&local.path().join(filename), "a").await?;
+            assert_eq!(content.len() as u64, stats.bytes());
+
+            // 41 個の DATA と 6 個以上の ACK をまとめて送受信する。
+            let summary = handle.await.unwrap()?;
+            if batch_io && cfg!(all(feature = "batch-io", target_os = "linux")) {
+                assert!(
+                    summary.batched_packets() >= 41 + 6,
+                    "{}",
+                    summary.batched_packets()
+                );
+            } else {
+                assert_eq!(0, summary.batched_packets());
+            }
+        }
+
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        assert_eq!(content, std::fs::read(local.path().join("b"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_read_ahead() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(512 * 40 + 100);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().windowsize(4).build();
+        let local = tempfile::tempdir()?;
+        for mode in ["octet", "netascii"] {
+            let mut received = vec![];
+            for read_ahead in [false, true] {
+                let mut server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+                if read_ahead {
+                    server = server.with_read_ahead();
+                }
+                let addr = testutil::serve(server).await;
+
+                let options = OptionBuilder::default().windowsize(4).build();
+                let client = Client::new(addr, mode, options);
+                let local_file = local.path().join(format!("{}-{}", mode, read_ahead));
+                client.get(&local_file, "a").await?;
+                received.push(std::fs::read(local_file)?);
+            }
+
+            // 先読みしたブロックは逐次読み込んだ場合と同じ内容で送信される。
+            assert_eq!(received[0], received[1]);
+        }
+
+        assert_eq!(content, std::fs::read(local.path().join("octet-true"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_sack_resend_missing_only() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(512 * 8);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().windowsize(4).sack().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::rrq("a", "octet", &limitations);
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        let recv_data = || async {
+            let mut buf = vec![0; 1024];
+            let size = sock.recv(&mut buf).await.unwrap();
+            let mut data = Bytes::from(buf[..size].to_vec());
+            packet::parse_opcode(&mut data).unwrap();
+            let blocknum = packet::parse_blocknum(&mut data).unwrap();
+            (blocknum, data)
+        };
+
+        sock.send(&packet::ack(0)).await?;
+        for blocknum in 1..=4 {
+            assert_eq!(blocknum, recv_data().await.0);
+        }
+
+        // ブロック 2 が欠けたことを通知すると、ブロック 2 のみが再送される。
+        sock.send(&packet::sack(1, &[0b0110_0000])).await?;
+        let (blocknum, data) = recv_data().await;
+        assert_eq!(2, blocknum);
+        assert_eq!(&content[512..512 * 2], data.as_ref());
+
+        sock.send(&packet::ack(4)).await?;
+        assert_eq!(5, recv_data().await.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_sack_round_trip() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(5000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default().windowsize(4).sack().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", limitations.clone());
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(
+            &OptionNegotiation::Accepted(limitations),
+            stats.negotiation()
+        );
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_window_upgrade() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(512 * 8 + 10);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default()
+            .windowsize(4)
+            .window_upgrade(1)
+            .build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let options = OptionBuilder::default()
+            .windowsize(1)
+            .window_upgrade(8)
+            .build();
+        let mut client = Client::new(addr, "octet", options);
+        client.with_trace();
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        match stats.negotiation() {
+            OptionNegotiation::Accepted(options) => assert_eq!(4, options.windowsize()),
+            n => panic!("{:?}", n),
+        }
+        // OACK と 4 ブロックごとの ACK のみ送信する。
+        let acks: Vec<u16> = client
+            .trace()
+            .iter()
+            .filter(|t| t.op_code() == Some(&OpCode::Ack))
+            .filter_map(|t| t.blocknum())
+            .collect();
+        assert_eq!(vec![0, 4, 8, 9], acks);
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn octet_preserves_bytes() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let local = tempfile::tempdir()?;
+        // netascii として扱うと変換される CR、LF、NUL を含める。
+        let content: Vec<u8> = (0..10000).map(|i| b"\r\n\0a\r"[i % 5]).collect();
+        std::fs::write(root.path().join("a"), &content)?;
+        std::fs::write(local.path().join("b"), &content)?;
+
+        let limitations = OptionBuilder::default().blksize(1024).windowsize(4).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        // モード名は大文字と小文字を区別しない。
+        for mode in ["octet", "OCTET"] {
+            let client = Client::new(addr, mode, limitations.clone());
+            let get = local.path().join(format!("get-{}", mode));
+            let stats = client.get(&get, "a").await?;
+            assert_eq!(content.len() as u64, stats.bytes());
+            assert_eq!(content, std::fs::read(&get)?);
+
+            let put = format!("put-{}", mode);
+            client.put(&local.path().join("b"), &put).await?;
+            assert_eq!(content, std::fs::read(root.path().join(&put))?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_past_rollover() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        // ブロック番号 0 が DATA として再び現れるまで転送する。
+        let content = testutil::content(8 * 65536 + 100);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let options = OptionBuilder::default().blksize(8).windowsize(16).build();
+        let server = Server::new(testutil::addr(), root.path(), options.clone())?;
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", options);
+        let stats = client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(content.len() as u64, stats.bytes());
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_rollover_targets() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(8 * 65536 + 100);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let limitations = OptionBuilder::default()
+            .blksize(8)
+            .windowsize(16)
+            .rollover(0)
+            .build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        for rollover in [0, 1] {
+            let options = OptionBuilder::default()
+                .blksize(8)
+                .windowsize(16)
+                .rollover(rollover)
+                .build();
+            let req = packet::Request::rrq("a", "octet", &options);
+            let (sock, mut buf) = testutil::request(addr, &req).await;
+            assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+            assert_eq!(rollover as u16, packet::parse_oack(&mut buf)?.rollover());
+            sock.send(&packet::ack(0)).await?;
+
+            let mut blocknums = vec![];
+            let mut received = vec![];
+            loop {
+                let mut buf = vec![0; 1024];
+                let size = sock.recv(&mut buf).await?;
+                let mut buf = Bytes::from(buf[..size].to_vec());
+                assert_eq!(Some(OpCode::Data), packet::parse_opcode(&mut buf)?);
+                let blocknum = packet::parse_blocknum(&mut buf)?;
+                blocknums.push(blocknum);
+                received.extend_from_slice(&buf);
+
+                if buf.len() < 8 {
+                    sock.send(&packet::ack(blocknum)).await?;
+                    break;
+                }
+                if blocknums.len() % 16 == 0 {
+                    sock.send(&packet::ack(blocknum)).await?;
+                }
+            }
+
+            // 65535 の次のブロック番号が交渉した値になる。
+            assert_eq!(65535, blocknums[65534]);
+            assert_eq!(rollover as u16, blocknums[65535]);
+            assert_eq!(rollover as u16 + 1, blocknums[65536]);
+            assert_eq!(content, received);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wrq_rollover_targets() -> Result<(), Error> {
+        let local = tempfile::tempdir()?;
+        let content = testutil::content(8 * 65536 + 100);
+        std::fs::write(local.path().join("a"), &content)?;
+
+        let root = tempfile::tempdir()?;
+        let limitations = OptionBuilder::default()
+            .blksize(8)
+            .windowsize(16)
+            .rollover(0)
+            .build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        for rollover in [0, 1] {
+            let options = OptionBuilder::default()
+                .blksize(8)
+                .windowsize(16)
+                .rollover(rollover)
+                .build();
+            let client = Client::new(addr, "octet", options);
+            let remote = format!("b{}", rollover);
+            let stats = client.put(&local.path().join("a"), &remote).await?;
+
+            assert_eq!(content.len() as u64, stats.bytes());
+            assert_eq!(content, std::fs::read(root.path().join(&remote))?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn active_sessions() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(5000))?;
+        std::fs::write(root.path().join("b"), testutil::content(5000))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let registry = server.session_registry();
+        assert!(server.active_sessions().is_empty());
+        let addr = testutil::serve(server).await;
+
+        // ACK を送信せずに転送を止めておく。
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (a, _) = testutil::request(addr, &req).await;
+        let req = packet::Request::rrq("b", "octet", &Options::default());
+        let (b, _) = testutil::request(addr, &req).await;
+
+        a.send(&packet::ack(1)).await?;
+        let mut buf = vec![0; 1024];
+        a.recv(&mut buf).await?;
+
+        let mut sessions = registry.active();
+        sessions.sort_by(|x, y| x.filename().cmp(y.filename()));
+        assert_eq!(2, sessions.len());
+        assert_eq!("a", sessions[0].filename());
+        assert_eq!(&a.local_addr()?, sessions[0].remote_addr());
+        assert_eq!(&OpCode::Rrq, sessions[0].op_code());
+        assert_eq!(512, sessions[0].bytes());
+        assert_eq!("b", sessions[1].filename());
+        assert_eq!(&b.local_addr()?, sessions[1].remote_addr());
+        assert_eq!(0, sessions[1].bytes());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_aborter_same_addr() {
+        let aborter = SessionAborter::default();
+        let remote_addr = testutil::addr();
+
+        // 同じアドレスのセッションが重なっても、先に終わった方だけを取り除く。
+        let a = aborter.register(remote_addr);
+        let b = aborter.register(remote_addr);
+        drop(a);
+        assert!(aborter.abort(&remote_addr));
+        tokio::time::timeout(Duration::from_secs(1), b.notify.notified())
+            .await
+            .unwrap();
+
+        drop(b);
+        assert!(!aborter.abort(&remote_addr));
+    }
+
+    #[test]
+    fn session_registry_same_addr() {
+        let registry = SessionRegistry::default();
+        let remote_addr = testutil::addr();
+
+        // 同じアドレスのセッションが重なっても、先に終わった方だけを取り除く。
+        let a = registry.register(remote_addr, "a", &OpCode::Rrq);
+        let b = registry.register(remote_addr, "b", &OpCode::Rrq);
+        assert_eq!(2, registry.active().len());
+
+        drop(a);
+        let sessions = registry.active();
+        assert_eq!(1, sessions.len());
+        assert_eq!("b", sessions[0].filename());
+
+        drop(b);
+        assert!(registry.active().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rrq_abort_session() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(5000))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let aborter = server.aborter();
+        let addr = testutil::serve(server).await;
+
+        // ACK を送信せずに転送を止めておく。
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        assert!(aborter.abort(&sock.local_addr()?));
+
+        let mut buf = vec![0; 1024];
+        let size = sock.recv(&mut buf).await?;
+        let mut buf = Bytes::from(buf[..size].to_vec());
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        assert_eq!("Aborted", packet::parse_error(&mut buf)?.message());
+
+        let unknown = "127.0.0.1:1".parse::<SocketAddr>()?;
+        assert!(!aborter.abort(&unknown));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_service_addrs() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(1000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let extra = testutil::addr();
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_service_addrs(&[extra]);
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        for (i, addr) in [addr, extra].into_iter().enumerate() {
+            let client = Client::new(addr, "octet", Options::default());
+            let localpath = local.path().join(i.to_string());
+            client.get(&localpath, "a").await?;
+            assert_eq!(content, std::fs::read(localpath)?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rrq_oack_never_acked() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), b"a")?;
+
+        let options = OptionBuilder::default().timeout(1).build();
+        let server = Server::new(testutil::addr(), root.path(), options.clone())?;
+        let addr = *server.service_addr();
+        let handle = tokio::spawn(server.serve_one());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // OACK を受信した後、応答せずに消える。
+        let started_at = tokio::time::Instant::now();
+        let req = packet::Request::rrq("a", "octet", &options);
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Oack)
+        ));
+
+        // 再送の上限に達するとセッションを終了する。
+        let ret = handle.await.unwrap();
+        assert!(matches!(ret, Err(Error::Timedout)));
+        assert!(started_at.elapsed() <= Duration::from_secs(11));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_source_truncated() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(512 * 64);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        // 転送中に送信元ファイルを切り詰める。
+        std::fs::write(root.path().join("a"), &content[..100])?;
+
+        let mut blocknum = 1;
+        loop {
+            sock.send(&packet::ack(blocknum)).await?;
+
+            let mut buf = vec![0; 1024];
+            let size = sock.recv(&mut buf).await?;
+            let mut buf = Bytes::from(buf[..size].to_vec());
+            match packet::parse_opcode(&mut buf)? {
+                Some(OpCode::Data) => {
+                    blocknum = packet::parse_blocknum(&mut buf)?;
+                    assert_eq!(512, buf.len());
+                }
+                Some(OpCode::Error) => {
+                    let error = packet::parse_error(&mut buf)?;
+                    assert_eq!("SourceChanged", error.message());
+                    break;
+                }
+                _ => panic!(),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "embedded")]
+    #[tokio::test]
+    async fn rrq_with_file_store() -> Result<(), Error> {
+        use crate::store::StaticFileStore;
+
+        let root = tempfile::tempdir()?;
+        let store = StaticFileStore::new(&[("boot/a.img", b"embedded")]);
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_file_store(store);
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client
+            .get(&local.path().join("a.img"), "boot/a.img")
+            .await?;
+        assert_eq!(
+            b"embedded",
+            std::fs::read(local.path().join("a.img"))?.as_slice()
+        );
+
+        let req = packet::Request::wrq("a.img", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::AccessViolation as u16, error.error_code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_oack_without_declined_option() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(10))?;
+
+        let limitations = OptionBuilder::default().blksize(1024).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations.clone())?;
+        let addr = testutil::serve(server).await;
+
+        let options = OptionBuilder::default().blksize(4096).tsize().build();
+        let req = packet::Request::rrq("a", "octet", &options);
+        let (_, mut buf) = testutil::request(addr, &req).await;
+
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Oack)
+        ));
+        assert_eq!(limitations, packet::parse_oack(&mut buf)?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_reject_oack() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1000))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (sock, _) = testutil::request(addr, &req).await;
+
+        let options = OptionBuilder::default().blksize(1024).build();
+        sock.send(&packet::oack(&options)).await?;
+
+        let mut buf = vec![0; 1024];
+        let size = sock.recv(&mut buf).await?;
+        let mut buf = Bytes::from(buf[..size].to_vec());
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::IllegalTftpOp as u16, error.error_code());
+        Ok(())
+    }
+
+    #[cfg(all(feature = "fd-reserve", target_os = "linux"))]
+    #[tokio::test]
+    async fn rrq_fd_reserve() -> Result<(), Error> {
+        use crate::fdlimit::{self, FDS_PER_SESSION};
+
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(3000))?;
+
+        // 転送中のセッションが 1 つになると予約分に達する制限。
+        let reserve = fdlimit::soft_limit()? - FDS_PER_SESSION;
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_fd_reserve(reserve);
+        let addr = testutil::serve(server).await;
+
+        // ACK を返さずにセッションを転送中のままにする。
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Data), packet::parse_opcode(&mut buf)?);
+
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Error), packet::parse_opcode(&mut buf)?);
+        let error = packet::parse_error(&mut buf)?;
+        assert!(error.message().contains("ServerBusy"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serve_until_rejects_while_draining() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(600);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = *server.service_addr();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let handle = tokio::spawn(server.serve_until(async {
+            let _ = rx.await;
+        }));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // ACK を返さずにセッションを転送中のままにして終了を開始する。
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (sock, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Data), packet::parse_opcode(&mut buf)?);
+        tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Error), packet::parse_opcode(&mut buf)?);
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::NotDefined as u16, error.error_code());
+        assert_eq!("ShuttingDown", error.message());
+        assert!(!handle.is_finished());
+
+        // 転送中のセッションが完了すると終了する。
+        sock.send(&packet::ack(1)).await?;
+        let mut buf = vec![0; 1024];
+        let size = sock.recv(&mut buf).await?;
+        assert_eq!(4 + 88, size);
+        sock.send(&packet::ack(2)).await?;
+
+        let ret = tokio::time::timeout(Duration::from_secs(1), handle).await;
+        assert!(matches!(ret, Ok(Ok(Ok(())))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_quiet_errors() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_quiet_errors();
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::rrq("secret/missing", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::FileNotFound as u16, error.error_code());
+        assert_eq!("File not found", error.message());
+        assert!(!error.message().contains("secret"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_case_insensitive() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(1000);
+        std::fs::create_dir(root.path().join("boot"))?;
+        std::fs::write(root.path().join("boot").join("pxelinux.0"), &content)?;
+
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_case_insensitive();
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client
+            .get(&local.path().join("a"), "boot/PXELINUX.0")
+            .await?;
+
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_root_check() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), b"a")?;
+
+        let server =
+            Server::new(testutil::addr(), root.path(), Options::default())?.with_root_check();
+        let addr = testutil::serve(server).await;
+
+        std::fs::remove_dir_all(root.path())?;
+
+        let req = packet::Request::rrq("a", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::NotDefined as u16, error.error_code());
+        assert_eq!("ServiceUnavailable", error.message());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_on_negotiate() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1000))?;
+
+        let limitations = OptionBuilder::default().blksize(1468).build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?.on_negotiate(
+            |remote_addr, requested, granted| {
+                // 特定のクライアントには blksize を 512 に固定する。
+                if remote_addr.ip() == IpAddr::V4(Ipv4Addr::LOCALHOST) && requested.blksize() > 512
+                {
+                    granted.set_blksize(512);
+                }
+            },
+        );
+        let addr = testutil::serve(server).await;
+
+        let options = OptionBuilder::default().blksize(1024).build();
+        let req = packet::Request::rrq("a", "octet", &options);
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert_eq!(Some(OpCode::Oack), packet::parse_opcode(&mut buf)?);
+        assert_eq!(512, packet::parse_oack(&mut buf)?.blksize());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_filename_mapper() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(1000);
+        std::fs::write(root.path().join("firmware-v1.2.3.bin"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_filename_mapper(|filename| match filename {
+                "latest" => Some("firmware-v1.2.3.bin".to_string()),
+                "denied" => None,
+                _ => Some(filename.to_string()),
+            });
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "latest").await?;
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+
+        let req = packet::Request::rrq("denied", "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        packet::parse_opcode(&mut buf)?;
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::FileNotFound as u16, error.error_code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_fallback_file() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(1000);
+        std::fs::write(root.path().join("default"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_fallback_file("default");
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client
+            .get(&local.path().join("a"), "pxelinux.cfg/01-aa-bb")
+            .await?;
+
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_disallowed_mode() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(10))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_allowed_modes(&["octet"]);
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::rrq("a", "netascii", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+        let error = packet::parse_error(&mut buf)?;
+        assert_eq!(ErrorCode::IllegalTftpOp as u16, error.error_code());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_head() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(1234))?;
+
+        let limitations = OptionBuilder::default().tsize().build();
+        let server = Server::new(testutil::addr(), root.path(), limitations)?;
+        let addr = testutil::serve(server).await;
+
+        let options = OptionBuilder::default().head().build();
+        let req = packet::Request::rrq("a", "octet", &options);
+        let (sock, mut buf) = testutil::request(addr, &req).await;
+
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Oack)
+        ));
+        let options = packet::parse_oack(&mut buf)?;
+        assert!(options.head());
+        assert_eq!(1234, options.tsize());
+
+        // OACK を確認しても DATA は送信されない。
+        sock.send(&packet::ack(0)).await?;
+        let mut buf = vec![0; 1024];
+        let ret = tokio::time::timeout(Duration::from_millis(500), sock.recv(&mut buf)).await;
+        assert!(!matches!(ret, Ok(Ok(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_outside_root() -> Result<(), Error> {
+        let outside = tempfile::tempdir()?;
+        std::fs::write(outside.path().join("a"), testutil::content(10))?;
+        let root = tempfile::tempdir()?;
+        let filename = outside.path().join("a").to_string_lossy().to_string();
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+
+        let req = packet::Request::rrq(&filename, "octet", &Options::default());
+        let (_, mut buf) = testutil::request(addr, &req).await;
+        assert!(matches!(
+            packet::parse_opcode(&mut buf)?,
+            Some(OpCode::Error)
+        ));
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_allow_outside_root();
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), &filename).await?;
+        assert_eq!(
+            testutil::content(10),
+            std::fs::read(local.path().join("a"))?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_denied() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir(root.path().join("etc"))?;
+        std::fs::write(root.path().join("server.key"), testutil::content(10))?;
+        std::fs::write(
+            root.path().join("etc").join("shadow"),
+            testutil::content(10),
+        )?;
+        std::fs::write(root.path().join("a"), testutil::content(10))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_denied("*.key")
+            .with_denied("shadow");
+        let addr = testutil::serve(server).await;
+
+        for filename in ["server.key", "etc/shadow"] {
+            let req = packet::Request::rrq(filename, "octet", &Options::default());
+            let (_, mut buf) = testutil::request(addr, &req).await;
+
+            assert!(matches!(
+                packet::parse_opcode(&mut buf)?,
+                Some(OpCode::Error)
+            ));
+            let error = packet::parse_error(&mut buf)?;
+            assert_eq!(ErrorCode::AccessViolation as u16, error.error_code());
+        }
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "a").await?;
+        assert_eq!(
+            testutil::content(10),
+            std::fs::read(local.path().join("a"))?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_denied_resolved_path() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("server.key"), testutil::content(10))?;
+        std::fs::write(root.path().join("a"), testutil::content(10))?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_case_insensitive()
+            .with_fallback_file("server.key")
+            .with_denied("*.key");
+        let addr = testutil::serve(server).await;
+
+        // 大文字の名前や存在しない名前も、解決したファイルで拒否する。
+        for filename in ["SERVER.KEY", "missing"] {
+            let req = packet::Request::rrq(filename, "octet", &Options::default());
+            let (_, mut buf) = testutil::request(addr, &req).await;
+
+            assert!(matches!(
+                packet::parse_opcode(&mut buf)?,
+                Some(OpCode::Error)
+            ));
+            let error = packet::parse_error(&mut buf)?;
+            assert_eq!(ErrorCode::AccessViolation as u16, error.error_code());
+        }
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "A").await?;
+        Ok(())
+    }
+
+    // 変更した環境変数を、テストの終了時に元の値へ戻す。
+    struct EnvGuard(Vec<(&'static str, Option<std::ffi::OsString>)>);
+
+    impl EnvGuard {
+        fn new(keys: &[&'static str]) -> Self {
+            EnvGuard(keys.iter().map(|k| (*k, env::var_os(k))).collect())
+        }
+    }
+
+    impl Drop for EnvGuard {
+        fn drop(&mut self) {
+            for (key, value) in &self.0 {
+                match value {
+                    Some(value) => env::set_var(key, value),
+                    None => env::remove_var(key),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_env_ok() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let _guard = EnvGuard::new(&[
+            "TFTP_BIND",
+            "TFTP_PORT",
+            "TFTP_ROOT",
+            "TFTP_BLKSIZE",
+            "TFTP_WINDOWSIZE",
+        ]);
+        env::set_var("TFTP_BIND", "127.0.0.1");
+        env::set_var("TFTP_PORT", "6969");
+        env::set_var("TFTP_ROOT", root.path());
+        env::set_var("TFTP_BLKSIZE", "1024");
+        env::set_var("TFTP_WINDOWSIZE", "4");
+
+        let server = Server::from_env()?.build()?;
+
+        assert_eq!("127.0.0.1:6969".parse::<SocketAddr>()?, server.service_addr);
+        assert_eq!(root.path().canonicalize()?, server.root);
+        assert_eq!(1024, server.options.blksize());
+        assert_eq!(4, server.options.windowsize());
+
+        env::set_var("TFTP_BLKSIZE", "1");
+        assert!(matches!(Server::from_env(), Err(Error::InvalidEnv(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn fd_exhausted() {
+        #[cfg(unix)]
+        assert!(is_fd_exhausted(&io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(!is_fd_exhausted(&io::Error::from(io::ErrorKind::AddrInUse)));
+    }
+
+    #[test]
+    fn normalize_addr_mapped() -> Result<(), Error> {
+        let mapped = "[::ffff:192.0.2.1]:1069".parse::<SocketAddr>()?;
+        assert_eq!(
+            "192.0.2.1:1069".parse::<SocketAddr>()?,
+            normalize_addr(mapped)
+        );
+
+        let v6 = "[::1]:1069".parse::<SocketAddr>()?;
+        assert_eq!(v6, normalize_addr(v6));
+
+        let remote = normalize_addr(mapped);
+        assert_eq!(
+            IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            reply_ip(IpAddr::from(Ipv6Addr::UNSPECIFIED), &remote)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rrq_with_progress() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("a"), testutil::content(5000))?;
+
+        let progress = Arc::new(std::sync::Mutex::new(vec![]));
+        let received = progress.clone();
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?
+            .with_progress(2, move |p| received.lock().unwrap().push(p.bytes()));
+        let addr = testutil::serve(server).await;
+
+        let local = tempfile::tempdir()?;
+        let client = Client::new(addr, "octet", Options::default());
+        client.get(&local.path().join("a"), "a").await?;
+        // 最後の ACK をサーバが処理するまで待つ。
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+
+        assert_eq!(
+            vec![1024, 2048, 3072, 4096, 5000],
+            *progress.lock().unwrap()
+        );
+        Ok(())
+    }
 }
@@ -1,17 +1,32 @@
+#[cfg(all(feature = "batch-io", target_os = "linux"))]
+use super::batch;
+#[cfg(feature = "digest")]
+use super::digest::{Digest, DigestReader, DigestWriter};
 use super::error::Error;
-use super::file;
-use super::options::Options;
+use super::file::{self, FileReader, FileWriter, Mode, OffsetReader};
+use super::options::{Clamp, Options};
 use super::packet;
-use super::{HEADER_LEN, ROLLOVER};
+use super::pool::{BufferPool, PooledBuffer};
+use super::recorder::{Direction, Recorder};
+use super::server::Progress;
+#[cfg(feature = "socks5")]
+use super::socks5;
+use super::{blocknum_add, ErrorCode, OpCode, HEADER_LEN};
 use bytes::Bytes;
-use log::{trace, warn};
+use log::{error, trace, warn};
+use std::collections::VecDeque;
 use std::future::Future;
+use std::io::SeekFrom;
 use std::net::SocketAddr;
-use tokio::fs::File;
-use tokio::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::UdpSocket;
 use tokio::sync::Mutex;
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, Instant};
 
 pub struct TftpSession {
     blocknum_ack: u16,
@@ -20,15 +35,82 @@ pub struct TftpSession {
     sock: UdpSocket,
     remote_addr: SocketAddr,
     local_file: Option<TftpSessionFile>,
-    mode: String,
+    mode: Mode,
     options: Options,
     rollover: u32,
     lastch: Option<u8>,
+    persist_path: Option<(PathBuf, PathBuf)>,
+    remove_guard: Option<file::RemoveGuard>,
+    peer_error_handler: Option<PeerErrorHandler>,
+    #[cfg(feature = "socks5")]
+    socks5: Option<socks5::UdpAssociate>,
+    recorder: Option<Recorder>,
+    preallocate: Option<File>,
+    transferred_blocks: u64,
+    transferred_bytes: u64,
+    progress_handler: Option<(u64, ProgressHandler)>,
+    started_at: Instant,
+    min_transfer_rate: Option<u64>,
+    max_duration: Option<Duration>,
+    min_timeout: Option<Duration>,
+    role: Role,
+    quiet_errors: bool,
+    peer_errored: bool,
+    ack_frequency: Option<u16>,
+    first_block_timeout: Option<Duration>,
+    dally: Option<Duration>,
+    reject_stray_ack: bool,
+    buffer_pool: Option<Arc<BufferPool>>,
+    source: Option<Source>,
+    sack_blocks: Vec<(u16, Bytes)>,
+    retransmits: AtomicU32,
+    clamps: Vec<Clamp>,
+    batch_io: bool,
+    batched_packets: AtomicU64,
+    read_ahead: Option<Mutex<ReadAhead>>,
+    confirmed: bool,
+    max_rate: Option<u64>,
+    amplification_budget: Option<AtomicUsize>,
+    oack_retransmit: Option<(Duration, u32)>,
+    progress_counter: Option<Arc<AtomicU64>>,
+}
+
+const MAX_RETRANSMITS: u32 = 10;
+
+pub type PeerErrorHandler = Arc<dyn Fn(ErrorCode, &str) + Send + Sync>;
+pub type ProgressHandler = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
 }
 
 pub enum TftpSessionFile {
-    Reader(Mutex<BufReader<File>>),
-    Writer(BufWriter<File>),
+    Reader(Mutex<BufReader<Box<dyn FileReader>>>),
+    Writer(BufWriter<Box<dyn FileWriter>>),
+}
+
+// 転送開始時の送信元ファイルの状態。
+struct Source {
+    path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+// 次のウィンドウのために先読みしたブロック。
+#[derive(Default)]
+struct ReadAhead {
+    next: Option<(u64, Option<u8>)>,
+    blocks: VecDeque<PrefetchedBlock>,
+}
+
+struct PrefetchedBlock {
+    reader_pos: u64,
+    lastch: Option<u8>,
+    data: Bytes,
+    reader_pos_len: usize,
+    ch: Option<u8>,
 }
 
 struct FileBlock {
@@ -48,10 +130,44 @@ impl TftpSession {
             sock,
             remote_addr,
             local_file: None,
-            mode: "netascii".to_string(),
+            mode: Mode::Netascii,
             options: Options::default(),
             rollover: 0,
             lastch: None,
+            persist_path: None,
+            remove_guard: None,
+            peer_error_handler: None,
+            #[cfg(feature = "socks5")]
+            socks5: None,
+            recorder: None,
+            preallocate: None,
+            transferred_blocks: 0,
+            transferred_bytes: 0,
+            progress_handler: None,
+            started_at: Instant::now(),
+            min_transfer_rate: None,
+            max_duration: None,
+            min_timeout: None,
+            role: Role::Client,
+            quiet_errors: false,
+            peer_errored: false,
+            ack_frequency: None,
+            first_block_timeout: None,
+            dally: None,
+            reject_stray_ack: false,
+            buffer_pool: None,
+            source: None,
+            sack_blocks: vec![],
+            retransmits: AtomicU32::new(0),
+            clamps: vec![],
+            batch_io: false,
+            batched_packets: AtomicU64::new(0),
+            read_ahead: None,
+            confirmed: false,
+            max_rate: None,
+            amplification_budget: None,
+            oack_retransmit: None,
+            progress_counter: None,
         }
     }
 
@@ -68,10 +184,7 @@ impl TftpSession {
     }
 
     pub fn blocknum_ack_add(&self, value: u16) -> u16 {
-        match self.blocknum_ack.checked_add(value) {
-            Some(v) => v,
-            _ => ROLLOVER,
-        }
+        blocknum_add(self.blocknum_ack, value, self.options().rollover())
     }
 
     pub fn blocknum_expect(&self, num: u16) -> bool {
@@ -89,7 +202,49 @@ impl TftpSession {
     }
 
     pub fn received_data_last(&self) -> bool {
-        self.received_data == self.options().windowsize()
+        // 送信側はウィンドウごとに ACK を待つため、windowsize より間隔を広げることはできない。
+        let windowsize = self.options().windowsize();
+        let threshold = self
+            .ack_frequency
+            .map(|f| f.clamp(1, windowsize))
+            .unwrap_or(windowsize);
+        self.received_data >= threshold
+    }
+
+    pub fn set_ack_frequency(&mut self, frequency: Option<u16>) {
+        self.ack_frequency = frequency;
+    }
+
+    // 現在のウィンドウの最後のブロック番号。
+    pub fn window_end(&self) -> u16 {
+        self.blocknum_ack
+            .wrapping_sub(self.received_data)
+            .wrapping_add(self.options().windowsize())
+    }
+
+    // 順序が入れ替わって届いたブロックをウィンドウ内であれば保持する。
+    pub fn sack_insert(&mut self, blocknum: u16, data: Bytes) {
+        let offset = blocknum.wrapping_sub(self.blocknum_ack);
+        let limit = self.window_end().wrapping_sub(self.blocknum_ack);
+        if offset <= limit && !self.sack_blocks.iter().any(|(b, _)| *b == blocknum) {
+            self.sack_blocks.push((blocknum, data));
+        }
+    }
+
+    pub fn sack_take(&mut self, blocknum: u16) -> Option<Bytes> {
+        let index = self.sack_blocks.iter().position(|(b, _)| *b == blocknum)?;
+        Some(self.sack_blocks.swap_remove(index).1)
+    }
+
+    fn sack_bitmap(&self) -> Vec<u8> {
+        let mut bitmap = vec![0u8; (self.options().windowsize() as usize + 7) / 8];
+        for (blocknum, _) in self.sack_blocks.iter() {
+            let i = (blocknum.wrapping_sub(self.blocknum_ack) - 1) as usize;
+            if i / 8 < bitmap.len() {
+                bitmap[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        bitmap
     }
 
     pub fn received_data_inc(&mut self) {
@@ -103,36 +258,507 @@ impl TftpSession {
         }
     }
 
-    pub fn reader(&self) -> &Mutex<BufReader<File>> {
+    pub fn reader(&self) -> &Mutex<BufReader<Box<dyn FileReader>>> {
         match self.local_file.as_ref() {
             Some(TftpSessionFile::Reader(reader)) => reader,
             _ => panic!(),
         }
     }
 
-    pub fn set_reader(&mut self, file: File) {
-        let reader = BufReader::new(file);
+    pub fn set_reader(&mut self, file: impl FileReader + 'static) {
+        let reader = BufReader::new(Box::new(file) as Box<dyn FileReader>);
         self.local_file = Some(TftpSessionFile::Reader(Mutex::new(reader)));
     }
 
-    pub fn writer_mut(&mut self) -> &mut BufWriter<File> {
+    pub fn writer_mut(&mut self) -> &mut BufWriter<Box<dyn FileWriter>> {
         match self.local_file.as_mut() {
             Some(TftpSessionFile::Writer(writer)) => writer,
             _ => panic!(),
         }
     }
 
-    pub fn set_writer(&mut self, file: File) {
+    pub fn set_writer(&mut self, file: Box<dyn FileWriter>) {
         let writer = BufWriter::new(file);
         self.local_file = Some(TftpSessionFile::Writer(writer));
     }
 
-    pub fn mode(&self) -> &str {
-        &self.mode
+    // 設定済みのファイルを包み、読み書きしたデータのハッシュ値を計算する。
+    #[cfg(feature = "digest")]
+    pub fn set_digest(&mut self, digest: &Digest) {
+        self.local_file = match self.local_file.take() {
+            Some(TftpSessionFile::Reader(reader)) => {
+                let file = reader.into_inner().into_inner();
+                let reader = DigestReader::new(file, digest.clone());
+                let reader = BufReader::new(Box::new(reader) as Box<dyn FileReader>);
+                Some(TftpSessionFile::Reader(Mutex::new(reader)))
+            }
+            Some(TftpSessionFile::Writer(writer)) => {
+                let file = writer.into_inner();
+                let writer = BufWriter::new(
+                    Box::new(DigestWriter::new(file, digest.clone())) as Box<dyn FileWriter>
+                );
+                Some(TftpSessionFile::Writer(writer))
+            }
+            None => None,
+        };
+    }
+
+    // 読み込みを offset バイト目から開始する。offset がファイルサイズを超える場合は false を返す。
+    pub async fn set_read_offset(&mut self, offset: u64) -> Result<bool, Error> {
+        let len = self.reader().lock().await.seek(SeekFrom::End(0)).await?;
+        if len < offset {
+            return Ok(false);
+        }
+
+        if let Some(TftpSessionFile::Reader(reader)) = self.local_file.take() {
+            let file = reader.into_inner().into_inner();
+            let reader = OffsetReader::new(file, offset);
+            self.set_reader(reader);
+        }
+        Ok(true)
+    }
+
+    pub fn has_file(&self) -> bool {
+        self.local_file.is_some()
+    }
+
+    pub fn close_file(&mut self) {
+        self.local_file = None;
+    }
+
+    pub fn set_persist_path(&mut self, from: &Path, to: &Path) {
+        self.persist_path = Some((from.to_path_buf(), to.to_path_buf()));
+    }
+
+    // ファイルを確定するまでは、guard が書きかけのファイルを削除できるよう保持する。
+    pub fn set_remove_guard(&mut self, guard: file::RemoveGuard) {
+        self.remove_guard = Some(guard);
+    }
+
+    // 書きかけのファイルを閉じて削除する。確定済みのファイルは削除しない。
+    pub fn discard_file(&mut self) {
+        self.close_file();
+        self.remove_guard = None;
+    }
+
+    pub fn transferred_blocks(&self) -> u64 {
+        self.transferred_blocks
+    }
+
+    pub fn retransmits(&self) -> u32 {
+        self.retransmits.load(Ordering::Relaxed)
+    }
+
+    pub fn transferred_bytes(&self) -> u64 {
+        self.transferred_bytes
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn set_progress_handler(&mut self, handler: Option<(u64, ProgressHandler)>) {
+        self.progress_handler = handler;
+    }
+
+    pub fn progress_ack(&mut self, blocknum: u16) {
+        let blocks = self.transferred_blocks + blocknum.wrapping_sub(self.blocknum_ack) as u64;
+        let bytes = self
+            .blocknum_blocks
+            .iter()
+            .find(|b| b.blocknum == blocknum)
+            .map(|b| b.reader_pos + b.reader_pos_len as u64)
+            .unwrap_or(self.transferred_bytes);
+        self.update_progress(blocks, bytes);
+    }
+
+    pub fn progress_data(&mut self, len: usize) {
+        let blocks = self.transferred_blocks + 1;
+        let bytes = self.transferred_bytes + len as u64;
+        self.update_progress(blocks, bytes);
+    }
+
+    // 転送中のバイト数を他のタスクから参照できるように共有する。
+    pub fn set_progress_counter(&mut self, counter: Option<Arc<AtomicU64>>) {
+        self.progress_counter = counter;
+    }
+
+    fn update_progress(&mut self, blocks: u64, bytes: u64) {
+        if let Some(counter) = self.progress_counter.as_ref() {
+            counter.store(bytes, Ordering::Relaxed);
+        }
+
+        if let Some((interval, handler)) = self.progress_handler.as_ref() {
+            if *interval > 0 && blocks / interval != self.transferred_blocks / interval {
+                handler(&Progress::new(self.remote_addr, blocks, bytes));
+            }
+        }
+
+        self.transferred_blocks = blocks;
+        self.transferred_bytes = bytes;
+    }
+
+    pub fn role(&self) -> &Role {
+        &self.role
+    }
+
+    pub fn set_role(&mut self, role: Role) {
+        self.role = role;
+    }
+
+    pub fn set_quiet_errors(&mut self, quiet: bool) {
+        self.quiet_errors = quiet;
+    }
+
+    pub fn set_peer_errored(&mut self) {
+        self.peer_errored = true;
+    }
+
+    pub fn set_min_timeout(&mut self, timeout: Option<Duration>) {
+        self.min_timeout = timeout;
+    }
+
+    // timeout オプションは秒単位のため、1 秒未満の再送間隔は min_timeout で指定する。
+    fn recv_timeout(&self) -> Duration {
+        let timeout = Duration::from_secs(self.options().timeout());
+        match self.min_timeout {
+            Some(min_timeout) => timeout.min(min_timeout),
+            _ => timeout,
+        }
+    }
+
+    pub fn set_dally(&mut self, dally: Option<Duration>) {
+        self.dally = dally;
+    }
+
+    pub fn reject_stray_ack(&self) -> bool {
+        self.reject_stray_ack
+    }
+
+    pub fn set_reject_stray_ack(&mut self, reject: bool) {
+        self.reject_stray_ack = reject;
+    }
+
+    pub fn set_buffer_pool(&mut self, buffer_pool: Option<Arc<BufferPool>>) {
+        self.buffer_pool = buffer_pool;
+    }
+
+    // 転送中に送信元ファイルが変更されたことを検出するため、現在の状態を保持する。
+    pub async fn set_source(&mut self, path: &Path) -> Result<(), Error> {
+        let metadata = fs::metadata(path).await?;
+        self.source = Some(Source {
+            path: path.to_path_buf(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+        Ok(())
+    }
+
+    async fn source_changed(&self) -> bool {
+        match self.source.as_ref() {
+            Some(source) => match fs::metadata(&source.path).await {
+                Ok(metadata) => {
+                    metadata.len() != source.len || metadata.modified().ok() != source.modified
+                }
+                _ => true,
+            },
+            _ => false,
+        }
+    }
+
+    // 共有のバッファがあれば借りて、破棄時に返却する。
+    fn buffer(&self, size: usize) -> PooledBuffer {
+        match self.buffer_pool.as_ref() {
+            Some(pool) => pool.take(size),
+            _ => PooledBuffer::alloc(size),
+        }
+    }
+
+    // 最後の ACK が失われた場合に備えて、再送された最後の DATA に ACK を返す。
+    pub async fn dally(&self) -> Result<(), Error> {
+        let deadline = match self.dally {
+            Some(dally) => Instant::now() + dally,
+            _ => return Ok(()),
+        };
+
+        let size = self.options().blksize() + HEADER_LEN;
+        // 転送は完了しているため、受信できない場合はそのまま終了する。
+        while let Ok(Ok(mut buf)) = time::timeout_at(deadline, self.recv(size)).await {
+            if matches!(packet::parse_opcode(&mut buf), Ok(Some(OpCode::Data)))
+                && matches!(packet::parse_blocknum(&mut buf), Ok(n) if n == self.blocknum_ack)
+            {
+                self.send_ack().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // x-confirm で双方が転送したバイト数の一致を確認できたか。
+    pub fn confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    // 受信側: 最後の ACK の後に受信したバイト数を通知し、送信側が同じ値を返すまで送り直す。
+    pub async fn confirm_received(&mut self) -> Result<(), Error> {
+        let bytes = self.transferred_bytes;
+        let confirm = packet::confirm(bytes);
+        self.wait_for_recv(
+            self.recv_timeout(),
+            |c| c.send(&confirm),
+            |c| c.recv_confirm_reply(bytes),
+        )
+        .await?;
+
+        self.confirmed = true;
+        Ok(())
+    }
+
+    async fn recv_confirm_reply(&self, bytes: u64) -> Result<(), Error> {
+        let size = self.options().blksize() + HEADER_LEN;
+        loop {
+            let mut buf = self.recv(size).await?;
+            match packet::parse_opcode(&mut buf)? {
+                Some(OpCode::Oack) if packet::parse_confirm(&mut buf)? == Some(bytes) => {
+                    return Ok(())
+                }
+                // 最後の ACK が失われたため、送信側が DATA を再送した。
+                Some(OpCode::Data) if matches!(packet::parse_blocknum(&mut buf), Ok(n) if n == self.blocknum_ack) =>
+                {
+                    self.send_ack().await?;
+                }
+                Some(OpCode::Error) => return Err(Error::SizeMismatch),
+                _ => {}
+            }
+        }
+    }
+
+    // 送信側: 受信側から通知されたバイト数が送信したバイト数と一致すれば、同じ値を返す。
+    // 返した確認が失われた場合に備えて、dally (未設定の場合は再送間隔) の間は再度の通知に応答する。
+    pub async fn confirm_sent(&mut self) -> Result<(), Error> {
+        let bytes = self.transferred_bytes;
+        let (_, received) = self
+            .wait_for_recv(
+                self.recv_timeout(),
+                |_| async { Ok(()) },
+                |c| c.recv_confirm(),
+            )
+            .await?;
+
+        if received != bytes {
+            warn!(
+                "[{}] confirmation mismatch: sent {} bytes, received {} bytes",
+                self.remote_addr(),
+                bytes,
+                received
+            );
+            return Err(Error::SizeMismatch);
+        }
+
+        let confirm = packet::confirm(bytes);
+        self.send(&confirm).await?;
+        self.confirmed = true;
+
+        let deadline = Instant::now() + self.dally.unwrap_or_else(|| self.recv_timeout());
+        // 確認は完了しているため、受信できない場合はそのまま終了する。
+        while let Ok(Ok(received)) = time::timeout_at(deadline, self.recv_confirm()).await {
+            if received == bytes {
+                self.send(&confirm).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn recv_confirm(&self) -> Result<u64, Error> {
+        let size = self.options().blksize() + HEADER_LEN;
+        loop {
+            let mut buf = self.recv(size).await?;
+            if let Some(OpCode::Oack) = packet::parse_opcode(&mut buf)? {
+                if let Some(bytes) = packet::parse_confirm(&mut buf)? {
+                    return Ok(bytes);
+                }
+            }
+        }
+    }
+
+    // OACK は失うと交渉全体が止まるため、DATA とは別の間隔と回数で再送できるようにする。
+    pub fn set_oack_retransmit(&mut self, retransmit: Option<(Duration, u32)>) {
+        self.oack_retransmit = retransmit;
+    }
+
+    pub fn set_first_block_timeout(&mut self, timeout: Option<Duration>) {
+        self.first_block_timeout = timeout;
+    }
+
+    // 最初のブロックは準備に時間がかかる場合があるため、別の待ち時間を使用する。
+    fn first_recv_timeout(&self) -> Duration {
+        self.first_block_timeout
+            .unwrap_or_else(|| self.recv_timeout())
+    }
+
+    pub fn set_min_transfer_rate(&mut self, rate: Option<u64>) {
+        self.min_transfer_rate = rate;
+    }
+
+    // クライアントの到達性を確認するまでに送信できるバイト数の上限。
+    // 送信元を詐称した要求で、第三者へ大量の応答を送りつける踏み台にされないようにする。
+    pub fn set_amplification_limit(&mut self, limit: Option<usize>) {
+        self.amplification_budget = limit.map(AtomicUsize::new);
+    }
+
+    // 上限を超える場合は送信せず、応答を待ち続ける。
+    async fn send_unverified(&self, buf: &Bytes) -> Result<usize, Error> {
+        if let Some(budget) = self.amplification_budget.as_ref() {
+            let allowed = budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |b| {
+                    b.checked_sub(buf.len())
+                })
+                .is_ok();
+            if !allowed {
+                trace!("[{}] suppressed: amplification limit", self.remote_addr());
+                return Ok(0);
+            }
+        }
+
+        self.send(buf).await
+    }
+
+    // DATA の送信速度 (bytes/sec) の上限。
+    pub fn set_max_rate(&mut self, rate: Option<u64>) {
+        self.max_rate = rate;
+    }
+
+    // 転送開始からの送信量が上限を超えないよう、送信できる時刻まで待つ。
+    async fn throttle(&self, reader_pos: u64) {
+        if let Some(rate) = self.max_rate.filter(|&r| r > 0) {
+            let due = self.started_at + Duration::from_secs_f64(reader_pos as f64 / rate as f64);
+            time::sleep_until(due).await;
+        }
+    }
+
+    // tsize が不明な場合は期限を設けない。
+    pub fn deadline_exceeded(&self) -> bool {
+        match self.min_transfer_rate {
+            Some(rate) if rate > 0 && self.options.tsize() > 0 => {
+                let millis = self.options.tsize().saturating_mul(1000) / rate;
+                self.started_at.elapsed() > Duration::from_millis(millis)
+            }
+            _ => false,
+        }
+    }
+
+    // パケットの送受信が続いていても、転送開始からこの時間を過ぎた場合は中断する。
+    pub fn set_max_duration(&mut self, duration: Option<Duration>) {
+        self.max_duration = duration;
+    }
+
+    fn remaining_duration(&self) -> Option<Duration> {
+        self.max_duration
+            .map(|d| d.saturating_sub(self.started_at.elapsed()))
+    }
+
+    pub fn preallocate(&self) -> bool {
+        self.preallocate.is_some()
+    }
+
+    pub fn set_preallocate(&mut self, file: Option<File>) {
+        self.preallocate = file;
+    }
+
+    pub async fn allocate(&mut self, len: u64) -> Result<(), Error> {
+        self.writer_mut().flush().await?;
+        if let Some(file) = self.preallocate.as_ref() {
+            file.set_len(len).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn finish_write(&mut self) -> Result<(), Error> {
+        let lastch = self.lastch.take();
+        let size = file::write_pending(self.writer_mut(), lastch).await?;
+        self.transferred_bytes += size as u64;
+
+        // 途中で打ち切られた転送を検出するため、octet では tsize と比較する。
+        let tsize = self.options.tsize();
+        if self.mode == Mode::Octet && tsize > 0 && self.transferred_bytes != tsize {
+            self.close_file();
+            return Err(Error::SizeMismatch);
+        }
+
+        if let Some(file) = self.preallocate.take() {
+            // 事前に確保したサイズより短い場合は切り詰める。
+            file.set_len(self.transferred_bytes).await?;
+        }
+
+        self.close_file();
+
+        if let Some((from, to)) = self.persist_path.take() {
+            file::persist(&from, &to).await?;
+        }
+
+        // 確定後に ACK の送信などが失敗しても、受信したファイルは残す。
+        if let Some(guard) = self.remove_guard.take() {
+            guard.defuse();
+        }
+
+        Ok(())
+    }
+
+    pub fn peer_error_handler(&self) -> Option<&PeerErrorHandler> {
+        self.peer_error_handler.as_ref()
+    }
+
+    pub fn set_peer_error_handler(&mut self, handler: Option<PeerErrorHandler>) {
+        self.peer_error_handler = handler;
+    }
+
+    #[cfg(feature = "socks5")]
+    pub fn set_socks5(&mut self, socks5: socks5::UdpAssociate) {
+        self.socks5 = Some(socks5);
+    }
+
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    fn record(&self, direction: Direction, buf: &[u8]) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record(direction, buf);
+        }
+    }
+
+    // ウィンドウ内の DATA をまとめて送信する。
+    // batch-io フィーチャが無効な場合や Linux 以外では 1 パケットずつ送信する。
+    pub fn set_batch_io(&mut self, batch_io: bool) {
+        self.batch_io = batch_io;
+    }
+
+    // sendmmsg と recvmmsg でまとめて送受信したパケットの数。
+    pub fn batched_packets(&self) -> u64 {
+        self.batched_packets.load(Ordering::Relaxed)
+    }
+
+    // ACK を待つ間に次のウィンドウのブロックを読み込んでおき、ディスクの読み込みと送信を重ねる。
+    // 先読みするのは 1 ウィンドウ分 (windowsize * blksize) まで。
+    pub fn set_read_ahead(&mut self, read_ahead: bool) {
+        self.read_ahead = read_ahead.then(|| Mutex::new(ReadAhead::default()));
+    }
+
+    pub fn clamps(&self) -> &[Clamp] {
+        &self.clamps
+    }
+
+    pub fn set_clamps(&mut self, clamps: Vec<Clamp>) {
+        self.clamps = clamps;
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
     }
 
     pub fn set_mode(&mut self, mode: &str) {
-        self.mode = mode.to_string();
+        self.mode = Mode::from(mode);
     }
 
     pub fn options(&self) -> &Options {
@@ -160,58 +786,134 @@ impl TftpSession {
     }
 
     pub async fn write(&mut self, buf: &[u8]) -> Result<(usize, Option<u8>), Error> {
-        let mode = self.mode().to_string();
-        let lastch = self.lastch();
-        file::write(self.writer_mut(), buf, &mode, lastch).await
+        let (mode, lastch) = (self.mode(), self.lastch());
+        file::write(self.writer_mut(), buf, mode, lastch).await
     }
 
+    // 受信サイズの超過を検出するため、1 バイト多く受信する。
     async fn recv(&self, size: usize) -> Result<Bytes, Error> {
-        self.retry_on_failed(|c| async {
-            let mut buf = vec![0u8; size];
-            let size = c.sock.recv(buf.as_mut_slice()).await?;
-            buf.resize(size, 0);
-            Ok(Bytes::from(buf))
-        })
-        .await
+        #[cfg(feature = "socks5")]
+        if self.socks5.is_some() {
+            // 中継されたパケットのうち、接続先以外からのものは無視する。
+            loop {
+                let (buf, addr) = self.recv_from(size).await?;
+                if addr == self.remote_addr {
+                    return Ok(buf);
+                }
+            }
+        }
+
+        let buf = self
+            .retry_on_failed(|c| async {
+                let mut buf = c.buffer(size + 1);
+                let size = c.sock.recv(&mut buf).await?;
+                Ok(buf.freeze(size))
+            })
+            .await?;
+        self.record(Direction::Received, &buf);
+        Ok(buf)
     }
 
     async fn recv_from(&self, size: usize) -> Result<(Bytes, SocketAddr), Error> {
-        self.retry_on_failed(|c| async {
-            let mut buf = vec![0u8; size];
-            let (size, addr) = c.sock.recv_from(buf.as_mut_slice()).await?;
-            buf.resize(size, 0);
-            Ok((Bytes::from(buf), addr))
-        })
-        .await
+        #[cfg(feature = "socks5")]
+        if self.socks5.is_some() {
+            let (buf, addr) = self
+                .retry_on_failed(|c| async {
+                    let mut buf = vec![0u8; size + 1 + socks5::MAX_HEADER_LEN];
+                    let size = c.sock.recv(buf.as_mut_slice()).await?;
+                    buf.resize(size, 0);
+                    socks5::decapsulate(Bytes::from(buf))
+                })
+                .await?;
+            self.record(Direction::Received, &buf);
+            return Ok((buf, addr));
+        }
+
+        let (buf, addr) = self
+            .retry_on_failed(|c| async {
+                let mut buf = c.buffer(size + 1);
+                let (size, addr) = c.sock.recv_from(&mut buf).await?;
+                Ok((buf.freeze(size), addr))
+            })
+            .await?;
+        self.record(Direction::Received, &buf);
+        Ok((buf, addr))
+    }
+
+    async fn recv_from_remote_ip(&self, size: usize) -> Result<(Bytes, SocketAddr), Error> {
+        loop {
+            let (buf, addr) = self.recv_from(size).await?;
+            // TID としてポート番号は変わるが、IP アドレスは送信先と一致しなければならない。
+            if addr.ip() == self.remote_addr.ip() {
+                return Ok((buf, addr));
+            }
+
+            warn!(
+                "[{}] ignored: reply from unexpected address {}",
+                self.remote_addr(),
+                addr
+            );
+        }
     }
 
     pub async fn recv_with_timeout(&self, size: usize) -> Result<Bytes, Error> {
         let (_, ret) = self
-            .wait_for_recv(|_| async { Ok(()) }, |c| c.recv(size))
+            .wait_for_recv(self.recv_timeout(), |_| async { Ok(()) }, |c| c.recv(size))
             .await?;
         Ok(ret)
     }
 
     async fn send(&self, buf: &Bytes) -> Result<usize, Error> {
-        self.retry_on_failed(|c| c.sock.send(buf)).await
+        #[cfg(feature = "socks5")]
+        if self.socks5.is_some() {
+            return self.send_to(buf, self.remote_addr()).await;
+        }
+
+        let size = self.retry_on_failed(|c| c.sock.send(buf)).await?;
+        self.record(Direction::Sent, buf);
+        Ok(size)
     }
 
     async fn send_to(&self, buf: &Bytes, addr: &SocketAddr) -> Result<usize, Error> {
-        self.retry_on_failed(|c| c.sock.send_to(buf, addr)).await
+        #[cfg(feature = "socks5")]
+        if self.socks5.is_some() {
+            let packet = socks5::encapsulate(addr, buf);
+            let size = self.retry_on_failed(|c| c.sock.send(&packet)).await?;
+            self.record(Direction::Sent, buf);
+            return Ok(size - (packet.len() - buf.len()));
+        }
+
+        let size = self.retry_on_failed(|c| c.sock.send_to(buf, addr)).await?;
+        self.record(Direction::Sent, buf);
+        Ok(size)
     }
 
     pub async fn send_ack(&self) -> Result<usize, Error> {
         trace!("[{}] send: ack #{}", self.remote_addr(), self.blocknum_ack);
+        if !self.sack_blocks.is_empty() {
+            let bitmap = self.sack_bitmap();
+            return self.send(&packet::sack(self.blocknum_ack, &bitmap)).await;
+        }
         self.send(&packet::ack(self.blocknum_ack)).await
     }
 
-    pub async fn send_error(&self, err: Error) -> Result<usize, Error> {
+    pub async fn send_error(&self, err: &Error) -> Result<usize, Error> {
+        // ERROR に対して ERROR を返さない (RFC 1350)。
+        if self.peer_errored {
+            return Ok(0);
+        }
         trace!("[{}] send: error {:?}", self.remote_addr(), err);
-        self.send(&packet::error(err)).await
+        let buf = if self.quiet_errors {
+            packet::error_quiet(err)
+        } else {
+            packet::error(err)
+        };
+        self.send(&buf).await
     }
 
     pub async fn send_ack_recv_data(&self) -> Result<(usize, Bytes), Error> {
         self.wait_for_recv(
+            self.recv_timeout(),
             |c| c.send_ack(),
             |c| c.recv(c.options().blksize() + HEADER_LEN),
         )
@@ -222,10 +924,7 @@ impl TftpSession {
         &mut self,
         blocknum_start: u16,
     ) -> Result<(usize, Bytes), Error> {
-        let blocknum_req = match blocknum_start.checked_add(1) {
-            Some(v) => v,
-            _ => ROLLOVER,
-        };
+        let blocknum_req = blocknum_add(blocknum_start, 1, self.options().rollover());
 
         let block = self
             .blocknum_blocks
@@ -241,8 +940,9 @@ impl TftpSession {
 
         let ((blocks, rollover, lastch), buf) = self
             .wait_for_recv(
+                self.recv_timeout(),
                 |c| c.send_multi_data(blocknum_start, reader_pos, lastch),
-                |c| c.recv(c.options().blksize() + HEADER_LEN),
+                |c| c.recv_read_ahead(c.options().blksize() + HEADER_LEN),
             )
             .await?;
         let sent_len = blocks.iter().fold(0, |s, b| s + b.data_len);
@@ -254,11 +954,162 @@ impl TftpSession {
         Ok((sent_len, buf))
     }
 
+    // 受信済みと通知されなかったブロックのみを再送する。
+    pub async fn send_missing_recv_ack(&self, bitmap: &[u8]) -> Result<(usize, Bytes), Error> {
+        let missing = self
+            .blocknum_blocks
+            .iter()
+            .filter(|b| {
+                let i = (b.blocknum.wrapping_sub(self.blocknum_ack) as usize).wrapping_sub(1);
+                i < self.options().windowsize() as usize
+                    && bitmap
+                        .get(i / 8)
+                        .map(|m| m & (0x80 >> (i % 8)) == 0)
+                        .unwrap_or(true)
+            })
+            .collect::<Vec<&FileBlock>>();
+
+        self.wait_for_recv(
+            self.recv_timeout(),
+            |c| c.resend_data(&missing),
+            |c| c.recv(c.options().blksize() + HEADER_LEN),
+        )
+        .await
+    }
+
+    // 先読み済みのブロックがあればそれを使い、なければファイルから読み込む。
+    async fn read_block(
+        &self,
+        buf: &mut [u8],
+        reader_pos: u64,
+        lastch: Option<u8>,
+    ) -> Result<(usize, usize, Option<u8>), Error> {
+        if let Some(read_ahead) = self.read_ahead.as_ref() {
+            let mut read_ahead = read_ahead.lock().await;
+            while let Some(block) = read_ahead.blocks.pop_front() {
+                if block.reader_pos == reader_pos && block.lastch == lastch {
+                    buf[..block.data.len()].copy_from_slice(&block.data);
+                    return Ok((block.reader_pos_len, block.data.len(), block.ch));
+                }
+                if block.reader_pos > reader_pos {
+                    break;
+                }
+            }
+            // 先読みと異なる位置から送り直す場合は破棄する。
+            read_ahead.blocks.clear();
+            read_ahead.next = None;
+        }
+
+        let reader_lock = self.reader();
+        let mut reader = reader_lock.lock().await;
+        file::read(&mut reader, buf, reader_pos, self.mode(), lastch).await
+    }
+
+    // 次のウィンドウのブロックを先読みする。stop が設定されたら次のブロックから読み込まない。
+    // 失敗した場合は送信時に読み込み直してエラーを返すため、ここでは先読みを止めるだけにする。
+    async fn read_ahead(&self, stop: &AtomicBool) {
+        let read_ahead = match self.read_ahead.as_ref() {
+            Some(read_ahead) => read_ahead,
+            _ => return,
+        };
+
+        let blksize = self.options().blksize();
+        while !stop.load(Ordering::Acquire) {
+            let mut read_ahead = read_ahead.lock().await;
+            let (reader_pos, lastch) = match read_ahead.next {
+                Some(next) if read_ahead.blocks.len() < self.options().windowsize() as usize => {
+                    next
+                }
+                _ => return,
+            };
+
+            let mut data_buf = self.buffer(blksize);
+            let reader_lock = self.reader();
+            let mut reader = reader_lock.lock().await;
+            let (reader_pos_len, data_buf_len, ch) =
+                match file::read(&mut reader, &mut data_buf, reader_pos, self.mode(), lastch).await
+                {
+                    Ok(ret) => ret,
+                    Err(_) => {
+                        read_ahead.next = None;
+                        return;
+                    }
+                };
+
+            read_ahead.blocks.push_back(PrefetchedBlock {
+                reader_pos,
+                lastch,
+                data: Bytes::copy_from_slice(&data_buf[..data_buf_len]),
+                reader_pos_len,
+                ch,
+            });
+            read_ahead.next = if data_buf_len < blksize {
+                None
+            } else {
+                Some((reader_pos + reader_pos_len as u64, ch))
+            };
+        }
+    }
+
+    // ACK の受信と並行して次のウィンドウを先読みする。
+    // ACK を受信したら読み込み中のブロックで先読みを止め、残りは送信時に読み込む。
+    // 読み込み中の操作を中断するとファイルの状態が壊れるため中断はしないが、
+    // そのブロックは次のウィンドウの先頭で必要になるため、待っても ACK の処理は遅れない。
+    async fn recv_read_ahead(&self, size: usize) -> Result<Bytes, Error> {
+        let received = AtomicBool::new(false);
+        let recv = async {
+            let buf = self.recv_acks(size).await;
+            received.store(true, Ordering::Release);
+            buf
+        };
+        let (buf, _) = tokio::join!(recv, self.read_ahead(&received));
+        buf
+    }
+
+    async fn resend_data(&self, blocks: &[&FileBlock]) -> Result<usize, Error> {
+        let mut sent_len = 0;
+        for block in blocks {
+            let mut data_buf = self.buffer(self.options().blksize());
+            let reader_lock = self.reader();
+            let mut reader = reader_lock.lock().await;
+            let (_, data_buf_len, _) = file::read(
+                &mut reader,
+                &mut data_buf,
+                block.reader_pos,
+                self.mode(),
+                block.lastch,
+            )
+            .await?;
+
+            trace!(
+                "[{}] resent: block num #{} ({} bytes)",
+                self.remote_addr(),
+                block.blocknum,
+                data_buf_len
+            );
+
+            sent_len += self
+                .send(&packet::data(block.blocknum, &data_buf[0..data_buf_len]))
+                .await?;
+        }
+        Ok(sent_len)
+    }
+
+    pub async fn send_oack(&self) -> Result<usize, Error> {
+        trace!("[{}] send: oack {:?}", self.remote_addr(), self.options());
+        self.send(&packet::oack(self.options())).await
+    }
+
     pub async fn send_oack_recv_data(&self) -> Result<(usize, Bytes), Error> {
         let oack = packet::oack(self.options());
         trace!("[{}] send: oack {:?}", self.remote_addr(), self.options());
-        self.wait_for_recv(
-            |c| c.send(&oack),
+        let (timeout, retries) = self
+            .oack_retransmit
+            .unwrap_or_else(|| (self.first_recv_timeout(), MAX_RETRANSMITS));
+        self.wait_for_recv_retries(
+            timeout,
+            retries,
+            |c| c.send_unverified(&oack),
             |c| c.recv(c.options().blksize() + HEADER_LEN),
         )
         .await
@@ -272,12 +1123,19 @@ impl TftpSession {
         trace!("[{}] send: req {:?}", self.remote_addr(), req);
         let (size, (buf, addr)) = self
             .wait_for_recv(
+                self.first_recv_timeout(),
                 |c| c.send_to(&req, c.remote_addr()),
-                |c| c.recv_from(c.options().blksize() + HEADER_LEN),
+                |c| c.recv_from_remote_ip(c.options().blksize() + HEADER_LEN),
             )
             .await?;
         self.remote_addr = addr;
 
+        #[cfg(feature = "socks5")]
+        if self.socks5.is_some() {
+            // ソケットは中継アドレスに接続済み。
+            return Ok((size, buf));
+        }
+
         self.sock.connect(self.remote_addr()).await?;
 
         Ok((size, buf))
@@ -296,26 +1154,39 @@ impl TftpSession {
         let mut lastch = lastch;
 
         let mut blocks = vec![];
+        let mut pending = vec![];
         for _ in 0..self.options().windowsize() {
             blocknum_req = match blocknum_req.checked_add(1) {
                 Some(v) => v,
                 _ => {
                     rollover += 1;
-                    ROLLOVER
+                    self.options().rollover()
                 }
             };
 
-            let mut data_buf = vec![0u8; self.options().blksize()];
-            let reader_lock = self.reader();
-            let mut reader = reader_lock.lock().await;
-            let (reader_pos_len, data_buf_len, ch) = file::read(
-                &mut reader,
-                data_buf.as_mut_slice(),
-                reader_pos,
-                self.mode(),
-                lastch,
-            )
-            .await?;
+            let mut data_buf = self.buffer(self.options().blksize());
+            let (reader_pos_len, data_buf_len, ch) =
+                match self.read_block(&mut data_buf, reader_pos, lastch).await {
+                    Ok(ret) => ret,
+                    Err(Error::IoContext(..)) if self.source.is_some() => {
+                        return Err(Error::SourceChanged)
+                    }
+                    Err(e) => {
+                        error!(
+                            "[{}] failed to read DATA block #{} at {}: {:?}",
+                            self.remote_addr(),
+                            blocknum_req,
+                            reader_pos,
+                            e
+                        );
+                        return Err(e);
+                    }
+                };
+
+            // 最後のブロックを送信する前に、転送中に切り詰められていないか確認する。
+            if data_buf_len < self.options().blksize() && self.source_changed().await {
+                return Err(Error::SourceChanged);
+            }
 
             trace!(
                 "[{}] readed: block num #{} ({} bytes)",
@@ -324,12 +1195,16 @@ impl TftpSession {
                 data_buf_len
             );
 
-            let sent_len = self
-                .send(&packet::data(
-                    blocknum_req,
-                    &data_buf.as_slice()[0..data_buf_len],
-                ))
-                .await?;
+            self.throttle(reader_pos).await;
+
+            let data = packet::data(blocknum_req, &data_buf[0..data_buf_len]);
+            let sent_len = if self.batch_io {
+                let len = data.len();
+                pending.push(data);
+                len
+            } else {
+                self.send(&data).await?
+            };
             let block = FileBlock {
                 blocknum: blocknum_req,
                 reader_pos,
@@ -353,9 +1228,103 @@ impl TftpSession {
             }
         }
 
+        self.send_batch(&pending).await?;
+
+        if let Some(read_ahead) = self.read_ahead.as_ref() {
+            let mut read_ahead = read_ahead.lock().await;
+            let full = blocks.len() == self.options().windowsize() as usize
+                && blocks.last().map(|b| b.data_len) == Some(self.options().blksize() + HEADER_LEN);
+            // 最後のブロックまで送信した場合は先読みしない。
+            read_ahead.next = if full {
+                Some((reader_pos, lastch))
+            } else {
+                None
+            };
+        }
+
         Ok((blocks, rollover, lastch))
     }
 
+    #[cfg(all(feature = "batch-io", target_os = "linux"))]
+    async fn send_batch(&self, packets: &[Bytes]) -> Result<(), Error> {
+        #[cfg(feature = "socks5")]
+        if self.socks5.is_some() {
+            for packet in packets {
+                self.send(packet).await?;
+            }
+            return Ok(());
+        }
+
+        // 一部を送信した後に失敗した場合は、送信済みのパケットを送り直さない。
+        let sent = AtomicUsize::new(0);
+        self.retry_on_failed(|c| batch::send_all(&c.sock, packets, &sent))
+            .await?;
+        for packet in packets {
+            self.record(Direction::Sent, packet);
+        }
+        self.batched_packets
+            .fetch_add(packets.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // ウィンドウの送信後に届いている ACK を recvmmsg でまとめて受信し、最も進んだものを返す。
+    // ACK 以外のパケットがあれば、それを優先して返す。
+    #[cfg(all(feature = "batch-io", target_os = "linux"))]
+    async fn recv_acks(&self, size: usize) -> Result<Bytes, Error> {
+        #[cfg(feature = "socks5")]
+        if self.socks5.is_some() {
+            return self.recv(size).await;
+        }
+        if !self.batch_io {
+            return self.recv(size).await;
+        }
+
+        let windowsize = self.options().windowsize();
+        let packets = self
+            .retry_on_failed(|c| batch::recv_all(&c.sock, size + 1, windowsize as usize))
+            .await?;
+        self.batched_packets
+            .fetch_add(packets.len() as u64, Ordering::Relaxed);
+
+        let mut latest: Option<(i32, Bytes)> = None;
+        for packet in packets {
+            self.record(Direction::Received, &packet);
+            if packet.len() < 4 || packet[..2] != (OpCode::Ack as u16).to_be_bytes() {
+                return Ok(packet);
+            }
+
+            // 送信済みのウィンドウより古い ACK は、新しい ACK より優先しない。
+            let blocknum = u16::from_be_bytes([packet[2], packet[3]]);
+            let distance = blocknum.wrapping_sub(self.blocknum_ack);
+            let rank = if distance <= windowsize {
+                distance as i32
+            } else {
+                -1
+            };
+            if latest.as_ref().map(|(r, _)| rank >= *r).unwrap_or(true) {
+                latest = Some((rank, packet));
+            }
+        }
+
+        match latest {
+            Some((_, packet)) => Ok(packet),
+            _ => self.recv(size).await,
+        }
+    }
+
+    #[cfg(not(all(feature = "batch-io", target_os = "linux")))]
+    async fn recv_acks(&self, size: usize) -> Result<Bytes, Error> {
+        self.recv(size).await
+    }
+
+    #[cfg(not(all(feature = "batch-io", target_os = "linux")))]
+    async fn send_batch(&self, packets: &[Bytes]) -> Result<(), Error> {
+        for packet in packets {
+            self.send(packet).await?;
+        }
+        Ok(())
+    }
+
     async fn retry_on_failed<'a, Fut, T>(
         &'a self,
         action: impl Fn(&'a Self) -> Fut,
@@ -386,6 +1355,22 @@ impl TftpSession {
 
     async fn wait_for_recv<'a, SFut, S, RFut, R>(
         &'a self,
+        timeout: Duration,
+        send_action: impl Fn(&'a Self) -> SFut,
+        recv_action: impl Fn(&'a Self) -> RFut,
+    ) -> Result<(S, R), Error>
+    where
+        SFut: Future<Output = Result<S, Error>>,
+        RFut: Future<Output = Result<R, Error>>,
+    {
+        self.wait_for_recv_retries(timeout, MAX_RETRANSMITS, send_action, recv_action)
+            .await
+    }
+
+    async fn wait_for_recv_retries<'a, SFut, S, RFut, R>(
+        &'a self,
+        timeout: Duration,
+        retries: u32,
         send_action: impl Fn(&'a Self) -> SFut,
         recv_action: impl Fn(&'a Self) -> RFut,
     ) -> Result<(S, R), Error>
@@ -393,32 +1378,36 @@ impl TftpSession {
         SFut: Future<Output = Result<S, Error>>,
         RFut: Future<Output = Result<R, Error>>,
     {
+        if self.remaining_duration() == Some(Duration::ZERO) {
+            return Err(Error::Timedout);
+        }
+
         let mut t = send_action(self).await?;
 
         let mut retransmit = 1;
         loop {
-            if let Ok(task) = time::timeout(
-                Duration::from_secs(self.options().timeout()),
-                recv_action(self),
-            )
-            .await
-            {
+            let wait = self
+                .remaining_duration()
+                .map(|r| r.min(timeout))
+                .unwrap_or(timeout);
+            if let Ok(task) = time::timeout(wait, recv_action(self)).await {
                 return Ok((t, task?));
             }
 
-            if retransmit >= 10 {
+            if retransmit >= retries || self.remaining_duration() == Some(Duration::ZERO) {
                 return Err(Error::Timedout);
             }
 
             warn!(
-                "[{}] timedout: {}s {}times",
+                "[{}] timedout: {:?} {}times",
                 self.remote_addr(),
-                self.options().timeout(),
+                timeout,
                 retransmit
             );
 
             t = send_action(self).await?;
             retransmit += 1;
+            self.retransmits.fetch_add(1, Ordering::Relaxed);
         }
     }
 }
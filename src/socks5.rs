@@ -0,0 +1,197 @@
+use super::error::Error;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// RSV(2) + FRAG(1) + ATYP(1) + IPv6(16) + PORT(2)
+pub const MAX_HEADER_LEN: usize = 22;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_IPV6: u8 = 4;
+
+pub struct UdpAssociate {
+    relay_addr: SocketAddr,
+    // 制御用の TCP 接続を閉じると UDP ASSOCIATE が解除されるため保持する。
+    _control: TcpStream,
+}
+
+impl UdpAssociate {
+    pub async fn connect(proxy_addr: SocketAddr, local_addr: SocketAddr) -> Result<Self, Error> {
+        let mut control = TcpStream::connect(proxy_addr).await?;
+
+        control.write_all(&[VERSION, 1, NO_AUTH]).await?;
+        let mut method = [0u8; 2];
+        control.read_exact(&mut method).await?;
+        if method != [VERSION, NO_AUTH] {
+            return Err(Error::Socks5Handshake);
+        }
+
+        let mut req = BytesMut::new();
+        req.put_slice(&[VERSION, CMD_UDP_ASSOCIATE, 0]);
+        put_addr(&mut req, &local_addr);
+        control.write_all(&req).await?;
+
+        let mut reply = [0u8; 3];
+        control.read_exact(&mut reply).await?;
+        if reply[0] != VERSION || reply[1] != 0 {
+            return Err(Error::Socks5Handshake);
+        }
+
+        let mut relay_addr = read_addr(&mut control).await?;
+        if relay_addr.ip().is_unspecified() {
+            // 中継アドレスが未指定の場合はプロキシと同じアドレスを使用する。
+            relay_addr.set_ip(proxy_addr.ip());
+        }
+
+        Ok(UdpAssociate {
+            relay_addr,
+            _control: control,
+        })
+    }
+
+    pub fn relay_addr(&self) -> &SocketAddr {
+        &self.relay_addr
+    }
+}
+
+pub fn encapsulate(addr: &SocketAddr, buf: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::new();
+    bytes.put_u16(0);
+    bytes.put_u8(0);
+    put_addr(&mut bytes, addr);
+    bytes.put_slice(buf);
+    bytes.freeze()
+}
+
+pub fn decapsulate(mut buf: Bytes) -> Result<(Bytes, SocketAddr), io::Error> {
+    if buf.len() < 4 {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    buf.advance(2);
+
+    // フラグメントには対応しない。
+    if buf.get_u8() != 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    let ip = match buf.get_u8() {
+        ATYP_IPV4 if buf.len() >= 6 => IpAddr::from(Ipv4Addr::from(buf.get_u32())),
+        ATYP_IPV6 if buf.len() >= 18 => IpAddr::from(Ipv6Addr::from(buf.get_u128())),
+        _ => return Err(io::Error::from(io::ErrorKind::InvalidData)),
+    };
+    let port = buf.get_u16();
+
+    Ok((buf, SocketAddr::new(ip, port)))
+}
+
+fn put_addr(bytes: &mut BytesMut, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes.put_u8(ATYP_IPV4);
+            bytes.put_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.put_u8(ATYP_IPV6);
+            bytes.put_slice(&ip.octets());
+        }
+    }
+    bytes.put_u16(addr.port());
+}
+
+async fn read_addr<T: AsyncRead + Unpin>(reader: &mut T) -> Result<SocketAddr, Error> {
+    let ip = match reader.read_u8().await? {
+        ATYP_IPV4 => IpAddr::from(Ipv4Addr::from(reader.read_u32().await?)),
+        ATYP_IPV6 => IpAddr::from(Ipv6Addr::from(reader.read_u128().await?)),
+        _ => return Err(Error::Socks5Handshake),
+    };
+    let port = reader.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::options::Options;
+    use crate::server::Server;
+    use crate::testutil;
+    use tokio::net::{TcpListener, UdpSocket};
+
+    async fn relay_stub() -> Result<SocketAddr, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let relay = UdpSocket::bind("127.0.0.1:0").await?;
+        let relay_addr = relay.local_addr()?;
+
+        tokio::spawn(async move {
+            let (mut control, _) = listener.accept().await.unwrap();
+
+            let mut buf = [0u8; 3];
+            control.read_exact(&mut buf).await.unwrap();
+            control.write_all(&[VERSION, NO_AUTH]).await.unwrap();
+
+            control.read_exact(&mut buf).await.unwrap();
+            read_addr(&mut control).await.unwrap();
+
+            let mut reply = BytesMut::new();
+            reply.put_slice(&[VERSION, 0, 0]);
+            put_addr(&mut reply, &relay_addr);
+            control.write_all(&reply).await.unwrap();
+
+            let mut client_addr = None;
+            loop {
+                let mut buf = vec![0u8; 2048];
+                let (size, addr) = relay.recv_from(&mut buf).await.unwrap();
+                buf.resize(size, 0);
+
+                match client_addr {
+                    Some(client) if client != addr => {
+                        let packet = encapsulate(&addr, &buf);
+                        relay.send_to(&packet, client).await.unwrap();
+                    }
+                    _ => {
+                        client_addr = Some(addr);
+                        let (payload, dst) = decapsulate(Bytes::from(buf)).unwrap();
+                        relay.send_to(&payload, dst).await.unwrap();
+                    }
+                }
+            }
+        });
+
+        Ok(proxy_addr)
+    }
+
+    #[test]
+    fn decapsulate_ok() -> Result<(), io::Error> {
+        let addr = "127.0.0.1:69".parse().unwrap();
+        let (buf, ret) = decapsulate(encapsulate(&addr, b"abc"))?;
+        assert_eq!(addr, ret);
+        assert_eq!(&b"abc"[..], &buf[..]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_via_proxy() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        let content = testutil::content(2000);
+        std::fs::write(root.path().join("a"), &content)?;
+
+        let server = Server::new(testutil::addr(), root.path(), Options::default())?;
+        let addr = testutil::serve(server).await;
+        let proxy_addr = relay_stub().await?;
+
+        let local = tempfile::tempdir()?;
+        let mut client = Client::new(addr, "octet", Options::default());
+        client.set_socks5_proxy(proxy_addr);
+        client.get(&local.path().join("a"), "a").await?;
+
+        assert_eq!(content, std::fs::read(local.path().join("a"))?);
+        Ok(())
+    }
+}
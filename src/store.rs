@@ -0,0 +1,260 @@
+use super::error::Error;
+#[cfg(feature = "http-origin")]
+use super::file;
+use bytes::Bytes;
+#[cfg(feature = "http-origin")]
+use std::fs;
+#[cfg(feature = "http-origin")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "http-origin")]
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(feature = "http-origin")]
+use std::path::{Component, Path, PathBuf};
+#[cfg(feature = "http-origin")]
+use std::time::Duration;
+
+// オリジンへの接続と送受信がこれを超えた場合は失敗とする。
+#[cfg(feature = "http-origin")]
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub trait FileStore: Send + Sync {
+    fn read(&self, filename: &str) -> Result<Bytes, Error>;
+}
+
+// バイナリに埋め込んだファイルを読み取り専用で提供する。
+#[derive(Clone, Debug)]
+pub struct StaticFileStore {
+    files: &'static [(&'static str, &'static [u8])],
+}
+
+impl StaticFileStore {
+    pub fn new(files: &'static [(&'static str, &'static [u8])]) -> Self {
+        StaticFileStore { files }
+    }
+}
+
+impl FileStore for StaticFileStore {
+    fn read(&self, filename: &str) -> Result<Bytes, Error> {
+        self.files
+            .iter()
+            .find(|(name, _)| *name == filename)
+            .map(|(_, content)| Bytes::from_static(content))
+            .ok_or(Error::FileNotFound)
+    }
+}
+
+// ローカルに存在しないファイルを HTTP のオリジンから取得し、cache_dir に保存してから提供する。
+// FileStore はサーバがブロッキング用のスレッドで呼び出すため、取得中も他の転送は止まらない。
+#[cfg(feature = "http-origin")]
+#[derive(Clone, Debug)]
+pub struct HttpOriginStore {
+    host: String,
+    prefix: String,
+    cache_dir: PathBuf,
+}
+
+#[cfg(feature = "http-origin")]
+impl HttpOriginStore {
+    // origin は http://host[:port][/prefix] の形式で指定する。
+    pub fn new(origin: &str, cache_dir: &Path) -> Result<Self, Error> {
+        let origin = origin
+            .strip_prefix("http://")
+            .ok_or_else(|| Error::InvalidConfig(origin.to_string()))?;
+        let (host, prefix) = match origin.find('/') {
+            Some(i) => (&origin[..i], origin[i..].trim_end_matches('/')),
+            _ => (origin, ""),
+        };
+
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+
+        Ok(HttpOriginStore {
+            host,
+            prefix: prefix.to_string(),
+            cache_dir: cache_dir.canonicalize()?,
+        })
+    }
+
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let mut last_err = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        for addr in self.host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, FETCH_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+                    stream.set_write_timeout(Some(FETCH_TIMEOUT))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_err = e,
+            }
+        }
+        Err(Error::from(last_err))
+    }
+
+    fn fetch(&self, filename: &str) -> Result<Vec<u8>, Error> {
+        let mut stream = self.connect()?;
+        let request = format!(
+            "GET {}/{} HTTP/1.0\r\nHost: {}\r\n\r\n",
+            self.prefix,
+            percent_encode(filename),
+            self.host
+        );
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+
+        let invalid = || Error::from(io::Error::from(io::ErrorKind::InvalidData));
+        let header_end = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(invalid)?;
+        let status = String::from_utf8_lossy(&response[..header_end])
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(invalid)?;
+
+        match status {
+            200 => Ok(response.split_off(header_end + 4)),
+            404 => Err(Error::FileNotFound),
+            _ => Err(Error::from(io::Error::new(
+                io::ErrorKind::Other,
+                format!("HTTP status {}", status),
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "http-origin")]
+impl FileStore for HttpOriginStore {
+    fn read(&self, filename: &str) -> Result<Bytes, Error> {
+        // 制御文字は要求行に混入するため受け付けない。
+        if filename.chars().any(|c| c.is_control()) {
+            return Err(Error::InvalidFileName);
+        }
+
+        let relative = Path::new(filename.trim_start_matches('/'));
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(Error::InvalidFileName);
+        }
+
+        let local_file = self.cache_dir.join(relative);
+        if local_file.is_file() {
+            return Ok(Bytes::from(fs::read(&local_file)?));
+        }
+
+        let content = self.fetch(&relative.to_string_lossy())?;
+
+        // 一時ファイルに書き込んでから置き換え、途中までのファイルを提供しないようにする。
+        if let Some(parent) = local_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp_file = file::temp_path(&local_file);
+        fs::write(&temp_file, &content)?;
+        if let Err(e) = fs::rename(&temp_file, &local_file) {
+            let _ = fs::remove_file(&temp_file);
+            return Err(Error::from(e));
+        }
+
+        Ok(Bytes::from(content))
+    }
+}
+
+// パスの区切り以外の予約文字をエンコードする (RFC 3986)。
+#[cfg(feature = "http-origin")]
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_read() -> Result<(), Error> {
+        let store = StaticFileStore::new(&[("a", b"abc")]);
+
+        assert_eq!(Bytes::from_static(b"abc"), store.read("a")?);
+        assert!(matches!(store.read("b"), Err(Error::FileNotFound)));
+        Ok(())
+    }
+
+    #[cfg(feature = "http-origin")]
+    #[test]
+    fn http_origin_read() -> Result<(), Error> {
+        use std::net::TcpListener;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let size = stream.read(&mut buf).unwrap();
+                    request.extend_from_slice(&buf[..size]);
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let found =
+                    request.starts_with(b"GET /fw/a ") || request.starts_with(b"GET /fw/a%20b ");
+                let response: &[u8] = if found {
+                    b"HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\nabc"
+                } else {
+                    b"HTTP/1.0 404 Not Found\r\n\r\n"
+                };
+                stream.write_all(response).unwrap();
+            }
+        });
+
+        let cache_dir = tempfile::tempdir()?;
+        let store = HttpOriginStore::new(&format!("http://{}/fw/", addr), cache_dir.path())?;
+
+        // キャッシュがない場合はオリジンから取得する。
+        assert_eq!(Bytes::from_static(b"abc"), store.read("a")?);
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+        assert_eq!(b"abc", fs::read(cache_dir.path().join("a"))?.as_slice());
+
+        // キャッシュがある場合はオリジンに問い合わせない。
+        assert_eq!(Bytes::from_static(b"abc"), store.read("a")?);
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+
+        assert!(matches!(store.read("b"), Err(Error::FileNotFound)));
+        assert!(matches!(store.read("../a"), Err(Error::InvalidFileName)));
+
+        // 空白はエンコードし、改行を含む名前は要求しない。
+        assert_eq!(Bytes::from_static(b"abc"), store.read("a b")?);
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+        let injected = store.read("b HTTP/1.0\r\nX-Injected: 1\r\n\r\n");
+        assert!(matches!(injected, Err(Error::InvalidFileName)));
+        assert_eq!(3, requests.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    #[cfg(feature = "http-origin")]
+    #[test]
+    fn http_origin_percent_encode() {
+        assert_eq!("boot/a%20b%25.img", percent_encode("boot/a b%.img"));
+    }
+}
@@ -0,0 +1,70 @@
+use super::packet;
+use super::server::Server;
+use bytes::Bytes;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use tokio::time::{self, Duration};
+
+pub fn addr() -> SocketAddr {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.local_addr().unwrap()
+}
+
+pub fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// テスト全体で共有するため、他のテストのログも含まれる。
+struct CaptureLogger {
+    logs: Mutex<Vec<String>>,
+}
+
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Info
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = format!("{} {}", record.level(), record.args());
+            self.logs.lock().unwrap().push(line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger {
+    logs: Mutex::new(vec![]),
+};
+
+pub fn capture_logs() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+pub fn logs() -> Vec<String> {
+    LOGGER.logs.lock().unwrap().clone()
+}
+
+pub async fn serve(server: Server) -> SocketAddr {
+    let addr = *server.service_addr();
+    tokio::spawn(server.serve_forever());
+    // サーバのソケットがバインドされるまで待つ。
+    time::sleep(Duration::from_millis(100)).await;
+    addr
+}
+
+pub async fn request(addr: SocketAddr, req: &packet::Request) -> (tokio::net::UdpSocket, Bytes) {
+    let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sock.send_to(&packet::request(req), addr).await.unwrap();
+
+    let mut buf = vec![0; 65536];
+    let (size, remote) = sock.recv_from(&mut buf).await.unwrap();
+    buf.resize(size, 0);
+    sock.connect(remote).await.unwrap();
+
+    (sock, Bytes::from(buf))
+}